@@ -0,0 +1,21 @@
+use std::fs;
+use std::io;
+
+// Expects a directory preopened as /data holding input.txt
+fn main() -> io::Result<()> {
+    let input = fs::read_to_string("/data/input.txt")?;
+    let words = input.split_whitespace().count();
+    println!("read {} bytes holding {words} words", input.len());
+
+    fs::write("/data/summary.txt", format!("words: {words}\n"))?;
+
+    let mut names = fs::read_dir("/data")?
+        .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+        .collect::<io::Result<Vec<_>>>()?;
+    names.sort();
+    println!("files: {}", names.join(", "));
+
+    let summary = fs::read_to_string("/data/summary.txt")?;
+    println!("summary: {}", summary.trim());
+    Ok(())
+}
@@ -0,0 +1,8 @@
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    // Report failure through the exit status rather than a trap
+    println!("checking the input");
+    eprintln!("no input given, giving up");
+    ExitCode::FAILURE
+}
@@ -0,0 +1,7 @@
+fn main() {
+    let items: Vec<u32> = std::env::args().skip(1).filter_map(|arg| arg.parse().ok()).collect();
+    println!("summing {} items", items.len());
+    // Without arguments this divides by zero, which panics and traps the guest
+    let average = items.iter().sum::<u32>() / items.len() as u32;
+    println!("average: {average}");
+}
@@ -0,0 +1,51 @@
+use wasi::http::outgoing_handler;
+use wasi::http::types::{Fields, IncomingBody, OutgoingRequest, Scheme};
+use wasi::io::streams::StreamError;
+
+// Fetches the URL given as the only argument, which should answer with a
+// chunked body followed by trailers, and prints each read and the trailers
+fn main() -> Result<(), String> {
+    let url = std::env::args().nth(1).ok_or("usage: http_trailers URL")?;
+    let rest = url.strip_prefix("http://").ok_or("only http:// URLs are supported")?;
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+
+    let request = OutgoingRequest::new(Fields::new());
+    request.set_scheme(Some(&Scheme::Http)).map_err(|()| "bad scheme")?;
+    request.set_authority(Some(authority)).map_err(|()| "bad authority")?;
+    request
+        .set_path_with_query(Some(if path.is_empty() { "/" } else { path }))
+        .map_err(|()| "bad path")?;
+
+    let future = outgoing_handler::handle(request, None).map_err(|err| err.to_string())?;
+    future.subscribe().block();
+    let response = future
+        .get()
+        .ok_or("response not ready")?
+        .map_err(|()| "response already taken")?
+        .map_err(|err| err.to_string())?;
+    println!("status: {}", response.status());
+
+    let body = response.consume().map_err(|()| "body already taken")?;
+    let stream = body.stream().map_err(|()| "body stream already taken")?;
+    loop {
+        match stream.blocking_read(1024) {
+            Ok(chunk) => println!("chunk: {:?}", String::from_utf8_lossy(&chunk)),
+            Err(StreamError::Closed) => break,
+            Err(StreamError::LastOperationFailed(err)) => return Err(err.to_debug_string()),
+        }
+    }
+    drop(stream);
+
+    let trailers = IncomingBody::finish(body);
+    trailers.subscribe().block();
+    match trailers.get() {
+        Some(Ok(Ok(Some(fields)))) => {
+            for (name, value) in fields.entries() {
+                println!("trailer: {name}: {}", String::from_utf8_lossy(&value));
+            }
+        }
+        Some(Ok(Ok(None))) => println!("no trailers"),
+        other => return Err(format!("reading trailers failed: {other:?}")),
+    }
+    Ok(())
+}
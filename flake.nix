@@ -71,7 +71,7 @@
           "${example}-wasm" = compileRust { name = example; inherit src; };
         };
 
-        examples = ["print_time" "print_args" "print_random" "fetch_quote" "bench_num" "read_stdin"];
+        examples = ["print_time" "print_args" "print_random" "fetch_quote" "bench_num" "read_stdin" "exit_code" "guest_trap" "dir_rw" "http_trailers"];
 
         packagesForExamples =
           builtins.foldl' (acc: example: acc // examplePackages example) {}
@@ -132,6 +132,10 @@
           fetch_quote = packagesForExamples."fetch_quote-wasm";
           bench_num = packagesForExamples."bench_num-wasm";
           read_stdin = packagesForExamples."read_stdin-wasm";
+          exit_code = packagesForExamples."exit_code-wasm";
+          guest_trap = packagesForExamples."guest_trap-wasm";
+          dir_rw = packagesForExamples."dir_rw-wasm";
+          http_trailers = packagesForExamples."http_trailers-wasm";
           c_hello_world = c_hello_world-wasm;
           go_hello_world = go_hello_world-wasm;
           hello_haskell = hello_haskell-wasm;
@@ -3,13 +3,17 @@ set -euo pipefail
 
 usage() {
   cat <<'EOF'
-Usage: golden-fixture --component NAME [--wasm PATH] [--scenario NAME] [--] [ARGS...]
+Usage: golden-fixture --component NAME [--wasm PATH] [--scenario NAME] [--dir HOST::GUEST]... [--] [ARGS...]
+
+Each --dir is recorded from a scratch copy of HOST, so the guest's writes
+leave the repository alone.
 EOF
 }
 
 component=""
 scenario=""
 wasm=""
+dirs=()
 args=()
 
 while [[ $# -gt 0 ]]; do
@@ -26,6 +30,10 @@ while [[ $# -gt 0 ]]; do
       wasm="$2"
       shift 2
       ;;
+    --dir)
+      dirs+=("$2")
+      shift 2
+      ;;
     --help|-h)
       usage
       exit 0
@@ -82,14 +90,42 @@ stderr_file="${output_dir}/stderr.txt"
 metadata="${output_dir}/metadata.toml"
 
 cmd=("$WASM_RR_BIN" "record" "$wasm" "-t" "$trace")
+if [[ ${#dirs[@]} -gt 0 ]]; then
+  scratch="$(mktemp -d)"
+  trap 'rm -rf "$scratch"' EXIT
+  for dir in "${dirs[@]}"; do
+    host="${dir%%::*}"
+    guest="${dir#*::}"
+    copy="$scratch/$(basename "$host")"
+    cp -r "$host" "$copy"
+    cmd+=("--dir" "$copy::$guest")
+  done
+fi
 if [[ ${#args[@]} -gt 0 ]]; then
   cmd+=("--")
   cmd+=("${args[@]}")
 fi
-"${cmd[@]}"
+# A guest that exits with a failure status still leaves a complete trace
+status=0
+"${cmd[@]}" || status=$?
 
 cmd=("$WASM_RR_BIN" "replay" "$wasm" "$trace")
-"${cmd[@]}" >"$stdout_file" 2>"$stderr_file"
+replay_status=0
+"${cmd[@]}" >"$stdout_file" 2>"$stderr_file" || replay_status=$?
+if [[ "$replay_status" != "$status" ]]; then
+  echo "replay exited with $replay_status but the recording with $status" >&2
+  cat "$stderr_file" >&2
+  exit 1
+fi
+
+# The causes of a trap include its wasm backtrace, which depends on the build,
+# so only the stderr before them is kept
+stderr_prefix=false
+if grep -qx 'Caused by:' "$stderr_file"; then
+  sed -n '/^Caused by:$/q;p' "$stderr_file" >"$stderr_file.prefix"
+  mv "$stderr_file.prefix" "$stderr_file"
+  stderr_prefix=true
+fi
 
 {
   printf 'component = "%s"\n' "$component"
@@ -99,6 +135,12 @@ cmd=("$WASM_RR_BIN" "replay" "$wasm" "$trace")
   printf 'trace = "trace.json"\n'
   printf 'stdout = "stdout.txt"\n'
   printf 'stderr = "stderr.txt"\n'
+  if [[ "$status" != 0 ]]; then
+    printf 'exit_code = %s\n' "$status"
+  fi
+  if [[ "$stderr_prefix" == true ]]; then
+    printf 'stderr_prefix = true\n'
+  fi
 } >"$metadata"
 
 echo "Updated $output_dir"
@@ -15,6 +15,10 @@ set -euo pipefail
 : "${FIZZBUZZ_ZIG_WASM:?FIZZBUZZ_ZIG_WASM must be set}"
 : "${JS_WORDSTATS_WASM:?JS_WORDSTATS_WASM must be set}"
 : "${COUNTS_WASM:?COUNTS_WASM must be set}"
+: "${EXIT_CODE_WASM:?EXIT_CODE_WASM must be set}"
+: "${GUEST_TRAP_WASM:?GUEST_TRAP_WASM must be set}"
+: "${DIR_RW_WASM:?DIR_RW_WASM must be set}"
+: "${HTTP_TRAILERS_WASM:?HTTP_TRAILERS_WASM must be set}"
 
 resolve_wasm() {
   case "$1" in
@@ -31,6 +35,10 @@ resolve_wasm() {
     fizzbuzz_zig) printf '%s\n' "$FIZZBUZZ_ZIG_WASM" ;;
     js_wordstats) printf '%s\n' "$JS_WORDSTATS_WASM" ;;
     counts) printf '%s\n' "$COUNTS_WASM" ;;
+    exit_code) printf '%s\n' "$EXIT_CODE_WASM" ;;
+    guest_trap) printf '%s\n' "$GUEST_TRAP_WASM" ;;
+    dir_rw) printf '%s\n' "$DIR_RW_WASM" ;;
+    http_trailers) printf '%s\n' "$HTTP_TRAILERS_WASM" ;;
     *)
       echo "unknown component: $1" >&2
       return 1
@@ -52,6 +60,7 @@ get_stdin_file() {
 }
 
 failures=0
+root="$PWD"
 
 while IFS= read -r metadata; do
   dir="$(dirname "$metadata")"
@@ -68,6 +77,8 @@ print(data["trace"])
 print(data["stdout"])
 print(data["stderr"])
 print("true" if data.get("must_fail", False) else "false")
+print(data.get("exit_code", 0))
+print("true" if data.get("stderr_prefix", False) else "false")
 PY
   )
 
@@ -77,6 +88,8 @@ PY
   stdout_rel="${meta[3]}"
   stderr_rel="${meta[4]}"
   must_fail="${meta[5]}"
+  exit_code="${meta[6]}"
+  stderr_prefix="${meta[7]}"
 
   wasm_path="$(resolve_wasm "$component")" || {
     failures=$((failures + 1))
@@ -103,17 +116,33 @@ PY
   fixture_fail=0
 
   stdin_file="$(get_stdin_file "$component")"
-  if [[ -n "$stdin_file" ]] && [[ -f "$stdin_file" ]]; then
-    if ! "$WASM_RR_BIN" replay "$wasm_path" "$trace_file" <"$stdin_file" >"$actual_stdout" 2>"$actual_stderr"; then
-      echo "replay failed for $label" >&2
-      cat "$actual_stderr" >&2 || true
-      fixture_fail=1
-    fi
-  elif ! "$WASM_RR_BIN" replay "$wasm_path" "$trace_file" >"$actual_stdout" 2>"$actual_stderr"; then
-    echo "replay failed for $label" >&2
+  if [[ -z "$stdin_file" ]] || [[ ! -f "$stdin_file" ]]; then
+    stdin_file=/dev/null
+  fi
+  # Replay must leave the host alone, so it runs in an empty directory that
+  # has to stay empty, and exits with the guest's own status
+  scratch="$(mktemp -d)"
+  status=0
+  (cd "$scratch" && "$WASM_RR_BIN" replay "$wasm_path" "$root/$trace_file") \
+    <"$stdin_file" >"$actual_stdout" 2>"$actual_stderr" || status=$?
+  if [[ "$status" != "$exit_code" ]]; then
+    echo "replay failed for $label: exit status $status, expected $exit_code" >&2
     cat "$actual_stderr" >&2 || true
     fixture_fail=1
   fi
+  if [[ -n "$(ls -A "$scratch")" ]]; then
+    echo "replay of $label wrote to the host:" >&2
+    ls -AR "$scratch" >&2
+    fixture_fail=1
+  fi
+  rm -rf "$scratch"
+
+  # A trap's wasm backtrace depends on the build, so such fixtures only keep
+  # the start of stderr
+  if [[ "$stderr_prefix" == "true" ]]; then
+    head -c "$(wc -c <"$stderr_file")" "$actual_stderr" >"$actual_stderr.prefix"
+    mv "$actual_stderr.prefix" "$actual_stderr"
+  fi
 
   if [[ $fixture_fail == 0 ]]; then
     if ! diff -u "$stdout_file" "$actual_stdout"; then
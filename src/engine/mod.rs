@@ -1,12 +1,15 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use std::marker::PhantomData;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use wasmtime::component::{HasData, Linker};
 use wasmtime::{Config, Engine};
-use wasmtime_wasi::p2::bindings::{cli, clocks, random, sync::filesystem, sync::io::streams};
-use wasmtime_wasi::{WasiCtx, WasiCtxBuilder, WasiView};
+use wasmtime_wasi::p2::bindings::sync::io::{poll, streams};
+use wasmtime_wasi::p2::bindings::{cli, clocks, random, sync::filesystem};
+use wasmtime_wasi::{DirPerms, FilePerms, WasiCtx, WasiCtxBuilder, WasiView};
 use wasmtime_wasi_http::WasiHttpView;
 
+use crate::util::glob::glob_match;
+
 struct Intercept<T>(PhantomData<T>);
 
 impl<T: 'static> HasData for Intercept<T> {
@@ -28,15 +31,21 @@ where
         + clocks::wall_clock::Host
         + clocks::monotonic_clock::Host
         + cli::environment::Host
+        + cli::stdin::Host
+        + cli::stdout::Host
+        + cli::stderr::Host
         + random::random::Host
         + random::insecure::Host
         + random::insecure_seed::Host
+        + filesystem::preopens::Host
         + filesystem::types::Host
         + filesystem::types::HostDescriptor
         + filesystem::types::HostDirectoryEntryStream
         + streams::Host
         + streams::HostInputStream
         + streams::HostOutputStream
+        + poll::Host
+        + poll::HostPollable
         + 'static,
 {
     // Create an engine with the component model enabled and a component linker.
@@ -49,19 +58,24 @@ where
     wasmtime_wasi_http::add_only_http_to_linker_sync(&mut linker)
         .context("failed to add wasi:http components")?;
 
-    // Add I/O components needed by both WASI and HTTP
-    add_wasi_io_to_linker(&mut linker)?;
+    // Add I/O error components needed by both WASI and HTTP
+    add_wasi_io_error_to_linker(&mut linker)?;
 
     // Now add the components we want to intercept using our custom implementations
     // We need to use a wrapper type pattern to make this work with the linker
     clocks::wall_clock::add_to_linker::<_, Intercept<T>>(&mut linker, |ctx| ctx)?;
     clocks::monotonic_clock::add_to_linker::<_, Intercept<T>>(&mut linker, |ctx| ctx)?;
     cli::environment::add_to_linker::<_, Intercept<T>>(&mut linker, |ctx| ctx)?;
+    cli::stdin::add_to_linker::<_, Intercept<T>>(&mut linker, |ctx| ctx)?;
+    cli::stdout::add_to_linker::<_, Intercept<T>>(&mut linker, |ctx| ctx)?;
+    cli::stderr::add_to_linker::<_, Intercept<T>>(&mut linker, |ctx| ctx)?;
     random::random::add_to_linker::<_, Intercept<T>>(&mut linker, |ctx| ctx)?;
     random::insecure::add_to_linker::<_, Intercept<T>>(&mut linker, |ctx| ctx)?;
     random::insecure_seed::add_to_linker::<_, Intercept<T>>(&mut linker, |ctx| ctx)?;
     filesystem::types::add_to_linker::<_, Intercept<T>>(&mut linker, |ctx| ctx)?;
+    filesystem::preopens::add_to_linker::<_, Intercept<T>>(&mut linker, |ctx| ctx)?;
     streams::add_to_linker::<_, Intercept<T>>(&mut linker, |ctx| ctx)?;
+    poll::add_to_linker::<_, Intercept<T>>(&mut linker, |ctx| ctx)?;
 
     // Add remaining WASI components that we don't need to intercept
     add_remaining_wasi_to_linker(&mut linker)?;
@@ -69,9 +83,8 @@ where
     Ok((engine, linker))
 }
 
-fn add_wasi_io_to_linker<T: WasiView>(linker: &mut Linker<T>) -> Result<()> {
+fn add_wasi_io_error_to_linker<T: WasiView>(linker: &mut Linker<T>) -> Result<()> {
     use wasmtime::component::ResourceTable;
-    use wasmtime_wasi::p2::bindings;
 
     struct HasIo;
     impl wasmtime::component::HasData for HasIo {
@@ -81,24 +94,21 @@ fn add_wasi_io_to_linker<T: WasiView>(linker: &mut Linker<T>) -> Result<()> {
     wasmtime_wasi_io::bindings::wasi::io::error::add_to_linker::<T, HasIo>(linker, |t| {
         t.ctx().table
     })?;
-    bindings::sync::io::poll::add_to_linker::<T, HasIo>(linker, |t| t.ctx().table)?;
 
     Ok(())
 }
 
 fn add_remaining_wasi_to_linker<T: WasiView + WasiHttpView>(linker: &mut Linker<T>) -> Result<()> {
     use wasmtime_wasi::cli::{WasiCli, WasiCliView};
-    use wasmtime_wasi::filesystem::{WasiFilesystem, WasiFilesystemView};
     use wasmtime_wasi::p2::bindings;
     use wasmtime_wasi::sockets::{WasiSockets, WasiSocketsView};
 
-    // Add CLI components (except environment which we intercept)
-    bindings::sync::cli::stdin::add_to_linker::<T, WasiCli>(linker, |ctx| ctx.cli())?;
-    bindings::sync::cli::stdout::add_to_linker::<T, WasiCli>(linker, |ctx| ctx.cli())?;
-    bindings::sync::cli::stderr::add_to_linker::<T, WasiCli>(linker, |ctx| ctx.cli())?;
-    bindings::sync::cli::exit::add_to_linker::<T, WasiCli>(linker, &Default::default(), |ctx| {
-        ctx.cli()
-    })?;
+    // Add CLI components (except environment and stdio which we intercept)
+    // Let guests exit with any code, not just success or failure, so record
+    // and replay can pass it on
+    let mut exit_options = bindings::sync::cli::exit::LinkOptions::default();
+    exit_options.cli_exit_with_code(true);
+    bindings::sync::cli::exit::add_to_linker::<T, WasiCli>(linker, &exit_options, |ctx| ctx.cli())?;
     bindings::sync::cli::terminal_input::add_to_linker::<T, WasiCli>(linker, |ctx| ctx.cli())?;
     bindings::sync::cli::terminal_output::add_to_linker::<T, WasiCli>(linker, |ctx| ctx.cli())?;
     bindings::sync::cli::terminal_stdin::add_to_linker::<T, WasiCli>(linker, |ctx| ctx.cli())?;
@@ -107,10 +117,7 @@ fn add_remaining_wasi_to_linker<T: WasiView + WasiHttpView>(linker: &mut Linker<
 
     // No clock components to add here - wall_clock and monotonic_clock are intercepted
 
-    // Add filesystem components
-    bindings::sync::filesystem::preopens::add_to_linker::<T, WasiFilesystem>(linker, |ctx| {
-        ctx.filesystem()
-    })?;
+    // No filesystem components to add here - types and preopens are intercepted
 
     // Note: random::insecure and random::insecure_seed are intercepted above
 
@@ -138,19 +145,119 @@ fn add_remaining_wasi_to_linker<T: WasiView + WasiHttpView>(linker: &mut Linker<
     Ok(())
 }
 
+/// A host directory exposed to the guest as a preopened directory
+#[derive(Debug, Clone)]
+pub struct DirMapping {
+    pub host: PathBuf,
+    pub guest: String,
+    pub writable: bool,
+}
+
+/// Parse a `HOST::GUEST` directory mapping; `GUEST` defaults to `HOST`
+///
+/// # Errors
+///
+/// Returns an error if either side of the mapping is empty
+pub fn parse_dir_mapping(value: &str) -> Result<DirMapping> {
+    let (host, guest) = value.split_once("::").unwrap_or((value, value));
+    if host.is_empty() || guest.is_empty() {
+        return Err(anyhow!(
+            "invalid directory mapping {value:?}, expected HOST::GUEST"
+        ));
+    }
+    Ok(DirMapping {
+        host: PathBuf::from(host),
+        guest: guest.to_string(),
+        writable: true,
+    })
+}
+
+/// Parse a `KEY=VALUE` environment variable
+///
+/// # Errors
+///
+/// Returns an error if there is no `=` or the key is empty
+pub fn parse_env_var(value: &str) -> Result<(String, String)> {
+    match value.split_once('=') {
+        Some((key, val)) if !key.is_empty() => Ok((key.to_string(), val.to_string())),
+        _ => Err(anyhow!(
+            "invalid environment variable {value:?}, expected KEY=VALUE"
+        )),
+    }
+}
+
+/// Which environment variables a recorded component gets to see
+#[derive(Debug, Clone, Default)]
+pub struct EnvOptions {
+    /// Variables set explicitly, taking precedence over inherited ones
+    pub vars: Vec<(String, String)>,
+    /// Pass through the whole host environment
+    pub inherit: bool,
+    /// Pass through host variables whose names match one of these patterns,
+    /// where `*` matches any run of characters
+    pub allow: Vec<String>,
+}
+
+impl EnvOptions {
+    /// The environment to hand to the component, in host order followed by
+    /// explicitly set variables
+    pub fn resolve(&self) -> Vec<(String, String)> {
+        let mut env: Vec<(String, String)> = std::env::vars_os()
+            .filter_map(|(key, val)| Some((key.into_string().ok()?, val.into_string().ok()?)))
+            .filter(|(key, _)| {
+                self.inherit || self.allow.iter().any(|pattern| glob_match(pattern, key))
+            })
+            .collect();
+        for (key, val) in &self.vars {
+            env.retain(|(existing, _)| existing != key);
+            env.push((key.clone(), val.clone()));
+        }
+        env
+    }
+}
+
 /// Build a WASI context for a given WASM component
-pub fn build_wasi_ctx(wasm_path: &Path, args: &[String]) -> WasiCtx {
+///
+/// # Errors
+///
+/// Returns an error if a preopened directory cannot be opened
+pub fn build_wasi_ctx(
+    wasm_path: &Path,
+    args: &[String],
+    dirs: &[DirMapping],
+    env: &[(String, String)],
+) -> Result<WasiCtx> {
     let mut builder = WasiCtxBuilder::new();
     builder.inherit_stdio();
+    builder.envs(env);
+
+    for dir in dirs {
+        let (dir_perms, file_perms) = if dir.writable {
+            (DirPerms::all(), FilePerms::all())
+        } else {
+            (DirPerms::READ, FilePerms::READ)
+        };
+        builder
+            .preopened_dir(&dir.host, &dir.guest, dir_perms, file_perms)
+            .with_context(|| {
+                format!("failed to preopen {} as {}", dir.host.display(), dir.guest)
+            })?;
+    }
 
+    for arg in guest_argv(wasm_path, args) {
+        builder.arg(arg);
+    }
+
+    Ok(builder.build())
+}
+
+/// The arguments a component sees: its file name followed by `args`
+pub fn guest_argv(wasm_path: &Path, args: &[String]) -> Vec<String> {
     let program_name = wasm_path
         .file_name()
         .and_then(|s| s.to_str())
         .unwrap_or("component");
-    builder.arg(program_name);
-    for arg in args {
-        builder.arg(arg);
-    }
-
-    builder.build()
+    std::iter::once(program_name.to_string())
+        .chain(args.iter().cloned())
+        .collect()
 }
@@ -24,6 +24,7 @@
 mod engine;
 mod playback;
 mod recorder;
+mod snapshot;
 mod trace;
 mod util;
 mod wasi;
@@ -33,8 +34,9 @@ use clap::{CommandFactory, Parser, Subcommand};
 use std::path::{Path, PathBuf};
 use trace::{convert, TraceFormat};
 use wasmtime::component::Component;
-use wasmtime::Store;
-use wasmtime_wasi::p2::bindings::{cli, clocks, random, sync::filesystem, sync::io::streams};
+use wasmtime::{Store, WasmBacktrace};
+use wasmtime_wasi::p2::bindings::sync::io::{poll, streams};
+use wasmtime_wasi::p2::bindings::{cli, clocks, random, sync::filesystem};
 use wasmtime_wasi::WasiView;
 use wasmtime_wasi_http::{WasiHttpCtx, WasiHttpView};
 
@@ -71,6 +73,37 @@ enum Command {
             value_parser = ["json", "cbor"]
         )]
         format: Option<String>,
+        /// Preopen a host directory read-write as HOST::GUEST (GUEST defaults to HOST)
+        #[arg(long = "dir", value_name = "HOST::GUEST", value_parser = engine::parse_dir_mapping)]
+        dirs: Vec<engine::DirMapping>,
+        /// Preopen a host directory read-only as HOST::GUEST (GUEST defaults to HOST)
+        #[arg(long = "ro-dir", value_name = "HOST::GUEST", value_parser = engine::parse_dir_mapping)]
+        ro_dirs: Vec<engine::DirMapping>,
+        /// Copy the preopened directories into a blob store next to the trace so
+        /// replay can serve any file from it, not just the ones that were read
+        #[arg(long = "snapshot")]
+        snapshot: bool,
+        /// Set an environment variable for the component as KEY=VALUE
+        #[arg(long = "env", value_name = "KEY=VALUE", value_parser = engine::parse_env_var)]
+        env: Vec<(String, String)>,
+        /// Pass the whole host environment through to the component
+        #[arg(long = "env-inherit")]
+        env_inherit: bool,
+        /// Pass through host environment variables whose names match PATTERN
+        /// (`*` matches any characters, e.g. `AWS_*`)
+        #[arg(long = "env-allow", value_name = "PATTERN")]
+        env_allow: Vec<String>,
+        /// Replace the values of environment variables matching PATTERN with
+        /// placeholders in the trace
+        #[arg(long = "redact-env", value_name = "PATTERN")]
+        redact_env: Vec<String>,
+        /// Replace the values of HTTP headers matching NAME with placeholders
+        /// in the trace; replay accepts any value for them
+        #[arg(long = "redact-header", value_name = "NAME")]
+        redact_headers: Vec<String>,
+        /// Load redaction patterns from a JSON file with `env` and `headers` lists
+        #[arg(long = "redact-config", value_name = "FILE")]
+        redact_config: Option<PathBuf>,
         /// Arguments to forward to the component (use `--` to separate)
         #[arg(value_name = "ARGS", num_args = 0.., trailing_var_arg = true)]
         args: Vec<String>,
@@ -90,6 +123,10 @@ enum Command {
             value_parser = ["json", "cbor"]
         )]
         format: Option<String>,
+        /// Replay even if the component differs from the one that was recorded,
+        /// only warning about it
+        #[arg(long = "allow-component-mismatch")]
+        allow_component_mismatch: bool,
     },
     /// Convert a trace file between JSON and CBOR formats
     Convert {
@@ -112,32 +149,188 @@ enum Command {
         )]
         output_format: Option<String>,
     },
+    /// Recover every complete event from a trace that was cut off, e.g. because
+    /// recording was killed, and write it out as a valid trace marked incomplete
+    Repair {
+        /// Damaged trace file
+        input: PathBuf,
+        /// Output trace file (extension determines format: .json or .cbor)
+        output: PathBuf,
+        /// Input format (json or cbor). If not specified, inferred from file extension
+        #[arg(
+            long = "input-format",
+            value_name = "FORMAT",
+            value_parser = ["json", "cbor"]
+        )]
+        input_format: Option<String>,
+        /// Output format (json or cbor). If not specified, inferred from file extension
+        #[arg(
+            long = "output-format",
+            value_name = "FORMAT",
+            value_parser = ["json", "cbor"]
+        )]
+        output_format: Option<String>,
+    },
+    /// Rewrite traces in place under the current trace schema, so they no
+    /// longer depend on being upgraded every time they are loaded
+    Migrate {
+        /// Trace files to migrate
+        #[arg(required = true)]
+        traces: Vec<PathBuf>,
+        /// Trace format (json or cbor). If not specified, inferred from each file's extension
+        #[arg(
+            short = 'f',
+            long = "format",
+            value_name = "FORMAT",
+            value_parser = ["json", "cbor"]
+        )]
+        format: Option<String>,
+    },
+}
+
+/// What the recorded component gets to see, and what the trace keeps of it
+struct RecordOptions {
+    args: Vec<String>,
+    dirs: Vec<engine::DirMapping>,
+    snapshot: bool,
+    env: engine::EnvOptions,
+    redaction: trace::RedactionPolicy,
 }
 
 /// Record a WASM component execution, capturing all non-deterministic host calls
-fn record(wasm: &Path, trace: &Path, format: TraceFormat, args: &[String]) -> Result<()> {
-    let wasi = engine::build_wasi_ctx(wasm, args);
+///
+/// Returns the guest's exit code.
+fn record(wasm: &Path, trace: &Path, format: TraceFormat, options: RecordOptions) -> Result<i32> {
+    let RecordOptions {
+        args,
+        dirs,
+        snapshot,
+        env,
+        redaction,
+    } = options;
+    let wasi = engine::build_wasi_ctx(wasm, &args, &dirs, &env.resolve())?;
     let http = WasiHttpCtx::new();
-    let ctx = recorder::CtxRecorder::new(
-        wasi,
-        http,
-        recorder::Recorder::new(trace.to_path_buf(), format),
-    );
-    let ctx = run_wasm_with_wasi(wasm, ctx)?;
-    ctx.into_recorder().save()
+    let header = trace::TraceHeader::new(wasm, engine::guest_argv(wasm, &args))?;
+    let mut recorder = recorder::Recorder::new(trace.to_path_buf(), format, &header);
+    recorder.set_redaction(redaction);
+    if snapshot {
+        let store_path = snapshot::store_path(trace);
+        let store = snapshot::BlobStore::create(store_path.clone())?;
+        let contents = snapshot::capture(&dirs, &store)?;
+        let name = store_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        recorder.record_snapshot(name, contents);
+    }
+    let ctx = recorder::CtxRecorder::new(wasi, http, recorder);
+    let (ctx, outcome) = run_wasm_with_wasi(wasm, ctx)?;
+    let mut recorder = ctx.into_recorder();
+    match outcome {
+        Outcome::Exit(code) => {
+            recorder.record_exit(code);
+            recorder.save()?;
+            Ok(code)
+        }
+        Outcome::Trap(error) => {
+            // Crashes are the runs most worth replaying, so the trace is
+            // still finished properly before reporting the trap
+            let (message, backtrace) = describe_trap(&error);
+            recorder.record_trap(message, backtrace);
+            recorder.save()?;
+            Err(error)
+        }
+    }
 }
 
 /// Replay a previously recorded WASM component execution from a trace file
-fn replay(wasm: &Path, trace: &Path, format: TraceFormat) -> Result<()> {
-    let playback = playback::Playback::from_file(trace, format)?;
-    let wasi = engine::build_wasi_ctx(wasm, &[]);
+///
+/// Returns the guest's exit code, which has been checked against the trace.
+fn replay(
+    wasm: &Path,
+    trace: &Path,
+    format: TraceFormat,
+    allow_component_mismatch: bool,
+) -> Result<i32> {
+    let mut playback = playback::Playback::from_file(trace, format)?;
+    // Traces migrated from before headers existed do not know their component
+    if let Some(expected) = &playback.header().component_sha256 {
+        // A different component diverges from the trace sooner or later with
+        // a confusing mismatch, so catch it up front
+        let digest = trace::component_digest(wasm)?;
+        if &digest != expected {
+            let message = format!(
+                "{} (sha256 {digest}) is not the component {} was recorded from (sha256 {expected})",
+                wasm.display(),
+                trace.display(),
+            );
+            if !allow_component_mismatch {
+                anyhow::bail!("{message}; pass --allow-component-mismatch to replay anyway");
+            }
+            util::warn(message);
+        }
+    }
+    let snapshot = playback
+        .next_snapshot()?
+        .map(|(store, dirs)| {
+            let store = snapshot::BlobStore::open(trace.with_file_name(store));
+            wasi::snapshot::Snapshot::mount(store, dirs)
+        })
+        .transpose()?;
+    // Preopens are handed out from the trace's get_directories event, so no
+    // host directories are needed
+    let wasi = engine::build_wasi_ctx(wasm, &[], &[], &[])?;
     let http = WasiHttpCtx::new();
-    let ctx = playback::CtxPlayback::new(wasi, http, playback);
-    let ctx = run_wasm_with_wasi(wasm, ctx)?;
-    ctx.into_playback().finish()
+    let ctx = playback::CtxPlayback::new(wasi, http, playback, snapshot);
+    let (ctx, outcome) = run_wasm_with_wasi(wasm, ctx)?;
+    let mut playback = ctx.into_playback()?;
+    match outcome {
+        Outcome::Exit(code) => {
+            playback.expect_exit(code)?;
+            playback.finish()?;
+            Ok(code)
+        }
+        Outcome::Trap(error) => {
+            // A trap the recording does not end with is where replay diverged,
+            // so report the trap itself rather than the trace's expectations
+            let (message, _) = describe_trap(&error);
+            if playback.expect_trap(&message).is_err() {
+                return Err(error);
+            }
+            playback.finish()?;
+            Err(error.context("the guest trapped, as it did when recorded"))
+        }
+    }
+}
+
+/// How a guest run ended
+enum Outcome {
+    /// The guest returned from `run` or called `exit` with this code
+    Exit(i32),
+    /// The guest was aborted by a trap
+    Trap(anyhow::Error),
 }
 
-fn run_wasm_with_wasi<P, T>(wasm_path: P, ctx: T) -> Result<T>
+/// Split a trap into its message and, when wasmtime captured one, the wasm
+/// backtrace
+fn describe_trap(error: &anyhow::Error) -> (String, Option<String>) {
+    let backtrace = error
+        .downcast_ref::<WasmBacktrace>()
+        .map(ToString::to_string);
+    // The backtrace is attached as context, so it shows up in the chain too
+    let message = error
+        .chain()
+        .map(ToString::to_string)
+        .filter(|cause| Some(cause) != backtrace.as_ref())
+        .collect::<Vec<_>>()
+        .join(": ");
+    (message, backtrace)
+}
+
+/// Run the component's `wasi:cli/run` export, returning the context and how
+/// the guest finished
+fn run_wasm_with_wasi<P, T>(wasm_path: P, ctx: T) -> Result<(T, Outcome)>
 where
     P: AsRef<Path>,
     T: WasiView
@@ -145,15 +338,21 @@ where
         + clocks::wall_clock::Host
         + clocks::monotonic_clock::Host
         + cli::environment::Host
+        + cli::stdin::Host
+        + cli::stdout::Host
+        + cli::stderr::Host
         + random::random::Host
         + random::insecure::Host
         + random::insecure_seed::Host
+        + filesystem::preopens::Host
         + filesystem::types::Host
         + filesystem::types::HostDescriptor
         + filesystem::types::HostDirectoryEntryStream
         + streams::Host
         + streams::HostInputStream
         + streams::HostOutputStream
+        + poll::Host
+        + poll::HostPollable
         + 'static,
 {
     let wasm_path = wasm_path.as_ref();
@@ -189,24 +388,22 @@ where
     let typed = func.typed::<(), (Result<(), ()>,)>(&store)?;
 
     // Try to call the function, but handle the case where it exits
-    match typed.call(&mut store, ()) {
+    let outcome = match typed.call(&mut store, ()) {
         Ok((result,)) => {
             // Required, see documentation of TypedFunc::call
             typed.post_return(&mut store)?;
-            result.map_err(|_| anyhow::anyhow!("error"))?;
-        }
-        Err(e) => {
-            // Check if this is an exit error using proper downcasting
-            if e.downcast_ref::<wasmtime_wasi::I32Exit>().is_none() {
-                // If it's not an exit error, propagate it
-                return Err(e);
-            }
-            // If it's an exit error, we've already recorded the trace,
-            // so we can continue and let the error propagate naturally
+            // `run` returning an error is how a guest reports failure without
+            // calling `exit`; wasmtime's own CLI exits with 1 for it too
+            Outcome::Exit(if result.is_ok() { 0 } else { 1 })
         }
-    }
+        Err(e) => match e.downcast_ref::<wasmtime_wasi::I32Exit>() {
+            Some(exit) => Outcome::Exit(exit.0),
+            // Anything else is a trap, which aborts the run
+            None => Outcome::Trap(e),
+        },
+    };
 
-    Ok(store.into_data())
+    Ok((store.into_data(), outcome))
 }
 
 fn main() -> Result<()> {
@@ -234,23 +431,68 @@ fn main() -> Result<()> {
         std::process::exit(1);
     };
 
-    match command {
+    let code = match command {
         Command::Record {
             wasm,
             trace,
             format,
+            dirs,
+            ro_dirs,
+            snapshot,
+            env,
+            env_inherit,
+            env_allow,
+            redact_env,
+            redact_headers,
+            redact_config,
             args,
         } => {
             let format = TraceFormat::from_path_and_option(&trace, format.as_deref())?;
-            record(wasm.as_path(), trace.as_path(), format, &args)
+            let dirs: Vec<_> = dirs
+                .into_iter()
+                .chain(ro_dirs.into_iter().map(|dir| engine::DirMapping {
+                    writable: false,
+                    ..dir
+                }))
+                .collect();
+            let mut redaction = match redact_config {
+                Some(path) => trace::RedactionPolicy::load(&path)?,
+                None => trace::RedactionPolicy::default(),
+            };
+            redaction.extend(trace::RedactionPolicy {
+                env: redact_env,
+                headers: redact_headers,
+            });
+            record(
+                wasm.as_path(),
+                trace.as_path(),
+                format,
+                RecordOptions {
+                    args,
+                    dirs,
+                    snapshot,
+                    env: engine::EnvOptions {
+                        vars: env,
+                        inherit: env_inherit,
+                        allow: env_allow,
+                    },
+                    redaction,
+                },
+            )
         }
         Command::Replay {
             wasm,
             trace,
             format,
+            allow_component_mismatch,
         } => {
             let format = TraceFormat::from_path_and_option(&trace, format.as_deref())?;
-            replay(wasm.as_path(), trace.as_path(), format)
+            replay(
+                wasm.as_path(),
+                trace.as_path(),
+                format,
+                allow_component_mismatch,
+            )
         }
         Command::Convert {
             input,
@@ -267,6 +509,48 @@ fn main() -> Result<()> {
                 input_format,
                 output_format,
             )
+            .map(|()| 0)
         }
+        Command::Repair {
+            input,
+            output,
+            input_format,
+            output_format,
+        } => {
+            let input_format = TraceFormat::from_path_and_option(&input, input_format.as_deref())?;
+            let output_format =
+                TraceFormat::from_path_and_option(&output, output_format.as_deref())?;
+            let (recovered, unfinished) = trace::repair(
+                input.as_path(),
+                output.as_path(),
+                input_format,
+                output_format,
+            )?;
+            if unfinished {
+                util::warn(format!(
+                    "the recording in {} did not finish; kept {recovered} events and marked the trace incomplete",
+                    input.display()
+                ));
+            }
+            Ok(0)
+        }
+        Command::Migrate { traces, format } => {
+            for trace in &traces {
+                let format = TraceFormat::from_path_and_option(trace, format.as_deref())?;
+                if let Some(schema) = trace::migrate(trace, format)? {
+                    util::warn(format!(
+                        "migrated {} from schema {schema} to {}",
+                        trace.display(),
+                        trace::SCHEMA_VERSION
+                    ));
+                }
+            }
+            Ok(0)
+        }
+    }?;
+
+    if code != 0 {
+        std::process::exit(code);
     }
+    Ok(())
 }
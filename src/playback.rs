@@ -1,38 +1,71 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
+use std::pin::Pin;
+use std::task::Poll;
 use std::time::Duration;
 
 use anyhow::anyhow;
 use anyhow::Context;
 use bytes::Bytes;
-use http_body_util::{BodyExt, Full};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame};
 use wasmtime::component::{Resource, ResourceTable};
+use wasmtime_wasi::cli::WasiCliView as _;
 use wasmtime_wasi::filesystem::WasiFilesystemView as _;
+use wasmtime_wasi::p2::bindings::filesystem::types::ErrorCode as FsErrorCode;
 use wasmtime_wasi::p2::bindings::sync::io::{poll, streams};
 use wasmtime_wasi::p2::bindings::{cli, clocks, random, sync::filesystem};
+use wasmtime_wasi::p2::pipe::{ClosedInputStream, SinkOutputStream};
 use wasmtime_wasi::p2::{FsError, FsResult, StreamError, StreamResult};
+use wasmtime_wasi::runtime::{self, AbortOnDropJoinHandle};
 use wasmtime_wasi::{WasiCtx, WasiCtxView, WasiView};
+use wasmtime_wasi_http::bindings::http::types::ErrorCode;
 use wasmtime_wasi_http::types::{
     HostFutureIncomingResponse, IncomingResponse, OutgoingRequestConfig,
 };
 use wasmtime_wasi_http::{HttpError, WasiHttpCtx, WasiHttpView};
 
-use crate::trace::{TraceEvent, TraceFile, TraceFormat};
-use crate::util::cbor::is_cbor_eof;
-use crate::wasi::util::{header_map_from_pairs, sorted_headers};
+use crate::trace::{
+    is_finished, recover, CborEvents, CborItem, FileTimestamp, HttpBodyFrame, HttpErrorCode,
+    HttpRequestConfig, Recovered, SnapshotDir, StreamOrigin, TraceEvent, TraceFormat, TraceHeader,
+};
+use crate::util::warn;
+use crate::wasi::clocks::VirtualClock;
+use crate::wasi::filesystem::{
+    descriptor_flag_names, descriptor_flags_from_names, descriptor_type_from_name, open_flag_names,
+    path_flag_names, recorded_timestamp_update, replay_error_code, replayed_entry, replayed_hash,
+    replayed_stat, replayed_timestamp_update, ReplayDescriptor, ReplayDirectoryStream,
+};
+use crate::wasi::handles::HandleRegistry;
+use crate::wasi::http;
+use crate::wasi::snapshot::{Snapshot, SnapshotView};
+use crate::wasi::util::{header_map_from_pairs, sorted_headers, MAX_BLOCKING_WRITE};
 use anyhow::Result;
 
 enum PlaybackSource {
     /// All events loaded in memory (used for JSON traces)
     Memory(VecDeque<TraceEvent>),
     /// Streaming from a CBOR file
-    Stream(BufReader<File>),
+    Stream(Box<CborEvents<BufReader<File>>>),
 }
 
 pub struct Playback {
     source: PlaybackSource,
+    header: TraceHeader,
+    /// Whether the trace captured stream and poll traffic that replay must match
+    io_events: bool,
+    /// Number of bytes verified so far on each output stream
+    output_offsets: HashMap<u32, u64>,
+    /// Output streams from interfaces we do not intercept, which carry HTTP
+    /// request bodies; those are checked request by request instead
+    request_body_streams: HashSet<u32>,
+    /// Events read ahead, by `peek_event` or while looking for the outcome or
+    /// body of an HTTP request, and not consumed yet
+    ahead: VecDeque<TraceEvent>,
+    /// Whether filesystem calls served from a snapshot were traced as well
+    checks_snapshot: bool,
 }
 
 impl Playback {
@@ -41,34 +74,101 @@ impl Playback {
             .with_context(|| format!("failed to open trace file at {}", path.display()))?;
         let reader = BufReader::new(file);
 
-        let source = match format {
+        // Traces recorded before stream and poll capture carry no `stream_create`
+        // events; replaying them must not fail on the guest's first stdio access.
+        let (header, source, io_events) = match format {
             TraceFormat::Json => {
-                let TraceFile { events } = serde_json::from_reader(reader).with_context(|| {
-                    format!("failed to parse JSON trace file at {}", path.display())
-                })?;
-                PlaybackSource::Memory(events.into())
+                let Recovered {
+                    header,
+                    mut events,
+                    truncated,
+                    ..
+                } = recover(path, format)?;
+                // Losing just the closing brackets loses nothing worth a warning
+                if truncated && !is_finished(&events) {
+                    warn_truncated(path, events.len());
+                    events.push(TraceEvent::Incomplete);
+                }
+                let io_events = events.iter().any(is_stream_create);
+                (header, PlaybackSource::Memory(events.into()), io_events)
             }
             TraceFormat::Cbor => {
-                // For CBOR, we stream events on demand instead of loading all at once
-                PlaybackSource::Stream(reader)
+                // For CBOR, we stream events on demand instead of loading all
+                // at once, so a cut-off tail has to be looked for separately
+                let scan = scan_cbor(path)?;
+                if scan.truncated && !scan.finished {
+                    warn_truncated(path, scan.events);
+                }
+                let (header, events) = CborEvents::new(reader).with_context(|| {
+                    format!("failed to read CBOR trace file at {}", path.display())
+                })?;
+                (
+                    header,
+                    PlaybackSource::Stream(Box::new(events)),
+                    scan.io_events,
+                )
             }
         };
 
-        Ok(Self { source })
+        Ok(Self {
+            source,
+            header,
+            io_events,
+            output_offsets: HashMap::new(),
+            request_body_streams: HashSet::new(),
+            ahead: VecDeque::new(),
+            checks_snapshot: false,
+        })
+    }
+
+    /// Describes the recording, as far as the trace's schema version records it
+    pub fn header(&self) -> &TraceHeader {
+        &self.header
     }
 
     pub fn next_event(&mut self) -> Result<TraceEvent> {
+        match self.read_event()? {
+            Some(TraceEvent::Incomplete) => Err(anyhow!(
+                "trace is incomplete: the recording was cut off before the guest got this far"
+            )),
+            Some(event) => Ok(event),
+            None => Err(anyhow!("trace exhausted")),
+        }
+    }
+
+    /// Read the next event, or `None` at the end of the trace
+    fn read_event(&mut self) -> Result<Option<TraceEvent>> {
+        if let Some(event) = self.ahead.pop_front() {
+            return Ok(Some(event));
+        }
+        self.read_source_event()
+    }
+
+    /// Read the next event from the trace itself, past any read ahead
+    fn read_source_event(&mut self) -> Result<Option<TraceEvent>> {
         match &mut self.source {
-            PlaybackSource::Memory(events) => events.pop_front().ok_or(anyhow!("trace exhausted")),
-            PlaybackSource::Stream(reader) => {
-                match ciborium::from_reader::<TraceEvent, _>(&mut *reader) {
-                    Ok(event) => Ok(event),
-                    Err(e) if is_cbor_eof(&e) => Err(anyhow!("trace exhausted")),
-                    Err(e) => Err(anyhow::Error::msg(format!("{}", e)))
-                        .context("failed to read next event from CBOR trace"),
-                }
+            PlaybackSource::Memory(events) => Ok(events.pop_front()),
+            PlaybackSource::Stream(reader) => match reader
+                .next_item()
+                .context("failed to read next event from CBOR trace")?
+            {
+                CborItem::Event(event) => Ok(Some(*event)),
+                CborItem::End => Ok(None),
+                // The file ends partway through an event, so the recording
+                // was cut off
+                CborItem::Truncated => Ok(Some(TraceEvent::Incomplete)),
+            },
+        }
+    }
+
+    /// Look at the next event without consuming it
+    fn peek_event(&mut self) -> Result<Option<&TraceEvent>> {
+        if self.ahead.is_empty() {
+            if let Some(event) = self.read_source_event()? {
+                self.ahead.push_back(event);
             }
         }
+        Ok(self.ahead.front())
     }
 
     pub fn next_now(&mut self) -> Result<clocks::wall_clock::Datetime> {
@@ -186,66 +286,1154 @@ impl Playback {
         }
     }
 
-    pub fn next_insecure_random_u64(&mut self) -> Result<u64> {
-        match self.next_event()? {
-            TraceEvent::InsecureRandomU64 { value } => Ok(value),
-            other => Err(anyhow!(
-                "expected next insecure_random_u64 event, got {:?}",
-                other
-            )),
+    pub fn next_insecure_random_u64(&mut self) -> Result<u64> {
+        match self.next_event()? {
+            TraceEvent::InsecureRandomU64 { value } => Ok(value),
+            other => Err(anyhow!(
+                "expected next insecure_random_u64 event, got {:?}",
+                other
+            )),
+        }
+    }
+
+    pub fn next_insecure_seed(&mut self) -> Result<(u64, u64)> {
+        match self.next_event()? {
+            TraceEvent::InsecureSeed { seed } => Ok(seed),
+            other => Err(anyhow!(
+                "expected next insecure_seed event, got {:?}",
+                other
+            )),
+        }
+    }
+
+    /// Returns the blob store and contents of the preopened directories when
+    /// they were snapshotted at the start of recording
+    pub fn next_snapshot(&mut self) -> Result<Option<(String, Vec<SnapshotDir>)>> {
+        if !matches!(self.peek_event()?, Some(TraceEvent::Snapshot { .. })) {
+            return Ok(None);
+        }
+        match self.next_event()? {
+            TraceEvent::Snapshot {
+                store,
+                dirs,
+                calls_traced,
+            } => {
+                self.checks_snapshot = calls_traced;
+                Ok(Some((store, dirs)))
+            }
+            other => Err(anyhow!("expected next snapshot event, got {:?}", other)),
+        }
+    }
+
+    /// Whether replay should check the snapshot against traced filesystem calls
+    pub fn checks_snapshot(&self) -> bool {
+        self.checks_snapshot
+    }
+
+    /// Returns the descriptor ids and guest paths of the recorded preopens
+    pub fn next_get_directories(&mut self) -> Result<Vec<(u32, String)>> {
+        // Traces from before preopens were intercepted never answer this call,
+        // and those runs could not have had any preopened directories
+        if !matches!(self.peek_event()?, Some(TraceEvent::GetDirectories { .. })) {
+            return Ok(Vec::new());
+        }
+        match self.next_event()? {
+            TraceEvent::GetDirectories { dirs } => Ok(dirs),
+            other => Err(anyhow!(
+                "expected next get_directories event, got {:?}",
+                other
+            )),
+        }
+    }
+
+    /// Returns the recorded outcome of the next file read
+    pub fn next_file_read(
+        &mut self,
+        descriptor: Option<u32>,
+        len: u64,
+        offset: u64,
+    ) -> FsResult<(Vec<u8>, bool)> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::FileRead {
+                descriptor: expected_descriptor,
+                offset: expected_offset,
+                bytes,
+                eof,
+                error,
+            } => {
+                check_descriptor("file read", expected_descriptor, descriptor)?;
+                if offset != expected_offset {
+                    return Err(FsError::trap(anyhow!(
+                        "file read offset mismatch: expected {}, got {}",
+                        expected_offset,
+                        offset
+                    )));
+                }
+                if let Some(code) = error {
+                    return Err(replay_error_code(&code));
+                }
+                if bytes.len() as u64 > len {
+                    return Err(FsError::trap(anyhow!(
+                        "file read length mismatch: requested at most {}, recorded {}",
+                        len,
+                        bytes.len()
+                    )));
+                }
+                Ok((bytes, eof))
+            }
+            // Replay serves descriptors from the trace, so there is no file
+            // left to read the data from
+            TraceEvent::Read => Err(FsError::trap(anyhow!(
+                "the trace only marks this file read without its data; \
+                 it predates recorded file reads, re-record it to replay this run"
+            ))),
+            other => Err(FsError::trap(anyhow!(
+                "expected next file_read event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    /// Returns the descriptor id assigned by a recorded `open_at`, after checking
+    /// the guest asks for the same path with the same flags.
+    pub fn next_open_at(
+        &mut self,
+        dir: Option<u32>,
+        path: &str,
+        path_flags: filesystem::types::PathFlags,
+        open_flags: filesystem::types::OpenFlags,
+        descriptor_flags: filesystem::types::DescriptorFlags,
+    ) -> FsResult<u32> {
+        let path_flags = path_flag_names(path_flags);
+        let open_flags = open_flag_names(open_flags);
+        let descriptor_flags = descriptor_flag_names(descriptor_flags);
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::OpenAt {
+                dir: expected_dir,
+                path: expected_path,
+                path_flags: expected_path_flags,
+                open_flags: expected_open_flags,
+                descriptor_flags: expected_descriptor_flags,
+                descriptor,
+                error,
+            } => {
+                if dir != expected_dir || path != expected_path {
+                    return Err(FsError::trap(anyhow!(
+                        "open_at mismatch: expected {:?} in directory {:?}, got {:?} in directory {:?}",
+                        expected_path,
+                        expected_dir,
+                        path,
+                        dir
+                    )));
+                }
+                if path_flags != expected_path_flags
+                    || open_flags != expected_open_flags
+                    || descriptor_flags != expected_descriptor_flags
+                {
+                    return Err(FsError::trap(anyhow!(
+                        "open_at flags mismatch for {:?}: expected {:?} {:?} {:?}, got {:?} {:?} {:?}",
+                        path,
+                        expected_path_flags,
+                        expected_open_flags,
+                        expected_descriptor_flags,
+                        path_flags,
+                        open_flags,
+                        descriptor_flags
+                    )));
+                }
+                recorded_result("open_at", descriptor, error)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next open_at event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub fn expect_descriptor_drop(&mut self, descriptor: u32) -> Result<()> {
+        match self.next_event()? {
+            TraceEvent::DescriptorDrop {
+                descriptor: expected_descriptor,
+            } if expected_descriptor == descriptor => Ok(()),
+            other => Err(anyhow!(
+                "expected descriptor_drop event for descriptor {}, got {:?}",
+                descriptor,
+                other
+            )),
+        }
+    }
+
+    /// Returns the directory stream id assigned by a recorded `read_directory`
+    pub fn next_read_directory(&mut self, dir: Option<u32>) -> FsResult<u32> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::ReadDirectory {
+                dir: expected_dir,
+                stream,
+                error,
+            } => {
+                check_descriptor("read_directory", expected_dir, dir)?;
+                recorded_result("read_directory", stream, error)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next read_directory event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub fn next_read_directory_entry(
+        &mut self,
+        stream: Option<u32>,
+    ) -> FsResult<Option<filesystem::types::DirectoryEntry>> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::ReadDirectoryEntry {
+                stream: expected_stream,
+                entry,
+                error,
+            } => {
+                if stream != expected_stream {
+                    return Err(FsError::trap(anyhow!(
+                        "read_directory_entry mismatch: expected directory stream {:?}, got {:?}",
+                        expected_stream,
+                        stream
+                    )));
+                }
+                if let Some(code) = error {
+                    return Err(replay_error_code(&code));
+                }
+                entry.map(replayed_entry).transpose()
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next read_directory_entry event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub fn expect_directory_stream_drop(&mut self, stream: u32) -> Result<()> {
+        match self.next_event()? {
+            TraceEvent::DirectoryStreamDrop {
+                stream: expected_stream,
+            } if expected_stream == stream => Ok(()),
+            other => Err(anyhow!(
+                "expected directory_stream_drop event for stream {}, got {:?}",
+                stream,
+                other
+            )),
+        }
+    }
+
+    pub fn next_stat(
+        &mut self,
+        descriptor: Option<u32>,
+    ) -> FsResult<filesystem::types::DescriptorStat> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::Stat {
+                descriptor: expected_descriptor,
+                stat,
+                error,
+            } => {
+                check_descriptor("stat", expected_descriptor, descriptor)?;
+                replayed_stat(recorded_result("stat", stat, error)?)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next stat event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub fn next_stat_at(
+        &mut self,
+        dir: Option<u32>,
+        path: &str,
+        path_flags: filesystem::types::PathFlags,
+    ) -> FsResult<filesystem::types::DescriptorStat> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::StatAt {
+                dir: expected_dir,
+                path: expected_path,
+                path_flags: expected_path_flags,
+                stat,
+                error,
+            } => {
+                check_path(
+                    "stat_at",
+                    (expected_dir, &expected_path, &expected_path_flags),
+                    (dir, path, &path_flag_names(path_flags)),
+                )?;
+                replayed_stat(recorded_result("stat_at", stat, error)?)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next stat_at event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub fn next_metadata_hash(
+        &mut self,
+        descriptor: Option<u32>,
+    ) -> FsResult<filesystem::types::MetadataHashValue> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::MetadataHash {
+                descriptor: expected_descriptor,
+                hash,
+                error,
+            } => {
+                check_descriptor("metadata_hash", expected_descriptor, descriptor)?;
+                recorded_result("metadata_hash", hash, error).map(replayed_hash)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next metadata_hash event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub fn next_metadata_hash_at(
+        &mut self,
+        dir: Option<u32>,
+        path: &str,
+        path_flags: filesystem::types::PathFlags,
+    ) -> FsResult<filesystem::types::MetadataHashValue> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::MetadataHashAt {
+                dir: expected_dir,
+                path: expected_path,
+                path_flags: expected_path_flags,
+                hash,
+                error,
+            } => {
+                check_path(
+                    "metadata_hash_at",
+                    (expected_dir, &expected_path, &expected_path_flags),
+                    (dir, path, &path_flag_names(path_flags)),
+                )?;
+                recorded_result("metadata_hash_at", hash, error).map(replayed_hash)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next metadata_hash_at event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub fn next_get_type(
+        &mut self,
+        descriptor: Option<u32>,
+    ) -> FsResult<filesystem::types::DescriptorType> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::GetType {
+                descriptor: expected_descriptor,
+                kind,
+                error,
+            } => {
+                check_descriptor("get_type", expected_descriptor, descriptor)?;
+                descriptor_type_from_name(&recorded_result("get_type", kind, error)?)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next get_type event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub fn next_get_flags(
+        &mut self,
+        descriptor: Option<u32>,
+    ) -> FsResult<filesystem::types::DescriptorFlags> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::GetFlags {
+                descriptor: expected_descriptor,
+                flags,
+                error,
+            } => {
+                check_descriptor("get_flags", expected_descriptor, descriptor)?;
+                descriptor_flags_from_names(&recorded_result("get_flags", flags, error)?)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next get_flags event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub fn next_readlink_at(&mut self, dir: Option<u32>, path: &str) -> FsResult<String> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::ReadlinkAt {
+                dir: expected_dir,
+                path: expected_path,
+                target,
+                error,
+            } => {
+                check_path(
+                    "readlink_at",
+                    (expected_dir, &expected_path, &[]),
+                    (dir, path, &[]),
+                )?;
+                recorded_result("readlink_at", target, error)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next readlink_at event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub fn next_is_same_object(&mut self, a: Option<u32>, b: Option<u32>) -> Result<bool> {
+        match self.next_event()? {
+            TraceEvent::IsSameObject {
+                a: expected_a,
+                b: expected_b,
+                same,
+            } if expected_a == a && expected_b == b => Ok(same),
+            other => Err(anyhow!(
+                "expected is_same_object event for descriptors {:?} and {:?}, got {:?}",
+                a,
+                b,
+                other
+            )),
+        }
+    }
+
+    pub fn next_advise(&mut self, descriptor: Option<u32>) -> FsResult<()> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::Advise {
+                descriptor: expected_descriptor,
+                error,
+            } => {
+                check_descriptor("advise", expected_descriptor, descriptor)?;
+                recorded_result("advise", Some(()), error)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next advise event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub fn next_sync(&mut self, descriptor: Option<u32>) -> FsResult<()> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::Sync {
+                descriptor: expected_descriptor,
+                error,
+            } => {
+                check_descriptor("sync", expected_descriptor, descriptor)?;
+                recorded_result("sync", Some(()), error)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next sync event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub fn next_sync_data(&mut self, descriptor: Option<u32>) -> FsResult<()> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::SyncData {
+                descriptor: expected_descriptor,
+                error,
+            } => {
+                check_descriptor("sync_data", expected_descriptor, descriptor)?;
+                recorded_result("sync_data", Some(()), error)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next sync_data event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    /// Check a write names the same descriptor, offset and bytes as recorded,
+    /// pointing at the first differing byte otherwise
+    pub fn next_file_write(
+        &mut self,
+        descriptor: Option<u32>,
+        offset: u64,
+        bytes: &[u8],
+    ) -> FsResult<u64> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::FileWrite {
+                descriptor: expected_descriptor,
+                offset: expected_offset,
+                bytes: expected,
+                written,
+                error,
+            } => {
+                check_descriptor("write", expected_descriptor, descriptor)?;
+                check_args("write offset", expected_offset, offset)?;
+                if let Some(index) = first_mismatch(&expected, bytes) {
+                    return Err(FsError::trap(anyhow!(
+                        "write mismatch on descriptor {} at file offset {}: expected {}, got {}",
+                        descriptor.map_or_else(|| "?".to_string(), |id| id.to_string()),
+                        offset + index as u64,
+                        describe_byte(expected.get(index)),
+                        describe_byte(bytes.get(index))
+                    )));
+                }
+                recorded_result("write", written, error)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next file_write event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub fn next_set_size(&mut self, descriptor: Option<u32>, size: u64) -> FsResult<()> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::SetSize {
+                descriptor: expected_descriptor,
+                size: expected_size,
+                error,
+            } => {
+                check_descriptor("set_size", expected_descriptor, descriptor)?;
+                check_args("set_size", expected_size, size)?;
+                recorded_result("set_size", Some(()), error)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next set_size event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    /// Returns the time the recorded call took for its `now` updates, if the
+    /// trace has it
+    pub fn next_set_times(
+        &mut self,
+        descriptor: Option<u32>,
+        access: filesystem::types::NewTimestamp,
+        modification: filesystem::types::NewTimestamp,
+    ) -> FsResult<Option<FileTimestamp>> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::SetTimes {
+                descriptor: expected_descriptor,
+                access: expected_access,
+                modification: expected_modification,
+                now,
+                error,
+            } => {
+                check_descriptor("set_times", expected_descriptor, descriptor)?;
+                check_args(
+                    "set_times",
+                    (expected_access, expected_modification),
+                    (
+                        recorded_timestamp_update(access),
+                        recorded_timestamp_update(modification),
+                    ),
+                )?;
+                recorded_result("set_times", Some(now), error)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next set_times event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    /// Returns the time the recorded call took for its `now` updates, if the
+    /// trace has it
+    pub fn next_set_times_at(
+        &mut self,
+        dir: Option<u32>,
+        path: &str,
+        path_flags: filesystem::types::PathFlags,
+        access: filesystem::types::NewTimestamp,
+        modification: filesystem::types::NewTimestamp,
+    ) -> FsResult<Option<FileTimestamp>> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::SetTimesAt {
+                dir: expected_dir,
+                path: expected_path,
+                path_flags: expected_flags,
+                access: expected_access,
+                modification: expected_modification,
+                now,
+                error,
+            } => {
+                check_path(
+                    "set_times_at",
+                    (expected_dir, &expected_path, &expected_flags),
+                    (dir, path, &path_flag_names(path_flags)),
+                )?;
+                check_args(
+                    "set_times_at",
+                    (expected_access, expected_modification),
+                    (
+                        recorded_timestamp_update(access),
+                        recorded_timestamp_update(modification),
+                    ),
+                )?;
+                recorded_result("set_times_at", Some(now), error)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next set_times_at event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub fn next_create_directory_at(&mut self, dir: Option<u32>, path: &str) -> FsResult<()> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::CreateDirectoryAt {
+                dir: expected_dir,
+                path: expected_path,
+                error,
+            } => {
+                check_path(
+                    "create_directory_at",
+                    (expected_dir, &expected_path, &[]),
+                    (dir, path, &[]),
+                )?;
+                recorded_result("create_directory_at", Some(()), error)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next create_directory_at event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub fn next_link_at(
+        &mut self,
+        dir: Option<u32>,
+        path_flags: filesystem::types::PathFlags,
+        old_path: &str,
+        new_dir: Option<u32>,
+        new_path: &str,
+    ) -> FsResult<()> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::LinkAt {
+                dir: expected_dir,
+                path_flags: expected_flags,
+                old_path: expected_old_path,
+                new_dir: expected_new_dir,
+                new_path: expected_new_path,
+                error,
+            } => {
+                check_path(
+                    "link_at",
+                    (expected_dir, &expected_old_path, &expected_flags),
+                    (dir, old_path, &path_flag_names(path_flags)),
+                )?;
+                check_path(
+                    "link_at",
+                    (expected_new_dir, &expected_new_path, &[]),
+                    (new_dir, new_path, &[]),
+                )?;
+                recorded_result("link_at", Some(()), error)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next link_at event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub fn next_rename_at(
+        &mut self,
+        dir: Option<u32>,
+        old_path: &str,
+        new_dir: Option<u32>,
+        new_path: &str,
+    ) -> FsResult<()> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::RenameAt {
+                dir: expected_dir,
+                old_path: expected_old_path,
+                new_dir: expected_new_dir,
+                new_path: expected_new_path,
+                error,
+            } => {
+                check_path(
+                    "rename_at",
+                    (expected_dir, &expected_old_path, &[]),
+                    (dir, old_path, &[]),
+                )?;
+                check_path(
+                    "rename_at",
+                    (expected_new_dir, &expected_new_path, &[]),
+                    (new_dir, new_path, &[]),
+                )?;
+                recorded_result("rename_at", Some(()), error)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next rename_at event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub fn next_symlink_at(
+        &mut self,
+        dir: Option<u32>,
+        old_path: &str,
+        new_path: &str,
+    ) -> FsResult<()> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::SymlinkAt {
+                dir: expected_dir,
+                old_path: expected_old_path,
+                new_path: expected_new_path,
+                error,
+            } => {
+                check_path(
+                    "symlink_at",
+                    (expected_dir, &expected_new_path, &[]),
+                    (dir, new_path, &[]),
+                )?;
+                check_args("symlink_at target", expected_old_path.as_str(), old_path)?;
+                recorded_result("symlink_at", Some(()), error)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next symlink_at event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub fn next_unlink_file_at(&mut self, dir: Option<u32>, path: &str) -> FsResult<()> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::UnlinkFileAt {
+                dir: expected_dir,
+                path: expected_path,
+                error,
+            } => {
+                check_path(
+                    "unlink_file_at",
+                    (expected_dir, &expected_path, &[]),
+                    (dir, path, &[]),
+                )?;
+                recorded_result("unlink_file_at", Some(()), error)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next unlink_file_at event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    pub fn next_remove_directory_at(&mut self, dir: Option<u32>, path: &str) -> FsResult<()> {
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::RemoveDirectoryAt {
+                dir: expected_dir,
+                path: expected_path,
+                error,
+            } => {
+                check_path(
+                    "remove_directory_at",
+                    (expected_dir, &expected_path, &[]),
+                    (dir, path, &[]),
+                )?;
+                recorded_result("remove_directory_at", Some(()), error)
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected next remove_directory_at event, got {:?}",
+                other
+            ))),
+        }
+    }
+
+    /// Returns the recorded error if opening a stream on a file failed; a
+    /// successful open is matched by the `stream_create` that follows
+    pub fn next_file_stream(
+        &mut self,
+        descriptor: Option<u32>,
+        origin: StreamOrigin,
+    ) -> FsResult<()> {
+        if !matches!(
+            self.peek_event().map_err(FsError::trap)?,
+            Some(TraceEvent::FileStreamError { .. })
+        ) {
+            return Ok(());
+        }
+        match self.next_event().map_err(FsError::trap)? {
+            TraceEvent::FileStreamError {
+                descriptor: expected_descriptor,
+                origin: expected_origin,
+                error,
+            } if expected_origin == origin => {
+                check_descriptor("file stream", expected_descriptor, descriptor)?;
+                Err(replay_error_code(&error))
+            }
+            other => Err(FsError::trap(anyhow!(
+                "expected file_stream_error event for {:?}, got {:?}",
+                origin,
+                other
+            ))),
+        }
+    }
+
+    pub fn expect_stream_create(&mut self, stream: u32, origin: StreamOrigin) -> Result<()> {
+        if !self.io_events {
+            return Ok(());
+        }
+        match self.next_event()? {
+            TraceEvent::StreamCreate {
+                stream: expected_stream,
+                origin: expected_origin,
+            } if expected_stream == stream && expected_origin == origin => {
+                if origin == StreamOrigin::Output {
+                    self.request_body_streams.insert(stream);
+                }
+                Ok(())
+            }
+            other => Err(anyhow!(
+                "expected stream_create event for {:?} stream {}, got {:?}",
+                origin,
+                stream,
+                other
+            )),
+        }
+    }
+
+    pub fn expect_stream_drop(&mut self, stream: u32) -> Result<()> {
+        if !self.io_events {
+            return Ok(());
+        }
+        match self.next_event()? {
+            TraceEvent::StreamDrop {
+                stream: expected_stream,
+            } if expected_stream == stream => Ok(()),
+            other => Err(anyhow!(
+                "expected stream_drop event for stream {}, got {:?}",
+                stream,
+                other
+            )),
+        }
+    }
+
+    /// Returns the recorded outcome of the next input-stream read, or `None` for
+    /// traces that only carry a payload-less `read` marker.
+    pub fn next_stream_read(&mut self, stream: u32, len: u64) -> StreamResult<Option<Vec<u8>>> {
+        match self.next_event() {
+            Ok(TraceEvent::StreamRead {
+                stream: expected_stream,
+                bytes,
+                eof,
+                error,
+            }) => {
+                if stream != expected_stream {
+                    return Err(StreamError::trap(&format!(
+                        "input stream mismatch: expected read from stream {}, got stream {}",
+                        expected_stream, stream
+                    )));
+                }
+                if let Some(message) = error {
+                    return Err(StreamError::LastOperationFailed(anyhow!(message)));
+                }
+                if eof {
+                    return Err(StreamError::Closed);
+                }
+                if bytes.len() as u64 > len {
+                    return Err(StreamError::trap(&format!(
+                        "stream read length mismatch: requested at most {}, recorded {}",
+                        len,
+                        bytes.len()
+                    )));
+                }
+                Ok(Some(bytes))
+            }
+            Ok(TraceEvent::Read) => Ok(None),
+            Ok(other) => Err(StreamError::trap(&format!(
+                "expected next stream_read event, got {:?}",
+                other
+            ))),
+            Err(err) => Err(StreamError::trap(&err.to_string())),
+        }
+    }
+
+    /// Returns the recorded outcome of the next skip, or `None` for traces that
+    /// predate stream capture.
+    pub fn next_stream_skip(&mut self, stream: u32, len: u64) -> StreamResult<Option<u64>> {
+        if !self.io_events {
+            return Ok(None);
+        }
+        match self.next_event() {
+            Ok(TraceEvent::StreamSkip {
+                stream: expected_stream,
+                len: expected_len,
+                skipped,
+                eof,
+                error,
+            }) => {
+                if stream != expected_stream {
+                    return Err(StreamError::trap(&format!(
+                        "input stream mismatch: expected skip on stream {}, got stream {}",
+                        expected_stream, stream
+                    )));
+                }
+                if len != expected_len {
+                    return Err(StreamError::trap(&format!(
+                        "stream skip length mismatch: expected {}, got {}",
+                        expected_len, len
+                    )));
+                }
+                if let Some(message) = error {
+                    return Err(StreamError::LastOperationFailed(anyhow!(message)));
+                }
+                if eof {
+                    return Err(StreamError::Closed);
+                }
+                Ok(Some(skipped))
+            }
+            Ok(other) => Err(StreamError::trap(&format!(
+                "expected next stream_skip event, got {:?}",
+                other
+            ))),
+            Err(err) => Err(StreamError::trap(&err.to_string())),
+        }
+    }
+
+    /// Checks a guest write against the next recorded write and returns the
+    /// recorded outcome.
+    pub fn next_stream_write(&mut self, stream: u32, bytes: &[u8]) -> StreamResult<()> {
+        if !self.io_events {
+            return Ok(());
+        }
+        match self.next_event() {
+            Ok(TraceEvent::StreamWrite {
+                stream: expected_stream,
+                bytes: expected,
+                closed,
+                error,
+            }) => {
+                if stream != expected_stream {
+                    return Err(StreamError::trap(&format!(
+                        "output stream mismatch: expected write to stream {}, got stream {}",
+                        expected_stream, stream
+                    )));
+                }
+                let offset = self.output_offsets.entry(stream).or_default();
+                let mismatch = first_mismatch(&expected, bytes)
+                    .filter(|_| !self.request_body_streams.contains(&stream));
+                if let Some(index) = mismatch {
+                    return Err(StreamError::trap(&format!(
+                        "output mismatch on stream {} at byte offset {}: expected {}, got {}",
+                        stream,
+                        *offset + index as u64,
+                        describe_byte(expected.get(index)),
+                        describe_byte(bytes.get(index))
+                    )));
+                }
+                *offset += bytes.len() as u64;
+                if let Some(message) = error {
+                    return Err(StreamError::LastOperationFailed(anyhow!(message)));
+                }
+                if closed {
+                    return Err(StreamError::Closed);
+                }
+                Ok(())
+            }
+            Ok(other) => Err(StreamError::trap(&format!(
+                "expected next stream_write event, got {:?}",
+                other
+            ))),
+            Err(err) => Err(StreamError::trap(&err.to_string())),
+        }
+    }
+
+    /// Returns the recorded ready list of the next `poll` call, or `None` for
+    /// traces that predate poll capture.
+    pub fn next_poll(&mut self, pollables: u32) -> Result<Option<Vec<u32>>> {
+        if !self.io_events {
+            return Ok(None);
+        }
+        match self.next_event()? {
+            TraceEvent::Poll {
+                pollables: expected,
+                ready,
+            } => {
+                if pollables != expected {
+                    return Err(anyhow!(
+                        "poll length mismatch: expected {} pollables, got {}",
+                        expected,
+                        pollables
+                    ));
+                }
+                if let Some(index) = ready.iter().find(|&&index| index >= pollables) {
+                    return Err(anyhow!(
+                        "recorded poll result {} is out of range for {} pollables",
+                        index,
+                        pollables
+                    ));
+                }
+                Ok(Some(ready))
+            }
+            other => Err(anyhow!("expected next poll event, got {:?}", other)),
+        }
+    }
+
+    pub fn next_pollable_ready(&mut self) -> Result<Option<bool>> {
+        if !self.io_events {
+            return Ok(None);
+        }
+        match self.next_event()? {
+            TraceEvent::PollableReady { ready } => Ok(Some(ready)),
+            other => Err(anyhow!(
+                "expected next pollable_ready event, got {:?}",
+                other
+            )),
+        }
+    }
+
+    /// Whether host readiness must be bypassed because the trace dictates it
+    pub fn replays_readiness(&self) -> bool {
+        self.io_events
+    }
+
+    /// The next request the guest sends, with its id
+    fn next_http_request(&mut self) -> Result<(u32, RecordedHttpRequest)> {
+        match self.next_event()? {
+            TraceEvent::HttpRequest {
+                request,
+                method,
+                url,
+                headers,
+                redacted_headers,
+                config,
+            } => Ok((
+                request,
+                RecordedHttpRequest {
+                    method,
+                    url,
+                    headers,
+                    redacted_headers,
+                    config,
+                },
+            )),
+            other => Err(anyhow!("expected next http_request event, got {:?}", other)),
+        }
+    }
+
+    /// What came of request `request`: a response, or the error that kept one
+    /// from arriving
+    ///
+    /// The outcome is written once the guest is done with the response, so it
+    /// is looked for ahead of the events in between. `None` means the
+    /// recording ended before the request completed.
+    fn take_http_outcome(
+        &mut self,
+        request: u32,
+    ) -> Result<Option<std::result::Result<RecordedHttpResponse, HttpErrorCode>>> {
+        let outcome = self.take_ahead(|event| {
+            matches!(
+                event,
+                TraceEvent::HttpResponse { request: id, .. }
+                | TraceEvent::HttpError { request: id, .. } if *id == request
+            )
+        })?;
+        match outcome {
+            Some(TraceEvent::HttpResponse {
+                status,
+                headers,
+                between_bytes_timeout_nanoseconds,
+                frames,
+                body,
+                body_error,
+                ..
+            }) => Ok(Some(Ok(RecordedHttpResponse {
+                status,
+                headers,
+                between_bytes_timeout_nanoseconds,
+                frames,
+                body,
+                body_error,
+            }))),
+            Some(TraceEvent::HttpError { error, .. }) => Ok(Some(Err(error))),
+            Some(other) => Err(anyhow!(
+                "expected http_response or http_error event, got {:?}",
+                other
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// The body the guest sent with request `request`, looked for ahead like
+    /// its outcome; `None` if the recording ended before the guest finished it
+    fn take_http_request_body(&mut self, request: u32) -> Result<Option<Vec<u8>>> {
+        let body = self.take_ahead(|event| {
+            matches!(event, TraceEvent::HttpRequestBody { request: id, .. } if *id == request)
+        })?;
+        match body {
+            Some(TraceEvent::HttpRequestBody { body, .. }) => Ok(Some(body)),
+            Some(other) => Err(anyhow!("expected http_request_body event, got {:?}", other)),
+            None => Ok(None),
         }
     }
 
-    pub fn next_insecure_seed(&mut self) -> Result<(u64, u64)> {
-        match self.next_event()? {
-            TraceEvent::InsecureSeed { seed } => Ok(seed),
-            other => Err(anyhow!(
-                "expected next insecure_seed event, got {:?}",
-                other
-            )),
+    /// Take the first event `wanted` picks out, reading as far ahead as it
+    /// takes; `None` if the recording ended first
+    fn take_ahead(&mut self, wanted: impl Fn(&TraceEvent) -> bool) -> Result<Option<TraceEvent>> {
+        let mut searched = 0;
+        loop {
+            if searched == self.ahead.len() {
+                match self.read_source_event()? {
+                    Some(event) => self.ahead.push_back(event),
+                    None => return Ok(None),
+                }
+            }
+            match self.ahead.get(searched) {
+                Some(TraceEvent::Incomplete) | None => return Ok(None),
+                Some(event) if wanted(event) => return Ok(self.ahead.remove(searched)),
+                Some(_) => searched += 1,
+            }
         }
     }
 
-    pub fn expect_read_event(&mut self) -> Result<()> {
-        match self.next_event() {
-            Ok(TraceEvent::Read) => Ok(()),
-            Ok(other) => Err(anyhow!(
-                "expected next filesystem event to be 'read', got {:?}",
+    /// Check the guest finished with the recorded exit code; traces from
+    /// before exits were recorded end without one
+    pub fn expect_exit(&mut self, code: i32) -> Result<()> {
+        if self.peek_event()?.is_none() {
+            return Ok(());
+        }
+        match self.next_event()? {
+            TraceEvent::Exit {
+                code: expected_code,
+            } if expected_code == code => Ok(()),
+            TraceEvent::Exit {
+                code: expected_code,
+            } => Err(anyhow!(
+                "exit code mismatch: expected {}, got {}",
+                expected_code,
+                code
+            )),
+            other => Err(anyhow!(
+                "expected exit event with code {}, got {:?}",
+                code,
                 other
             )),
-            Err(err) => Err(err),
         }
     }
 
-    fn next_http_response(&mut self) -> Result<(RecordedHttpRequest, RecordedHttpResponse)> {
+    /// Check the guest trapped where and how the recorded run did
+    pub fn expect_trap(&mut self, message: &str) -> Result<()> {
         match self.next_event()? {
-            TraceEvent::HttpResponse {
-                request_method,
-                request_url,
-                request_headers,
-                status,
-                headers,
-                body,
-            } => Ok((
-                RecordedHttpRequest {
-                    method: request_method,
-                    url: request_url,
-                    headers: request_headers,
-                },
-                RecordedHttpResponse {
-                    status,
-                    headers,
-                    body,
-                },
-            )),
+            TraceEvent::Trap {
+                message: expected_message,
+                ..
+            } if expected_message == message => Ok(()),
             other => Err(anyhow!(
-                "expected next http_response event, got {:?}",
+                "expected trap event with message {:?}, got {:?}",
+                message,
                 other
             )),
         }
     }
 
     pub fn finish(mut self) -> Result<()> {
+        if let Some(event) = self
+            .ahead
+            .iter()
+            .find(|event| !matches!(event, TraceEvent::Read))
+        {
+            return Err(anyhow!(
+                "trace contains unused events, starting with: {:?}",
+                event
+            ));
+        }
         match &mut self.source {
             PlaybackSource::Memory(events) => {
                 if events.iter().all(|event| matches!(event, TraceEvent::Read)) {
@@ -259,37 +1447,344 @@ impl Playback {
                 }
             }
             PlaybackSource::Stream(reader) => loop {
-                match ciborium::from_reader::<TraceEvent, _>(&mut *reader) {
-                    Ok(TraceEvent::Read) => continue,
-                    Ok(event) => {
+                match reader
+                    .next_item()
+                    .context("error while checking for remaining events in CBOR trace")?
+                {
+                    CborItem::Event(event) if matches!(*event, TraceEvent::Read) => continue,
+                    CborItem::Event(event) => {
                         return Err(anyhow!(
                             "trace contains unused events, starting with: {:?}",
                             event
                         ))
                     }
-                    Err(e) => {
-                        if is_cbor_eof(&e) {
-                            return Ok(());
-                        }
-                        return Err(anyhow::Error::msg(format!("{}", e)))
-                            .context("error while checking for remaining events in CBOR trace");
-                    }
+                    CborItem::End | CborItem::Truncated => return Ok(()),
                 }
             },
         }
     }
 }
 
+/// Turn a recorded filesystem outcome back into the result the guest sees
+fn recorded_result<T>(call: &str, value: Option<T>, error: Option<String>) -> FsResult<T> {
+    match (value, error) {
+        (_, Some(code)) => Err(replay_error_code(&code)),
+        (Some(value), None) => Ok(value),
+        (None, None) => Err(FsError::trap(anyhow!(
+            "{call} event has neither a result nor an error"
+        ))),
+    }
+}
+
+fn check_descriptor(call: &str, expected: Option<u32>, actual: Option<u32>) -> FsResult<()> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(FsError::trap(anyhow!(
+            "{call} mismatch: expected descriptor {:?}, got {:?}",
+            expected,
+            actual
+        )))
+    }
+}
+
+fn check_args<T: std::fmt::Debug + PartialEq>(call: &str, expected: T, actual: T) -> FsResult<()> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(FsError::trap(anyhow!(
+            "{call} mismatch: expected {:?}, got {:?}",
+            expected,
+            actual
+        )))
+    }
+}
+
+/// Check a path-based call names the same path, relative to the same
+/// directory, with the same path flags as recorded
+fn check_path(
+    call: &str,
+    expected: (Option<u32>, &str, &[String]),
+    actual: (Option<u32>, &str, &[String]),
+) -> FsResult<()> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(FsError::trap(anyhow!(
+            "{call} mismatch: expected {:?} in directory {:?} with flags {:?}, got {:?} in directory {:?} with flags {:?}",
+            expected.1,
+            expected.0,
+            expected.2,
+            actual.1,
+            actual.0,
+            actual.2
+        )))
+    }
+}
+
+/// Check a filesystem call served from the snapshot against its traced
+/// outcome, when there is one. Returns the emulated value along with the
+/// recorded one, which carries the host's timestamps and hashes.
+fn check_snapshot<T: std::fmt::Debug, U: std::fmt::Debug>(
+    call: &str,
+    emulated: FsResult<T>,
+    recorded: Option<FsResult<U>>,
+    same: impl Fn(&T, &U) -> bool,
+) -> FsResult<(T, Option<U>)> {
+    let Some(recorded) = recorded else {
+        return emulated.map(|value| (value, None));
+    };
+    let recorded = guest_outcome(recorded)?;
+    let emulated = guest_outcome(emulated)?;
+    match (emulated, recorded) {
+        (Ok(emulated), Ok(recorded)) if same(&emulated, &recorded) => {
+            Ok((emulated, Some(recorded)))
+        }
+        (Err(emulated), Err(recorded)) if emulated == recorded => Err(emulated.into()),
+        (emulated, recorded) => Err(FsError::trap(anyhow!(
+            "snapshot diverged from the recording in {call}: recorded {:?}, replayed {:?}",
+            recorded,
+            emulated
+        ))),
+    }
+}
+
+/// Split the error codes the guest sees off from traps
+fn guest_outcome<T>(result: FsResult<T>) -> FsResult<Result<T, FsErrorCode>> {
+    match result {
+        Ok(value) => Ok(Ok(value)),
+        Err(err) => match err.downcast_ref() {
+            Some(code) => Ok(Err(*code)),
+            None => Err(err),
+        },
+    }
+}
+
+fn recorded_now(recorded: &Option<FsResult<Option<FileTimestamp>>>) -> Option<FileTimestamp> {
+    recorded
+        .as_ref()
+        .and_then(|result| result.as_ref().ok().copied().flatten())
+}
+
+/// Stats agree on everything the snapshot keeps; timestamps of entries
+/// created during the run are the host's
+fn same_stat(
+    emulated: &filesystem::types::DescriptorStat,
+    recorded: &filesystem::types::DescriptorStat,
+) -> bool {
+    (emulated.type_, emulated.link_count, emulated.size)
+        == (recorded.type_, recorded.link_count, recorded.size)
+}
+
+/// Compare request headers against the recorded ones, where the headers named
+/// in `redacted` may have any value
+fn headers_match(
+    expected: &[(String, String)],
+    redacted: &[String],
+    actual: &[(String, String)],
+) -> bool {
+    // Redaction changes values and with them the order within a header name,
+    // so only the order of names is relied on
+    let by_name = |headers: &[(String, String)]| {
+        let mut headers = headers.to_vec();
+        headers.sort_by(|a, b| a.0.cmp(&b.0));
+        headers
+    };
+    expected.len() == actual.len()
+        && by_name(expected).iter().zip(by_name(actual).iter()).all(
+            |((expected_name, expected_value), (actual_name, actual_value))| {
+                expected_name == actual_name
+                    && (expected_value == actual_value || redacted.contains(expected_name))
+            },
+        )
+}
+
+/// Describe each transport setting a replayed request changed, e.g.
+/// `connect timeout: expected 5s, got 2s`
+fn config_diff(expected: &HttpRequestConfig, actual: &HttpRequestConfig) -> String {
+    let timeouts = [
+        (
+            "connect timeout",
+            expected.connect_timeout_nanoseconds,
+            actual.connect_timeout_nanoseconds,
+        ),
+        (
+            "first byte timeout",
+            expected.first_byte_timeout_nanoseconds,
+            actual.first_byte_timeout_nanoseconds,
+        ),
+        (
+            "between bytes timeout",
+            expected.between_bytes_timeout_nanoseconds,
+            actual.between_bytes_timeout_nanoseconds,
+        ),
+    ];
+    let mut changes = Vec::new();
+    if expected.use_tls != actual.use_tls {
+        changes.push(format!(
+            "tls: expected {}, got {}",
+            expected.use_tls, actual.use_tls
+        ));
+    }
+    for (name, expected, actual) in timeouts {
+        if expected != actual {
+            changes.push(format!(
+                "{name}: expected {:?}, got {:?}",
+                Duration::from_nanos(expected),
+                Duration::from_nanos(actual)
+            ));
+        }
+    }
+    changes.join("; ")
+}
+
+fn is_stream_create(event: &TraceEvent) -> bool {
+    matches!(event, TraceEvent::StreamCreate { .. })
+}
+
+/// What a pass over a CBOR trace found out, without keeping its events
+struct CborScan {
+    events: usize,
+    /// Whether the trace captured stream and poll traffic
+    io_events: bool,
+    /// Whether the file stops partway through an event
+    truncated: bool,
+    /// Whether the last event is one every finished recording ends with
+    finished: bool,
+}
+
+fn scan_cbor(path: &Path) -> Result<CborScan> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open trace file at {}", path.display()))?;
+    let context = || format!("failed to scan CBOR trace at {}", path.display());
+    let (_, mut reader) = CborEvents::new(BufReader::new(file)).with_context(context)?;
+    let mut scan = CborScan {
+        events: 0,
+        io_events: false,
+        truncated: false,
+        finished: false,
+    };
+    loop {
+        match reader.next_item().with_context(context)? {
+            CborItem::Event(event) => {
+                scan.events += 1;
+                scan.io_events |= is_stream_create(&event);
+                scan.finished = is_finished(std::slice::from_ref(&*event));
+            }
+            CborItem::End => return Ok(scan),
+            CborItem::Truncated => {
+                scan.truncated = true;
+                return Ok(scan);
+            }
+        }
+    }
+}
+
+fn warn_truncated(path: &Path, events: usize) {
+    warn(format!(
+        "{} is truncated; replaying the {events} events before the cut, \
+         run `wasm-rr repair` to fix the file",
+        path.display(),
+    ));
+}
+
+/// Index of the first byte where two writes differ, including a length difference
+fn first_mismatch(expected: &[u8], actual: &[u8]) -> Option<usize> {
+    expected
+        .iter()
+        .zip(actual)
+        .position(|(a, b)| a != b)
+        .or_else(|| (expected.len() != actual.len()).then(|| expected.len().min(actual.len())))
+}
+
+fn describe_byte(byte: Option<&u8>) -> String {
+    match byte {
+        Some(byte) => format!("{byte:#04x}"),
+        None => "end of write".to_string(),
+    }
+}
+
 struct RecordedHttpRequest {
     method: String,
     url: String,
     headers: Vec<(String, String)>,
+    redacted_headers: Vec<String>,
+    /// `None` for traces from before request settings were recorded
+    config: Option<HttpRequestConfig>,
 }
 
 struct RecordedHttpResponse {
     status: u16,
     headers: Vec<(String, String)>,
-    body: Vec<u8>,
+    between_bytes_timeout_nanoseconds: Option<u64>,
+    frames: Vec<HttpBodyFrame>,
+    /// Only in traces from before body stream reads were recorded
+    body: Option<Vec<u8>>,
+    body_error: Option<HttpErrorCode>,
+}
+
+/// A response body that yields the recorded frames in order, and ends with the
+/// error that cut it short if there was one
+///
+/// Every frame is ready as soon as it is asked for: when the guest saw data or
+/// trailers arrive is already in the trace's poll and `stream_read` events, so
+/// there is nothing to wait for. What the guest reads comes from those
+/// `stream_read` events too, so data frames are only filled in from the legacy
+/// whole body, if there is one.
+struct ReplayBody {
+    frames: VecDeque<Frame<Bytes>>,
+    error: Option<ErrorCode>,
+}
+
+impl ReplayBody {
+    fn new(
+        response_frames: Vec<HttpBodyFrame>,
+        body: Option<Vec<u8>>,
+        body_error: Option<HttpErrorCode>,
+    ) -> Result<Self> {
+        let mut body = Bytes::from(body.unwrap_or_default());
+        let mut frames = VecDeque::with_capacity(response_frames.len());
+        for frame in response_frames {
+            match frame {
+                HttpBodyFrame::Data { size, .. } => {
+                    let size = usize::try_from(size)?;
+                    let data = if body.is_empty() {
+                        Bytes::from(vec![0; size])
+                    } else {
+                        body.split_to(size.min(body.len()))
+                    };
+                    frames.push_back(Frame::data(data));
+                }
+                HttpBodyFrame::Trailers { trailers, .. } => {
+                    frames.push_back(Frame::trailers(header_map_from_pairs(&trailers)?));
+                }
+            }
+        }
+        Ok(Self {
+            frames,
+            error: body_error.map(http::replay_error_code),
+        })
+    }
+}
+
+impl Body for ReplayBody {
+    type Data = Bytes;
+    type Error = ErrorCode;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<std::result::Result<Frame<Bytes>, ErrorCode>>> {
+        let this = self.get_mut();
+        match this.frames.pop_front() {
+            Some(frame) => Poll::Ready(Some(Ok(frame))),
+            None => Poll::Ready(this.error.take().map(Err)),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.frames.is_empty() && self.error.is_none()
+    }
 }
 
 pub struct CtxPlayback {
@@ -297,20 +1792,159 @@ pub struct CtxPlayback {
     wasi: WasiCtx,
     http: WasiHttpCtx,
     playback: Playback,
+    streams: HandleRegistry,
+    descriptors: HandleRegistry,
+    directory_streams: HandleRegistry,
+    clock: VirtualClock,
+    snapshot: Option<Snapshot>,
+    /// Streams on snapshot files, which are served from memory instead of the trace
+    snapshot_streams: HashSet<u32>,
+    /// Pollables on streams, whose readiness and contents both come from the
+    /// trace
+    stream_pollables: HashSet<u32>,
+    /// Tasks comparing the bodies of requests sent with the recorded ones,
+    /// which finish once the guest is done writing them
+    request_bodies: Vec<AbortOnDropJoinHandle<Result<()>>>,
 }
 
 impl CtxPlayback {
-    pub fn new(wasi: WasiCtx, http: WasiHttpCtx, playback: Playback) -> Self {
+    pub fn new(
+        wasi: WasiCtx,
+        http: WasiHttpCtx,
+        playback: Playback,
+        snapshot: Option<Snapshot>,
+    ) -> Self {
         Self {
             table: ResourceTable::new(),
             wasi,
             http,
             playback,
+            streams: HandleRegistry::default(),
+            descriptors: HandleRegistry::default(),
+            directory_streams: HandleRegistry::default(),
+            clock: VirtualClock::default(),
+            snapshot,
+            snapshot_streams: HashSet::new(),
+            stream_pollables: HashSet::new(),
+            request_bodies: Vec::new(),
+        }
+    }
+
+    /// The playback state once the guest is done, after checking the bodies
+    /// of the requests it sent
+    pub fn into_playback(self) -> Result<Playback> {
+        let Self {
+            table,
+            playback,
+            request_bodies,
+            ..
+        } = self;
+        // Bodies the guest never finished end along with its resources
+        drop(table);
+        for check in request_bodies {
+            runtime::in_tokio(check)?;
+        }
+        Ok(playback)
+    }
+
+    /// Trace id of a stream, registering it on first sight, or `None` for
+    /// streams on snapshot files
+    fn stream_id(&mut self, rep: u32, origin: StreamOrigin) -> Result<Option<u32>> {
+        if self.snapshot_streams.contains(&rep) {
+            return Ok(None);
+        }
+        if let Some(id) = self.streams.get(rep) {
+            return Ok(Some(id));
+        }
+        let id = self.streams.register(rep);
+        self.playback.expect_stream_create(id, origin)?;
+        Ok(Some(id))
+    }
+
+    fn drop_stream(&mut self, rep: u32) -> Result<()> {
+        self.snapshot_streams.remove(&rep);
+        match self.streams.remove(rep) {
+            Some(id) => self.playback.expect_stream_drop(id),
+            None => Ok(()),
+        }
+    }
+
+    /// Bring a pollable the trace reports ready to the state the guest saw
+    ///
+    /// Stream traffic comes from the trace, so there is nothing to wait for
+    /// there. Timers jump the virtual clock to their deadline. Anything else
+    /// is an HTTP future over a replayed response, such as trailers, whose
+    /// frames are all in memory; blocking on it only lets the host catch up
+    /// with the trace, so the guest can read it straight from the host.
+    fn settle(&mut self, pollable: &Resource<poll::Pollable>) -> Result<()> {
+        self.clock.fire(pollable.rep());
+        if self.stream_pollables.contains(&pollable.rep()) {
+            return Ok(());
         }
+        let view = WasiView::ctx(self);
+        <ResourceTable as poll::HostPollable>::block(
+            view.table,
+            Resource::new_borrow(pollable.rep()),
+        )
     }
 
-    pub fn into_playback(self) -> Playback {
+    /// The traced outcome of a call served from the snapshot, for traces that
+    /// have one
+    fn recorded<T>(
+        &mut self,
+        next: impl FnOnce(&mut Playback) -> FsResult<T>,
+    ) -> Option<FsResult<T>> {
         self.playback
+            .checks_snapshot()
+            .then(|| next(&mut self.playback))
+    }
+
+    /// The in-memory filesystem, when the recording snapshotted its preopens
+    fn snapshot(&mut self) -> Option<SnapshotView<'_>> {
+        self.snapshot
+            .as_ref()
+            .map(|snapshot| snapshot.view(&mut self.table))
+    }
+
+    /// Hand the guest a descriptor that only exists in the trace
+    fn push_descriptor(&mut self, id: u32) -> FsResult<Resource<filesystem::types::Descriptor>> {
+        let rep = self
+            .table
+            .push(ReplayDescriptor)
+            .map_err(FsError::trap)?
+            .rep();
+        register_recorded(&mut self.descriptors, rep, id)?;
+        Ok(Resource::new_own(rep))
+    }
+
+    /// Hand the guest an output stream on a file; writes are checked against
+    /// the trace and then discarded, so replay never changes the host
+    fn push_file_output_stream(
+        &mut self,
+        fd: Resource<filesystem::types::Descriptor>,
+        origin: StreamOrigin,
+    ) -> FsResult<Resource<streams::OutputStream>> {
+        let descriptor = self.descriptors.get(fd.rep());
+        self.playback.next_file_stream(descriptor, origin)?;
+        let stream: streams::OutputStream = Box::new(SinkOutputStream);
+        let stream = self.table.push(stream).map_err(FsError::trap)?;
+        self.stream_id(stream.rep(), origin)
+            .map_err(FsError::trap)?;
+        Ok(stream)
+    }
+}
+
+/// Register a resource created on replay, checking it gets the recorded id
+fn register_recorded(registry: &mut HandleRegistry, rep: u32, recorded: u32) -> FsResult<()> {
+    let id = registry.register(rep);
+    if id == recorded {
+        Ok(())
+    } else {
+        Err(FsError::trap(anyhow!(
+            "trace assigned id {} but replay reached id {}",
+            recorded,
+            id
+        )))
     }
 }
 
@@ -335,16 +1969,13 @@ impl WasiHttpView for CtxPlayback {
     fn send_request(
         &mut self,
         request: hyper::Request<wasmtime_wasi_http::body::HyperOutgoingBody>,
-        _config: OutgoingRequestConfig,
+        config: OutgoingRequestConfig,
     ) -> wasmtime_wasi_http::HttpResult<HostFutureIncomingResponse> {
         let method = request.method().to_string();
         let url = request.uri().to_string();
         let actual_headers = sorted_headers(request.headers())?;
 
-        let (expected_request, recorded_response) = self
-            .playback
-            .next_http_response()
-            .map_err(HttpError::trap)?;
+        let (id, expected_request) = self.playback.next_http_request().map_err(HttpError::trap)?;
 
         if method != expected_request.method || url != expected_request.url {
             return Err(HttpError::trap(anyhow!(
@@ -354,40 +1985,102 @@ impl WasiHttpView for CtxPlayback {
             )));
         }
 
-        if actual_headers != expected_request.headers {
+        if !headers_match(
+            &expected_request.headers,
+            &expected_request.redacted_headers,
+            &actual_headers,
+        ) {
             return Err(HttpError::trap(anyhow!(
                 "http request headers mismatch for {method} {url}"
             )));
         }
 
-        let RecordedHttpResponse {
-            status,
-            headers,
-            body,
-        } = recorded_response;
+        if let Some(expected) = &expected_request.config {
+            let actual = http::recorded_request_config(&config);
+            if *expected != actual {
+                return Err(HttpError::trap(anyhow!(
+                    "http request config mismatch for {method} {url}: {}",
+                    config_diff(expected, &actual)
+                )));
+            }
+        }
 
-        let mut builder = hyper::Response::builder().status(status);
-        *builder
-            .headers_mut()
-            .ok_or_else(|| HttpError::trap(anyhow!("failed to access response headers")))? =
-            header_map_from_pairs(&headers)?;
+        // The guest may still be writing the body, so it is compared with the
+        // recorded one in the background and reported when replay ends
+        let expected_body = self
+            .playback
+            .take_http_request_body(id)
+            .map_err(HttpError::trap)?;
+        let mut body = request.into_body();
+        let exchange = format!("{method} {url}");
+        self.request_bodies.push(runtime::spawn(async move {
+            let mut sent = Vec::new();
+            while let Some(frame) = body.frame().await {
+                match frame {
+                    Ok(frame) => {
+                        if let Some(data) = frame.data_ref() {
+                            sent.extend_from_slice(data);
+                        }
+                    }
+                    // An aborted body never reached the server either
+                    Err(_) => return Ok(()),
+                }
+            }
+            match expected_body.and_then(|expected| first_mismatch(&expected, &sent)) {
+                Some(index) => Err(anyhow!(
+                    "request body for {exchange} first differs at byte {index}"
+                )),
+                None => Ok(()),
+            }
+        }));
 
-        // Full<Bytes> is infallible, but we need to convert the error type to match the expected signature.
-        // We use an explicit match on Infallible rather than Into::into because ErrorCode doesn't
-        // implement From<Infallible>.
-        let boxed_body = Full::new(Bytes::from(body))
-            .map_err(|e: std::convert::Infallible| match e {})
-            .boxed_unsync();
+        let Some(recorded_response) = self
+            .playback
+            .take_http_outcome(id)
+            .map_err(HttpError::trap)?
+        else {
+            return Ok(HostFutureIncomingResponse::ready(Ok(Err(
+                ErrorCode::InternalError(Some(
+                    "the recording ended before this request completed".to_string(),
+                )),
+            ))));
+        };
 
-        let response = builder.body(boxed_body).map_err(HttpError::trap)?;
+        // The guest's own setting is what the host would have applied, so it
+        // stands in for traces that did not record one
+        let between_bytes_timeout = recorded_response
+            .as_ref()
+            .ok()
+            .and_then(|response| response.between_bytes_timeout_nanoseconds)
+            .map_or(config.between_bytes_timeout, Duration::from_nanos);
 
-        let incoming = IncomingResponse {
-            resp: response,
-            worker: None,
-            between_bytes_timeout: Duration::from_secs(600),
+        let response = match recorded_response {
+            Ok(RecordedHttpResponse {
+                status,
+                headers,
+                frames,
+                body,
+                body_error,
+                ..
+            }) => {
+                let mut builder = hyper::Response::builder().status(status);
+                *builder.headers_mut().ok_or_else(|| {
+                    HttpError::trap(anyhow!("failed to access response headers"))
+                })? = header_map_from_pairs(&headers)?;
+
+                let body = ReplayBody::new(frames, body, body_error).map_err(HttpError::trap)?;
+                Ok(builder.body(body.boxed_unsync()).map_err(HttpError::trap)?)
+            }
+            Err(code) => Err(http::replay_error_code(code)),
         };
 
-        Ok(HostFutureIncomingResponse::ready(Ok(Ok(incoming))))
+        Ok(HostFutureIncomingResponse::ready(Ok(response.map(
+            |resp| IncomingResponse {
+                resp,
+                worker: None,
+                between_bytes_timeout,
+            },
+        ))))
     }
 }
 
@@ -403,7 +2096,9 @@ impl clocks::wall_clock::Host for CtxPlayback {
 
 impl clocks::monotonic_clock::Host for CtxPlayback {
     fn now(&mut self) -> anyhow::Result<u64> {
-        self.playback.next_monotonic_now()
+        let now = self.playback.next_monotonic_now()?;
+        self.clock.observe(now)?;
+        Ok(now)
     }
 
     fn resolution(&mut self) -> anyhow::Result<u64> {
@@ -418,9 +2113,7 @@ impl clocks::monotonic_clock::Host for CtxPlayback {
             wasmtime_wasi::p2::bindings::clocks::monotonic_clock::Pollable,
         >,
     > {
-        // Delegate to underlying WasiClocks implementation
-        use wasmtime_wasi::clocks::WasiClocksView;
-        self.clocks().subscribe_instant(when)
+        self.clock.subscribe_instant(&mut self.table, when)
     }
 
     fn subscribe_duration(
@@ -431,9 +2124,7 @@ impl clocks::monotonic_clock::Host for CtxPlayback {
             wasmtime_wasi::p2::bindings::clocks::monotonic_clock::Pollable,
         >,
     > {
-        // Delegate to underlying WasiClocks implementation
-        use wasmtime_wasi::clocks::WasiClocksView;
-        self.clocks().subscribe_duration(duration)
+        self.clock.subscribe_duration(&mut self.table, duration)
     }
 }
 
@@ -451,6 +2142,30 @@ impl cli::environment::Host for CtxPlayback {
     }
 }
 
+impl cli::stdin::Host for CtxPlayback {
+    fn get_stdin(&mut self) -> anyhow::Result<Resource<streams::InputStream>> {
+        let stream = self.cli().get_stdin()?;
+        self.stream_id(stream.rep(), StreamOrigin::Stdin)?;
+        Ok(stream)
+    }
+}
+
+impl cli::stdout::Host for CtxPlayback {
+    fn get_stdout(&mut self) -> anyhow::Result<Resource<streams::OutputStream>> {
+        let stream = self.cli().get_stdout()?;
+        self.stream_id(stream.rep(), StreamOrigin::Stdout)?;
+        Ok(stream)
+    }
+}
+
+impl cli::stderr::Host for CtxPlayback {
+    fn get_stderr(&mut self) -> anyhow::Result<Resource<streams::OutputStream>> {
+        let stream = self.cli().get_stderr()?;
+        self.stream_id(stream.rep(), StreamOrigin::Stderr)?;
+        Ok(stream)
+    }
+}
+
 impl random::random::Host for CtxPlayback {
     fn get_random_bytes(&mut self, len: u64) -> anyhow::Result<Vec<u8>> {
         self.playback.next_random_bytes(len)
@@ -477,6 +2192,51 @@ impl random::insecure_seed::Host for CtxPlayback {
     }
 }
 
+impl poll::Host for CtxPlayback {
+    fn poll(&mut self, pollables: Vec<Resource<poll::Pollable>>) -> anyhow::Result<Vec<u32>> {
+        if let Some(ready) = self.playback.next_poll(u32::try_from(pollables.len())?)? {
+            for pollable in ready
+                .iter()
+                .filter_map(|&index| pollables.get(index as usize))
+            {
+                self.settle(pollable)?;
+            }
+            return Ok(ready);
+        }
+        let view = WasiView::ctx(self);
+        <ResourceTable as poll::Host>::poll(view.table, pollables)
+    }
+}
+
+impl poll::HostPollable for CtxPlayback {
+    fn ready(&mut self, pollable: Resource<poll::Pollable>) -> anyhow::Result<bool> {
+        if let Some(ready) = self.playback.next_pollable_ready()? {
+            if ready {
+                self.settle(&pollable)?;
+            }
+            return Ok(ready);
+        }
+        let view = WasiView::ctx(self);
+        <ResourceTable as poll::HostPollable>::ready(view.table, pollable)
+    }
+
+    fn block(&mut self, pollable: Resource<poll::Pollable>) -> anyhow::Result<()> {
+        if self.playback.replays_readiness() {
+            return self.settle(&pollable);
+        }
+        self.clock.fire(pollable.rep());
+        let view = WasiView::ctx(self);
+        <ResourceTable as poll::HostPollable>::block(view.table, pollable)
+    }
+
+    fn drop(&mut self, pollable: Resource<poll::Pollable>) -> anyhow::Result<()> {
+        self.clock.forget(pollable.rep());
+        self.stream_pollables.remove(&pollable.rep());
+        let view = WasiView::ctx(self);
+        <ResourceTable as poll::HostPollable>::drop(view.table, pollable)
+    }
+}
+
 impl streams::Host for CtxPlayback {
     fn convert_stream_error(&mut self, err: StreamError) -> anyhow::Result<streams::StreamError> {
         let view = WasiView::ctx(self);
@@ -486,14 +2246,20 @@ impl streams::Host for CtxPlayback {
 
 impl streams::HostInputStream for CtxPlayback {
     fn drop(&mut self, stream: Resource<streams::InputStream>) -> anyhow::Result<()> {
+        self.drop_stream(stream.rep())?;
         let view = WasiView::ctx(self);
         <ResourceTable as streams::HostInputStream>::drop(view.table, stream)
     }
 
     fn read(&mut self, stream: Resource<streams::InputStream>, len: u64) -> StreamResult<Vec<u8>> {
-        self.playback
-            .expect_read_event()
+        let id = self
+            .stream_id(stream.rep(), StreamOrigin::Input)
             .map_err(|err| StreamError::trap(&err.to_string()))?;
+        if let Some(id) = id {
+            if let Some(bytes) = self.playback.next_stream_read(id, len)? {
+                return Ok(bytes);
+            }
+        }
         let view = WasiView::ctx(self);
         <ResourceTable as streams::HostInputStream>::read(view.table, stream, len)
     }
@@ -503,14 +2269,27 @@ impl streams::HostInputStream for CtxPlayback {
         stream: Resource<streams::InputStream>,
         len: u64,
     ) -> StreamResult<Vec<u8>> {
-        self.playback
-            .expect_read_event()
+        let id = self
+            .stream_id(stream.rep(), StreamOrigin::Input)
             .map_err(|err| StreamError::trap(&err.to_string()))?;
+        if let Some(id) = id {
+            if let Some(bytes) = self.playback.next_stream_read(id, len)? {
+                return Ok(bytes);
+            }
+        }
         let view = WasiView::ctx(self);
         <ResourceTable as streams::HostInputStream>::blocking_read(view.table, stream, len)
     }
 
     fn skip(&mut self, stream: Resource<streams::InputStream>, len: u64) -> StreamResult<u64> {
+        let id = self
+            .stream_id(stream.rep(), StreamOrigin::Input)
+            .map_err(|err| StreamError::trap(&err.to_string()))?;
+        if let Some(id) = id {
+            if let Some(skipped) = self.playback.next_stream_skip(id, len)? {
+                return Ok(skipped);
+            }
+        }
         let view = WasiView::ctx(self);
         <ResourceTable as streams::HostInputStream>::skip(view.table, stream, len)
     }
@@ -520,6 +2299,14 @@ impl streams::HostInputStream for CtxPlayback {
         stream: Resource<streams::InputStream>,
         len: u64,
     ) -> StreamResult<u64> {
+        let id = self
+            .stream_id(stream.rep(), StreamOrigin::Input)
+            .map_err(|err| StreamError::trap(&err.to_string()))?;
+        if let Some(id) = id {
+            if let Some(skipped) = self.playback.next_stream_skip(id, len)? {
+                return Ok(skipped);
+            }
+        }
         let view = WasiView::ctx(self);
         <ResourceTable as streams::HostInputStream>::blocking_skip(view.table, stream, len)
     }
@@ -529,12 +2316,15 @@ impl streams::HostInputStream for CtxPlayback {
         stream: Resource<streams::InputStream>,
     ) -> anyhow::Result<Resource<poll::Pollable>> {
         let view = WasiView::ctx(self);
-        <ResourceTable as streams::HostInputStream>::subscribe(view.table, stream)
+        let pollable = <ResourceTable as streams::HostInputStream>::subscribe(view.table, stream)?;
+        self.stream_pollables.insert(pollable.rep());
+        Ok(pollable)
     }
 }
 
 impl streams::HostOutputStream for CtxPlayback {
     fn drop(&mut self, stream: Resource<streams::OutputStream>) -> anyhow::Result<()> {
+        self.drop_stream(stream.rep())?;
         let view = WasiView::ctx(self);
         <ResourceTable as streams::HostOutputStream>::drop(view.table, stream)
     }
@@ -549,6 +2339,12 @@ impl streams::HostOutputStream for CtxPlayback {
         stream: Resource<streams::OutputStream>,
         bytes: Vec<u8>,
     ) -> StreamResult<()> {
+        let id = self
+            .stream_id(stream.rep(), StreamOrigin::Output)
+            .map_err(|err| StreamError::trap(&err.to_string()))?;
+        if let Some(id) = id {
+            self.playback.next_stream_write(id, &bytes)?;
+        }
         let view = WasiView::ctx(self);
         <ResourceTable as streams::HostOutputStream>::write(view.table, stream, bytes)
     }
@@ -558,6 +2354,12 @@ impl streams::HostOutputStream for CtxPlayback {
         stream: Resource<streams::OutputStream>,
         bytes: Vec<u8>,
     ) -> StreamResult<()> {
+        let id = self
+            .stream_id(stream.rep(), StreamOrigin::Output)
+            .map_err(|err| StreamError::trap(&err.to_string()))?;
+        if let Some(id) = id {
+            self.playback.next_stream_write(id, &bytes)?;
+        }
         let view = WasiView::ctx(self);
         <ResourceTable as streams::HostOutputStream>::blocking_write_and_flush(
             view.table, stream, bytes,
@@ -569,10 +2371,16 @@ impl streams::HostOutputStream for CtxPlayback {
         stream: Resource<streams::OutputStream>,
         len: u64,
     ) -> StreamResult<()> {
-        let view = WasiView::ctx(self);
-        <ResourceTable as streams::HostOutputStream>::blocking_write_zeroes_and_flush(
-            view.table, stream, len,
-        )
+        // Zeroes are written as plain bytes so the trace carries them like any
+        // other write
+        if len > MAX_BLOCKING_WRITE {
+            return Err(StreamError::trap(&format!(
+                "Buffer too large for blocking-write-zeroes-and-flush (expected at most {MAX_BLOCKING_WRITE})"
+            )));
+        }
+        let zeroes =
+            vec![0; usize::try_from(len).map_err(|err| StreamError::trap(&err.to_string()))?];
+        self.blocking_write_and_flush(stream, zeroes)
     }
 
     fn subscribe(
@@ -580,7 +2388,9 @@ impl streams::HostOutputStream for CtxPlayback {
         stream: Resource<streams::OutputStream>,
     ) -> anyhow::Result<Resource<poll::Pollable>> {
         let view = WasiView::ctx(self);
-        <ResourceTable as streams::HostOutputStream>::subscribe(view.table, stream)
+        let pollable = <ResourceTable as streams::HostOutputStream>::subscribe(view.table, stream)?;
+        self.stream_pollables.insert(pollable.rep());
+        Ok(pollable)
     }
 
     fn write_zeroes(
@@ -588,8 +2398,9 @@ impl streams::HostOutputStream for CtxPlayback {
         stream: Resource<streams::OutputStream>,
         len: u64,
     ) -> StreamResult<()> {
-        let view = WasiView::ctx(self);
-        <ResourceTable as streams::HostOutputStream>::write_zeroes(view.table, stream, len)
+        let zeroes =
+            vec![0; usize::try_from(len).map_err(|err| StreamError::trap(&err.to_string()))?];
+        <Self as streams::HostOutputStream>::write(self, stream, zeroes)
     }
 
     fn flush(&mut self, stream: Resource<streams::OutputStream>) -> StreamResult<()> {
@@ -602,14 +2413,22 @@ impl streams::HostOutputStream for CtxPlayback {
         <ResourceTable as streams::HostOutputStream>::blocking_flush(view.table, stream)
     }
 
+    // Splices go through `read` and `write` so the bytes they move show up in
+    // the trace as a read on `src` and a write on `dst`
     fn splice(
         &mut self,
         dst: Resource<streams::OutputStream>,
         src: Resource<streams::InputStream>,
         len: u64,
     ) -> StreamResult<u64> {
-        let view = WasiView::ctx(self);
-        <ResourceTable as streams::HostOutputStream>::splice(view.table, dst, src, len)
+        let permit = self.check_write(Resource::new_borrow(dst.rep()))?;
+        let bytes = <Self as streams::HostInputStream>::read(self, src, len.min(permit))?;
+        if bytes.is_empty() {
+            return Ok(0);
+        }
+        let spliced = bytes.len() as u64;
+        <Self as streams::HostOutputStream>::write(self, dst, bytes)?;
+        Ok(spliced)
     }
 
     fn blocking_splice(
@@ -618,8 +2437,46 @@ impl streams::HostOutputStream for CtxPlayback {
         src: Resource<streams::InputStream>,
         len: u64,
     ) -> StreamResult<u64> {
-        let view = WasiView::ctx(self);
-        <ResourceTable as streams::HostOutputStream>::blocking_splice(view.table, dst, src, len)
+        let bytes = <Self as streams::HostInputStream>::blocking_read(
+            self,
+            src,
+            len.min(MAX_BLOCKING_WRITE),
+        )?;
+        if bytes.is_empty() {
+            return Ok(0);
+        }
+        let spliced = bytes.len() as u64;
+        self.blocking_write_and_flush(dst, bytes)?;
+        Ok(spliced)
+    }
+}
+
+impl filesystem::preopens::Host for CtxPlayback {
+    fn get_directories(
+        &mut self,
+    ) -> anyhow::Result<Vec<(Resource<filesystem::types::Descriptor>, String)>> {
+        if let Some(mut fs) = self.snapshot() {
+            let dirs = fs.get_directories()?;
+            if self.playback.checks_snapshot() {
+                let recorded = self.playback.next_get_directories()?;
+                let paths = dirs.iter().map(|(_, path)| path);
+                if !paths.eq(recorded.iter().map(|(_, path)| path)) {
+                    return Err(anyhow!(
+                        "snapshot diverged from the recording in get_directories: recorded {:?}, replayed {:?}",
+                        recorded,
+                        dirs.iter().map(|(_, path)| path).collect::<Vec<_>>()
+                    ));
+                }
+                for ((fd, _), (id, _)) in dirs.iter().zip(&recorded) {
+                    register_recorded(&mut self.descriptors, fd.rep(), *id)?;
+                }
+            }
+            return Ok(dirs);
+        }
+        let dirs = self.playback.next_get_directories()?;
+        dirs.into_iter()
+            .map(|(id, path)| Ok((self.push_descriptor(id)?, path)))
+            .collect()
     }
 }
 
@@ -647,25 +2504,51 @@ impl filesystem::types::HostDescriptor for CtxPlayback {
         len: filesystem::types::Filesize,
         advice: filesystem::types::Advice,
     ) -> FsResult<()> {
-        self.filesystem().advise(fd, offset, len, advice)
+        let descriptor = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.advise(fd, offset, len, advice);
+            let recorded = self.recorded(|playback| playback.next_advise(descriptor));
+            return check_snapshot("advise", emulated, recorded, |_, _| true).map(|_| ());
+        }
+        self.playback.next_advise(descriptor)
     }
 
     fn sync_data(&mut self, fd: Resource<filesystem::types::Descriptor>) -> FsResult<()> {
-        self.filesystem().sync_data(fd)
+        let descriptor = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.sync_data(fd);
+            let recorded = self.recorded(|playback| playback.next_sync_data(descriptor));
+            return check_snapshot("sync_data", emulated, recorded, |_, _| true).map(|_| ());
+        }
+        self.playback.next_sync_data(descriptor)
     }
 
     fn get_flags(
         &mut self,
         fd: Resource<filesystem::types::Descriptor>,
     ) -> FsResult<filesystem::types::DescriptorFlags> {
-        self.filesystem().get_flags(fd)
+        let descriptor = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.get_flags(fd);
+            let recorded = self.recorded(|playback| playback.next_get_flags(descriptor));
+            return check_snapshot("get_flags", emulated, recorded, |a, b| a == b)
+                .map(|(flags, _)| flags);
+        }
+        self.playback.next_get_flags(descriptor)
     }
 
     fn get_type(
         &mut self,
         fd: Resource<filesystem::types::Descriptor>,
     ) -> FsResult<filesystem::types::DescriptorType> {
-        self.filesystem().get_type(fd)
+        let descriptor = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.get_type(fd);
+            let recorded = self.recorded(|playback| playback.next_get_type(descriptor));
+            return check_snapshot("get_type", emulated, recorded, |a, b| a == b)
+                .map(|(kind, _)| kind);
+        }
+        self.playback.next_get_type(descriptor)
     }
 
     fn set_size(
@@ -673,7 +2556,13 @@ impl filesystem::types::HostDescriptor for CtxPlayback {
         fd: Resource<filesystem::types::Descriptor>,
         size: filesystem::types::Filesize,
     ) -> FsResult<()> {
-        self.filesystem().set_size(fd, size)
+        let descriptor = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.set_size(fd, size);
+            let recorded = self.recorded(|playback| playback.next_set_size(descriptor, size));
+            return check_snapshot("set_size", emulated, recorded, |_, _| true).map(|_| ());
+        }
+        self.playback.next_set_size(descriptor, size)
     }
 
     fn set_times(
@@ -682,7 +2571,21 @@ impl filesystem::types::HostDescriptor for CtxPlayback {
         atim: filesystem::types::NewTimestamp,
         mtim: filesystem::types::NewTimestamp,
     ) -> FsResult<()> {
-        self.filesystem().set_times(fd, atim, mtim)
+        let descriptor = self.descriptors.get(fd.rep());
+        // The snapshot applies `now` as the time it took when recorded
+        let recorded = self.recorded(|playback| playback.next_set_times(descriptor, atim, mtim));
+        if let Some(mut fs) = self.snapshot() {
+            let now = recorded_now(&recorded);
+            let emulated = fs.set_times(
+                fd,
+                replayed_timestamp_update(atim, now),
+                replayed_timestamp_update(mtim, now),
+            );
+            return check_snapshot("set_times", emulated, recorded, |_, _| true).map(|_| ());
+        }
+        self.playback
+            .next_set_times(descriptor, atim, mtim)
+            .map(|_| ())
     }
 
     fn read(
@@ -691,8 +2594,14 @@ impl filesystem::types::HostDescriptor for CtxPlayback {
         len: filesystem::types::Filesize,
         offset: filesystem::types::Filesize,
     ) -> FsResult<(Vec<u8>, bool)> {
-        self.playback.expect_read_event().map_err(FsError::trap)?;
-        self.filesystem().read(fd, len, offset)
+        let descriptor = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.read(fd, len, offset);
+            let recorded =
+                self.recorded(|playback| playback.next_file_read(descriptor, len, offset));
+            return check_snapshot("read", emulated, recorded, |a, b| a == b).map(|(read, _)| read);
+        }
+        self.playback.next_file_read(descriptor, len, offset)
     }
 
     fn write(
@@ -701,18 +2610,49 @@ impl filesystem::types::HostDescriptor for CtxPlayback {
         buf: Vec<u8>,
         offset: filesystem::types::Filesize,
     ) -> FsResult<filesystem::types::Filesize> {
-        self.filesystem().write(fd, buf, offset)
+        let descriptor = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.write(fd, buf.clone(), offset);
+            let recorded =
+                self.recorded(|playback| playback.next_file_write(descriptor, offset, &buf));
+            return check_snapshot("write", emulated, recorded, |a, b| a == b)
+                .map(|(written, _)| written);
+        }
+        self.playback.next_file_write(descriptor, offset, &buf)
     }
 
     fn read_directory(
         &mut self,
         fd: Resource<filesystem::types::Descriptor>,
     ) -> FsResult<Resource<filesystem::types::DirectoryEntryStream>> {
-        self.filesystem().read_directory(fd)
+        let dir = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.read_directory(fd);
+            let recorded = self.recorded(|playback| playback.next_read_directory(dir));
+            let (stream, id) = check_snapshot("read_directory", emulated, recorded, |_, _| true)?;
+            if let Some(id) = id {
+                register_recorded(&mut self.directory_streams, stream.rep(), id)?;
+            }
+            return Ok(stream);
+        }
+        let id = self.playback.next_read_directory(dir)?;
+        let rep = self
+            .table
+            .push(ReplayDirectoryStream)
+            .map_err(FsError::trap)?
+            .rep();
+        register_recorded(&mut self.directory_streams, rep, id)?;
+        Ok(Resource::new_own(rep))
     }
 
     fn sync(&mut self, fd: Resource<filesystem::types::Descriptor>) -> FsResult<()> {
-        self.filesystem().sync(fd)
+        let descriptor = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.sync(fd);
+            let recorded = self.recorded(|playback| playback.next_sync(descriptor));
+            return check_snapshot("sync", emulated, recorded, |_, _| true).map(|_| ());
+        }
+        self.playback.next_sync(descriptor)
     }
 
     fn create_directory_at(
@@ -720,14 +2660,28 @@ impl filesystem::types::HostDescriptor for CtxPlayback {
         fd: Resource<filesystem::types::Descriptor>,
         path: String,
     ) -> FsResult<()> {
-        self.filesystem().create_directory_at(fd, path)
+        let dir = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.create_directory_at(fd, path.clone());
+            let recorded = self.recorded(|playback| playback.next_create_directory_at(dir, &path));
+            return check_snapshot("create_directory_at", emulated, recorded, |_, _| true)
+                .map(|_| ());
+        }
+        self.playback.next_create_directory_at(dir, &path)
     }
 
     fn stat(
         &mut self,
         fd: Resource<filesystem::types::Descriptor>,
     ) -> FsResult<filesystem::types::DescriptorStat> {
-        self.filesystem().stat(fd)
+        let descriptor = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.stat(fd);
+            let recorded = self.recorded(|playback| playback.next_stat(descriptor));
+            return check_snapshot("stat", emulated, recorded, same_stat)
+                .map(|(emulated, recorded)| recorded.unwrap_or(emulated));
+        }
+        self.playback.next_stat(descriptor)
     }
 
     fn stat_at(
@@ -736,7 +2690,14 @@ impl filesystem::types::HostDescriptor for CtxPlayback {
         path_flags: filesystem::types::PathFlags,
         path: String,
     ) -> FsResult<filesystem::types::DescriptorStat> {
-        self.filesystem().stat_at(fd, path_flags, path)
+        let dir = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.stat_at(fd, path_flags, path.clone());
+            let recorded = self.recorded(|playback| playback.next_stat_at(dir, &path, path_flags));
+            return check_snapshot("stat_at", emulated, recorded, same_stat)
+                .map(|(emulated, recorded)| recorded.unwrap_or(emulated));
+        }
+        self.playback.next_stat_at(dir, &path, path_flags)
     }
 
     fn set_times_at(
@@ -747,8 +2708,24 @@ impl filesystem::types::HostDescriptor for CtxPlayback {
         atim: filesystem::types::NewTimestamp,
         mtim: filesystem::types::NewTimestamp,
     ) -> FsResult<()> {
-        self.filesystem()
-            .set_times_at(fd, path_flags, path, atim, mtim)
+        let dir = self.descriptors.get(fd.rep());
+        // The snapshot applies `now` as the time it took when recorded
+        let recorded = self
+            .recorded(|playback| playback.next_set_times_at(dir, &path, path_flags, atim, mtim));
+        if let Some(mut fs) = self.snapshot() {
+            let now = recorded_now(&recorded);
+            let emulated = fs.set_times_at(
+                fd,
+                path_flags,
+                path,
+                replayed_timestamp_update(atim, now),
+                replayed_timestamp_update(mtim, now),
+            );
+            return check_snapshot("set_times_at", emulated, recorded, |_, _| true).map(|_| ());
+        }
+        self.playback
+            .next_set_times_at(dir, &path, path_flags, atim, mtim)
+            .map(|_| ())
     }
 
     fn link_at(
@@ -759,8 +2736,17 @@ impl filesystem::types::HostDescriptor for CtxPlayback {
         new_fd: Resource<filesystem::types::Descriptor>,
         new_path: String,
     ) -> FsResult<()> {
-        self.filesystem()
-            .link_at(fd, path_flags, old_path, new_fd, new_path)
+        let dir = self.descriptors.get(fd.rep());
+        let new_dir = self.descriptors.get(new_fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.link_at(fd, path_flags, old_path.clone(), new_fd, new_path.clone());
+            let recorded = self.recorded(|playback| {
+                playback.next_link_at(dir, path_flags, &old_path, new_dir, &new_path)
+            });
+            return check_snapshot("link_at", emulated, recorded, |_, _| true).map(|_| ());
+        }
+        self.playback
+            .next_link_at(dir, path_flags, &old_path, new_dir, &new_path)
     }
 
     fn open_at(
@@ -771,13 +2757,36 @@ impl filesystem::types::HostDescriptor for CtxPlayback {
         open_flags: filesystem::types::OpenFlags,
         descriptor_flags: filesystem::types::DescriptorFlags,
     ) -> FsResult<Resource<filesystem::types::Descriptor>> {
-        self.filesystem()
-            .open_at(fd, path_flags, path, open_flags, descriptor_flags)
+        let dir = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.open_at(fd, path_flags, path.clone(), open_flags, descriptor_flags);
+            let recorded = self.recorded(|playback| {
+                playback.next_open_at(dir, &path, path_flags, open_flags, descriptor_flags)
+            });
+            let (descriptor, id) = check_snapshot("open_at", emulated, recorded, |_, _| true)?;
+            if let Some(id) = id {
+                register_recorded(&mut self.descriptors, descriptor.rep(), id)?;
+            }
+            return Ok(descriptor);
+        }
+        let id =
+            self.playback
+                .next_open_at(dir, &path, path_flags, open_flags, descriptor_flags)?;
+        self.push_descriptor(id)
     }
 
     fn drop(&mut self, fd: Resource<filesystem::types::Descriptor>) -> anyhow::Result<()> {
-        let mut fs = self.filesystem();
-        filesystem::types::HostDescriptor::drop(&mut fs, fd)
+        let rep = fd.rep();
+        if let Some(mut fs) = self.snapshot() {
+            filesystem::types::HostDescriptor::drop(&mut fs, fd)?;
+        } else {
+            self.table
+                .delete(Resource::<ReplayDescriptor>::new_own(rep))?;
+        }
+        if let Some(id) = self.descriptors.remove(rep) {
+            self.playback.expect_descriptor_drop(id)?;
+        }
+        Ok(())
     }
 
     fn readlink_at(
@@ -785,7 +2794,14 @@ impl filesystem::types::HostDescriptor for CtxPlayback {
         fd: Resource<filesystem::types::Descriptor>,
         path: String,
     ) -> FsResult<String> {
-        self.filesystem().readlink_at(fd, path)
+        let dir = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.readlink_at(fd, path.clone());
+            let recorded = self.recorded(|playback| playback.next_readlink_at(dir, &path));
+            return check_snapshot("readlink_at", emulated, recorded, |a, b| a == b)
+                .map(|(target, _)| target);
+        }
+        self.playback.next_readlink_at(dir, &path)
     }
 
     fn remove_directory_at(
@@ -793,7 +2809,14 @@ impl filesystem::types::HostDescriptor for CtxPlayback {
         fd: Resource<filesystem::types::Descriptor>,
         path: String,
     ) -> FsResult<()> {
-        self.filesystem().remove_directory_at(fd, path)
+        let dir = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.remove_directory_at(fd, path.clone());
+            let recorded = self.recorded(|playback| playback.next_remove_directory_at(dir, &path));
+            return check_snapshot("remove_directory_at", emulated, recorded, |_, _| true)
+                .map(|_| ());
+        }
+        self.playback.next_remove_directory_at(dir, &path)
     }
 
     fn rename_at(
@@ -803,7 +2826,16 @@ impl filesystem::types::HostDescriptor for CtxPlayback {
         new_fd: Resource<filesystem::types::Descriptor>,
         new_path: String,
     ) -> FsResult<()> {
-        self.filesystem().rename_at(fd, old_path, new_fd, new_path)
+        let dir = self.descriptors.get(fd.rep());
+        let new_dir = self.descriptors.get(new_fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.rename_at(fd, old_path.clone(), new_fd, new_path.clone());
+            let recorded = self
+                .recorded(|playback| playback.next_rename_at(dir, &old_path, new_dir, &new_path));
+            return check_snapshot("rename_at", emulated, recorded, |_, _| true).map(|_| ());
+        }
+        self.playback
+            .next_rename_at(dir, &old_path, new_dir, &new_path)
     }
 
     fn symlink_at(
@@ -812,7 +2844,14 @@ impl filesystem::types::HostDescriptor for CtxPlayback {
         old_path: String,
         new_path: String,
     ) -> FsResult<()> {
-        self.filesystem().symlink_at(fd, old_path, new_path)
+        let dir = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.symlink_at(fd, old_path.clone(), new_path.clone());
+            let recorded =
+                self.recorded(|playback| playback.next_symlink_at(dir, &old_path, &new_path));
+            return check_snapshot("symlink_at", emulated, recorded, |_, _| true).map(|_| ());
+        }
+        self.playback.next_symlink_at(dir, &old_path, &new_path)
     }
 
     fn unlink_file_at(
@@ -820,7 +2859,13 @@ impl filesystem::types::HostDescriptor for CtxPlayback {
         fd: Resource<filesystem::types::Descriptor>,
         path: String,
     ) -> FsResult<()> {
-        self.filesystem().unlink_file_at(fd, path)
+        let dir = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.unlink_file_at(fd, path.clone());
+            let recorded = self.recorded(|playback| playback.next_unlink_file_at(dir, &path));
+            return check_snapshot("unlink_file_at", emulated, recorded, |_, _| true).map(|_| ());
+        }
+        self.playback.next_unlink_file_at(dir, &path)
     }
 
     fn read_via_stream(
@@ -828,7 +2873,24 @@ impl filesystem::types::HostDescriptor for CtxPlayback {
         fd: Resource<filesystem::types::Descriptor>,
         offset: filesystem::types::Filesize,
     ) -> FsResult<Resource<streams::InputStream>> {
-        self.filesystem().read_via_stream(fd, offset)
+        let descriptor = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.read_via_stream(fd, offset);
+            let recorded = self.recorded(|playback| {
+                playback.next_file_stream(descriptor, StreamOrigin::ReadViaStream)
+            });
+            let (stream, _) = check_snapshot("read_via_stream", emulated, recorded, |_, _| true)?;
+            self.snapshot_streams.insert(stream.rep());
+            return Ok(stream);
+        }
+        self.playback
+            .next_file_stream(descriptor, StreamOrigin::ReadViaStream)?;
+        // Reads are answered from the trace, so the stream itself never yields data
+        let stream: streams::InputStream = Box::new(ClosedInputStream);
+        let stream = self.table.push(stream).map_err(FsError::trap)?;
+        self.stream_id(stream.rep(), StreamOrigin::ReadViaStream)
+            .map_err(FsError::trap)?;
+        Ok(stream)
     }
 
     fn write_via_stream(
@@ -836,14 +2898,34 @@ impl filesystem::types::HostDescriptor for CtxPlayback {
         fd: Resource<filesystem::types::Descriptor>,
         offset: filesystem::types::Filesize,
     ) -> FsResult<Resource<streams::OutputStream>> {
-        self.filesystem().write_via_stream(fd, offset)
+        let descriptor = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.write_via_stream(fd, offset);
+            let recorded = self.recorded(|playback| {
+                playback.next_file_stream(descriptor, StreamOrigin::WriteViaStream)
+            });
+            let (stream, _) = check_snapshot("write_via_stream", emulated, recorded, |_, _| true)?;
+            self.snapshot_streams.insert(stream.rep());
+            return Ok(stream);
+        }
+        self.push_file_output_stream(fd, StreamOrigin::WriteViaStream)
     }
 
     fn append_via_stream(
         &mut self,
         fd: Resource<filesystem::types::Descriptor>,
     ) -> FsResult<Resource<streams::OutputStream>> {
-        self.filesystem().append_via_stream(fd)
+        let descriptor = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.append_via_stream(fd);
+            let recorded = self.recorded(|playback| {
+                playback.next_file_stream(descriptor, StreamOrigin::AppendViaStream)
+            });
+            let (stream, _) = check_snapshot("append_via_stream", emulated, recorded, |_, _| true)?;
+            self.snapshot_streams.insert(stream.rep());
+            return Ok(stream);
+        }
+        self.push_file_output_stream(fd, StreamOrigin::AppendViaStream)
     }
 
     fn is_same_object(
@@ -851,14 +2933,34 @@ impl filesystem::types::HostDescriptor for CtxPlayback {
         a: Resource<filesystem::types::Descriptor>,
         b: Resource<filesystem::types::Descriptor>,
     ) -> anyhow::Result<bool> {
-        self.filesystem().is_same_object(a, b)
+        let ids = (self.descriptors.get(a.rep()), self.descriptors.get(b.rep()));
+        if let Some(mut fs) = self.snapshot() {
+            let same = fs.is_same_object(a, b)?;
+            if self.playback.checks_snapshot() {
+                let recorded = self.playback.next_is_same_object(ids.0, ids.1)?;
+                if same != recorded {
+                    return Err(anyhow!(
+                        "snapshot diverged from the recording in is_same_object: recorded {recorded}, replayed {same}"
+                    ));
+                }
+            }
+            return Ok(same);
+        }
+        self.playback.next_is_same_object(ids.0, ids.1)
     }
 
     fn metadata_hash(
         &mut self,
         fd: Resource<filesystem::types::Descriptor>,
     ) -> FsResult<filesystem::types::MetadataHashValue> {
-        self.filesystem().metadata_hash(fd)
+        let descriptor = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.metadata_hash(fd);
+            let recorded = self.recorded(|playback| playback.next_metadata_hash(descriptor));
+            return check_snapshot("metadata_hash", emulated, recorded, |_, _| true)
+                .map(|(emulated, recorded)| recorded.unwrap_or(emulated));
+        }
+        self.playback.next_metadata_hash(descriptor)
     }
 
     fn metadata_hash_at(
@@ -867,7 +2969,15 @@ impl filesystem::types::HostDescriptor for CtxPlayback {
         path_flags: filesystem::types::PathFlags,
         path: String,
     ) -> FsResult<filesystem::types::MetadataHashValue> {
-        self.filesystem().metadata_hash_at(fd, path_flags, path)
+        let dir = self.descriptors.get(fd.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.metadata_hash_at(fd, path_flags, path.clone());
+            let recorded =
+                self.recorded(|playback| playback.next_metadata_hash_at(dir, &path, path_flags));
+            return check_snapshot("metadata_hash_at", emulated, recorded, |_, _| true)
+                .map(|(emulated, recorded)| recorded.unwrap_or(emulated));
+        }
+        self.playback.next_metadata_hash_at(dir, &path, path_flags)
     }
 }
 
@@ -876,14 +2986,77 @@ impl filesystem::types::HostDirectoryEntryStream for CtxPlayback {
         &mut self,
         stream: Resource<filesystem::types::DirectoryEntryStream>,
     ) -> FsResult<Option<filesystem::types::DirectoryEntry>> {
-        self.filesystem().read_directory_entry(stream)
+        let id = self.directory_streams.get(stream.rep());
+        if let Some(mut fs) = self.snapshot() {
+            let emulated = fs.read_directory_entry(stream);
+            let recorded = self.recorded(|playback| playback.next_read_directory_entry(id));
+            return check_snapshot("read_directory_entry", emulated, recorded, |a, b| {
+                a.as_ref().map(|entry| (entry.type_, &entry.name))
+                    == b.as_ref().map(|entry| (entry.type_, &entry.name))
+            })
+            .map(|(entry, _)| entry);
+        }
+        self.playback.next_read_directory_entry(id)
     }
 
     fn drop(
         &mut self,
         stream: Resource<filesystem::types::DirectoryEntryStream>,
     ) -> anyhow::Result<()> {
-        let mut fs = self.filesystem();
-        filesystem::types::HostDirectoryEntryStream::drop(&mut fs, stream)
+        let rep = stream.rep();
+        if let Some(mut fs) = self.snapshot() {
+            filesystem::types::HostDirectoryEntryStream::drop(&mut fs, stream)?;
+        } else {
+            self.table
+                .delete(Resource::<ReplayDirectoryStream>::new_own(rep))?;
+        }
+        if let Some(id) = self.directory_streams.remove(rep) {
+            self.playback.expect_directory_stream_drop(id)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::headers_match;
+
+    fn pairs(entries: &[(&str, &str)]) -> Vec<(String, String)> {
+        entries
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn headers_match_exactly_without_redaction() {
+        let recorded = pairs(&[("accept", "*/*"), ("host", "example.com")]);
+        assert!(headers_match(&recorded, &[], &recorded));
+        let changed = pairs(&[("accept", "text/html"), ("host", "example.com")]);
+        assert!(!headers_match(&recorded, &[], &changed));
+        let extra = pairs(&[("accept", "*/*"), ("host", "example.com"), ("x-a", "1")]);
+        assert!(!headers_match(&recorded, &[], &extra));
+        let renamed = pairs(&[("accept", "*/*"), ("origin", "example.com")]);
+        assert!(!headers_match(&recorded, &[], &renamed));
+    }
+
+    #[test]
+    fn redacted_headers_accept_any_value() {
+        let recorded = pairs(&[("authorization", "<redacted:1>"), ("host", "example.com")]);
+        let redacted = ["authorization".to_string()];
+        let actual = pairs(&[("authorization", "Bearer new"), ("host", "example.com")]);
+        assert!(headers_match(&recorded, &redacted, &actual));
+        // Only the value is free; the header still has to be sent
+        let missing = pairs(&[("host", "example.com")]);
+        assert!(!headers_match(&recorded, &redacted, &missing));
+        let other_host = pairs(&[("authorization", "Bearer new"), ("host", "example.org")]);
+        assert!(!headers_match(&recorded, &redacted, &other_host));
+    }
+
+    #[test]
+    fn placeholder_values_are_not_wildcards_by_themselves() {
+        let recorded = pairs(&[("x-note", "<redacted:1>")]);
+        assert!(!headers_match(&recorded, &[], &pairs(&[("x-note", "hi")])));
+        assert!(headers_match(&recorded, &[], &recorded));
     }
 }
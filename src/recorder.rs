@@ -1,27 +1,44 @@
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::{BufWriter, Write};
 use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::task::{Context, Poll};
+use std::time::Instant;
 
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, Context as _};
 use bytes::Bytes;
-use http_body_util::{BodyExt, Full};
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::BodyExt;
+use hyper::body::{Body, Frame, SizeHint};
 use wasmtime::component::{Resource, ResourceTable};
 use wasmtime_wasi::cli::WasiCliView;
 use wasmtime_wasi::clocks::WasiClocksView as _;
 use wasmtime_wasi::filesystem::WasiFilesystemView as _;
 use wasmtime_wasi::p2::bindings::sync::io::{poll, streams};
 use wasmtime_wasi::p2::bindings::{cli, clocks, random, sync::filesystem};
-use wasmtime_wasi::p2::{FsResult, StreamError, StreamResult};
+use wasmtime_wasi::p2::{FsError, FsResult, StreamError, StreamResult};
 use wasmtime_wasi::random::WasiRandomView as _;
 use wasmtime_wasi::runtime;
 use wasmtime_wasi::{WasiCtx, WasiCtxView, WasiView};
+use wasmtime_wasi_http::bindings::http::types::ErrorCode;
 use wasmtime_wasi_http::types::{
     default_send_request, HostFutureIncomingResponse, IncomingResponse, OutgoingRequestConfig,
 };
-use wasmtime_wasi_http::{HttpError, WasiHttpCtx, WasiHttpView};
+use wasmtime_wasi_http::{WasiHttpCtx, WasiHttpView};
 
-use crate::trace::{TraceEvent, TraceFormat};
-use crate::wasi::util::sorted_headers;
+use crate::trace::{
+    DirEntry, FileHash, FileStat, FileTimestamp, HttpBodyFrame, HttpErrorCode, HttpRequestConfig,
+    RedactionPolicy, Redactor, SnapshotDir, StreamOrigin, TraceEvent, TraceFormat, TraceHeader,
+};
+use crate::wasi::filesystem::{
+    applied_now, descriptor_flag_names, descriptor_type_name, open_flag_names, path_flag_names,
+    recorded_entry, recorded_error_code, recorded_hash, recorded_stat, recorded_timestamp_update,
+};
+use crate::wasi::handles::HandleRegistry;
+use crate::wasi::http;
+use crate::wasi::util::{sorted_headers, MAX_BLOCKING_WRITE};
 use anyhow::Result;
 
 enum TraceWriter {
@@ -35,24 +52,29 @@ enum TraceWriter {
 }
 
 impl TraceWriter {
-    fn new(output: PathBuf, format: TraceFormat) -> Result<Self> {
+    fn new(output: PathBuf, format: TraceFormat, header: &TraceHeader) -> Result<Self> {
         let file = File::create(&output)
             .with_context(|| format!("failed to create trace file at {}", output.display()))?;
-        let writer = BufWriter::new(file);
+        let mut writer = BufWriter::new(file);
 
         match format {
             TraceFormat::Json => {
-                let mut writer = writer;
-                // Write the beginning of the JSON array
+                // Write the header and the beginning of the events array
                 writer
-                    .write_all(b"{\"events\":[\n")
+                    .write_all(b"{\"header\":")
+                    .and_then(|()| serde_json::to_writer(&mut writer, header).map_err(Into::into))
+                    .and_then(|()| writer.write_all(b",\"events\":[\n"))
                     .context("failed to write JSON header")?;
                 Ok(TraceWriter::Json {
                     writer,
                     first: true,
                 })
             }
-            TraceFormat::Cbor => Ok(TraceWriter::Cbor { writer }),
+            TraceFormat::Cbor => {
+                ciborium::into_writer(header, &mut writer)
+                    .context("failed to write CBOR header")?;
+                Ok(TraceWriter::Cbor { writer })
+            }
         }
     }
 
@@ -93,23 +115,66 @@ impl TraceWriter {
 pub struct Recorder {
     writer: Option<TraceWriter>,
     error: Option<anyhow::Error>,
+    /// Whether the preopens were snapshotted, so replay serves filesystem calls
+    /// from the snapshot and only checks their results against the trace;
+    /// traffic on file streams stays out of it
+    snapshot: bool,
+    redactor: Redactor,
+    /// Number of HTTP requests sent so far, which is the id of the next one
+    http_requests: u32,
+    /// Handed to each request's task to report back what came of it
+    http_outcomes: mpsc::Sender<HttpOutcome>,
+    /// Outcomes reported but not written yet
+    finished_http: mpsc::Receiver<HttpOutcome>,
+}
+
+/// What came of an HTTP request, reported back by the task that sent it or,
+/// for a response, by its body once the guest is done reading it
+pub enum HttpOutcome {
+    Response {
+        request: u32,
+        status: u16,
+        headers: Vec<(String, String)>,
+        between_bytes_timeout_nanoseconds: u64,
+        frames: Vec<HttpBodyFrame>,
+        body_error: Option<HttpErrorCode>,
+    },
+    Error {
+        request: u32,
+        error: HttpErrorCode,
+    },
+    RequestBody {
+        request: u32,
+        body: Vec<u8>,
+    },
 }
 
 impl Recorder {
-    pub fn new(output: PathBuf, format: TraceFormat) -> Self {
-        match TraceWriter::new(output, format) {
-            Ok(writer) => Self {
-                writer: Some(writer),
-                error: None,
-            },
-            Err(e) => Self {
-                writer: None,
-                error: Some(e),
-            },
+    pub fn new(output: PathBuf, format: TraceFormat, header: &TraceHeader) -> Self {
+        let (writer, error) = match TraceWriter::new(output, format, header) {
+            Ok(writer) => (Some(writer), None),
+            Err(e) => (None, Some(e)),
+        };
+        let (http_outcomes, finished_http) = mpsc::channel();
+        Self {
+            writer,
+            error,
+            snapshot: false,
+            redactor: Redactor::default(),
+            http_requests: 0,
+            http_outcomes,
+            finished_http,
         }
     }
 
+    /// Write an event, after the outcomes of any HTTP requests that finished
+    /// since the last one
     fn write_event(&mut self, event: TraceEvent) {
+        self.record_http_outcomes();
+        self.emit(event);
+    }
+
+    fn emit(&mut self, event: TraceEvent) {
         if self.error.is_some() {
             return;
         }
@@ -120,6 +185,10 @@ impl Recorder {
         }
     }
 
+    pub fn has_snapshot(&self) -> bool {
+        self.snapshot
+    }
+
     pub fn record_now(&mut self, dt: &clocks::wall_clock::Datetime) {
         self.write_event(TraceEvent::ClockNow {
             seconds: dt.seconds,
@@ -142,7 +211,13 @@ impl Recorder {
         self.write_event(TraceEvent::MonotonicClockResolution { nanoseconds });
     }
 
+    /// Keep secrets matching `policy` out of every event recorded from now on
+    pub fn set_redaction(&mut self, policy: RedactionPolicy) {
+        self.redactor = Redactor::new(policy);
+    }
+
     pub fn record_environment(&mut self, entries: Vec<(String, String)>) {
+        let entries = self.redactor.redact_env(entries);
         self.write_event(TraceEvent::Environment { entries });
     }
 
@@ -174,29 +249,569 @@ impl Recorder {
         self.write_event(TraceEvent::InsecureSeed { seed });
     }
 
-    pub fn record_filesystem_read(&mut self) {
-        self.write_event(TraceEvent::Read);
+    pub fn record_snapshot(&mut self, store: String, dirs: Vec<SnapshotDir>) {
+        self.write_event(TraceEvent::Snapshot {
+            store,
+            dirs,
+            calls_traced: true,
+        });
+        self.snapshot = true;
     }
 
-    pub fn record_http_response(
+    pub fn record_get_directories(&mut self, dirs: Vec<(u32, String)>) {
+        self.write_event(TraceEvent::GetDirectories { dirs });
+    }
+
+    pub fn record_file_read(
         &mut self,
-        request_method: String,
-        request_url: String,
-        request_headers: Vec<(String, String)>,
-        status: u16,
-        headers: Vec<(String, String)>,
-        body: Vec<u8>,
+        descriptor: Option<u32>,
+        offset: u64,
+        result: &FsResult<(Vec<u8>, bool)>,
     ) {
-        self.write_event(TraceEvent::HttpResponse {
-            request_method,
-            request_url,
-            request_headers,
-            status,
+        let event = match result {
+            Ok((bytes, eof)) => TraceEvent::FileRead {
+                descriptor,
+                offset,
+                bytes: bytes.clone(),
+                eof: *eof,
+                error: None,
+            },
+            Err(err) => match recorded_error_code(err) {
+                Some(code) => TraceEvent::FileRead {
+                    descriptor,
+                    offset,
+                    bytes: Vec::new(),
+                    eof: false,
+                    error: Some(code.to_string()),
+                },
+                None => return,
+            },
+        };
+        self.write_event(event);
+    }
+
+    pub fn record_open_at(
+        &mut self,
+        dir: Option<u32>,
+        path: String,
+        path_flags: filesystem::types::PathFlags,
+        open_flags: filesystem::types::OpenFlags,
+        descriptor_flags: filesystem::types::DescriptorFlags,
+        result: Result<u32, &FsError>,
+    ) {
+        let Some((descriptor, error)) = fs_outcome(result) else {
+            return;
+        };
+        self.write_event(TraceEvent::OpenAt {
+            dir,
+            path,
+            path_flags: path_flag_names(path_flags),
+            open_flags: open_flag_names(open_flags),
+            descriptor_flags: descriptor_flag_names(descriptor_flags),
+            descriptor,
+            error,
+        });
+    }
+
+    pub fn record_descriptor_drop(&mut self, descriptor: u32) {
+        self.write_event(TraceEvent::DescriptorDrop { descriptor });
+    }
+
+    pub fn record_read_directory(&mut self, dir: Option<u32>, result: Result<u32, &FsError>) {
+        let Some((stream, error)) = fs_outcome(result) else {
+            return;
+        };
+        self.write_event(TraceEvent::ReadDirectory { dir, stream, error });
+    }
+
+    pub fn record_read_directory_entry(
+        &mut self,
+        stream: Option<u32>,
+        result: Result<Option<DirEntry>, &FsError>,
+    ) {
+        let Some((entry, error)) = fs_outcome(result) else {
+            return;
+        };
+        self.write_event(TraceEvent::ReadDirectoryEntry {
+            stream,
+            entry: entry.flatten(),
+            error,
+        });
+    }
+
+    pub fn record_directory_stream_drop(&mut self, stream: u32) {
+        self.write_event(TraceEvent::DirectoryStreamDrop { stream });
+    }
+
+    pub fn record_stat(&mut self, descriptor: Option<u32>, result: Result<FileStat, &FsError>) {
+        let Some((stat, error)) = fs_outcome(result) else {
+            return;
+        };
+        self.write_event(TraceEvent::Stat {
+            descriptor,
+            stat,
+            error,
+        });
+    }
+
+    pub fn record_stat_at(
+        &mut self,
+        dir: Option<u32>,
+        path: String,
+        path_flags: filesystem::types::PathFlags,
+        result: Result<FileStat, &FsError>,
+    ) {
+        let Some((stat, error)) = fs_outcome(result) else {
+            return;
+        };
+        self.write_event(TraceEvent::StatAt {
+            dir,
+            path,
+            path_flags: path_flag_names(path_flags),
+            stat,
+            error,
+        });
+    }
+
+    pub fn record_metadata_hash(
+        &mut self,
+        descriptor: Option<u32>,
+        result: Result<FileHash, &FsError>,
+    ) {
+        let Some((hash, error)) = fs_outcome(result) else {
+            return;
+        };
+        self.write_event(TraceEvent::MetadataHash {
+            descriptor,
+            hash,
+            error,
+        });
+    }
+
+    pub fn record_metadata_hash_at(
+        &mut self,
+        dir: Option<u32>,
+        path: String,
+        path_flags: filesystem::types::PathFlags,
+        result: Result<FileHash, &FsError>,
+    ) {
+        let Some((hash, error)) = fs_outcome(result) else {
+            return;
+        };
+        self.write_event(TraceEvent::MetadataHashAt {
+            dir,
+            path,
+            path_flags: path_flag_names(path_flags),
+            hash,
+            error,
+        });
+    }
+
+    pub fn record_get_type(&mut self, descriptor: Option<u32>, result: Result<String, &FsError>) {
+        let Some((kind, error)) = fs_outcome(result) else {
+            return;
+        };
+        self.write_event(TraceEvent::GetType {
+            descriptor,
+            kind,
+            error,
+        });
+    }
+
+    pub fn record_get_flags(
+        &mut self,
+        descriptor: Option<u32>,
+        result: Result<Vec<String>, &FsError>,
+    ) {
+        let Some((flags, error)) = fs_outcome(result) else {
+            return;
+        };
+        self.write_event(TraceEvent::GetFlags {
+            descriptor,
+            flags,
+            error,
+        });
+    }
+
+    pub fn record_readlink_at(
+        &mut self,
+        dir: Option<u32>,
+        path: String,
+        result: Result<String, &FsError>,
+    ) {
+        let Some((target, error)) = fs_outcome(result) else {
+            return;
+        };
+        self.write_event(TraceEvent::ReadlinkAt {
+            dir,
+            path,
+            target,
+            error,
+        });
+    }
+
+    pub fn record_is_same_object(&mut self, a: Option<u32>, b: Option<u32>, same: bool) {
+        self.write_event(TraceEvent::IsSameObject { a, b, same });
+    }
+
+    pub fn record_advise(&mut self, descriptor: Option<u32>, result: &FsResult<()>) {
+        let Some((_, error)) = fs_outcome(result.as_ref()) else {
+            return;
+        };
+        self.write_event(TraceEvent::Advise { descriptor, error });
+    }
+
+    pub fn record_sync(&mut self, descriptor: Option<u32>, result: &FsResult<()>) {
+        let Some((_, error)) = fs_outcome(result.as_ref()) else {
+            return;
+        };
+        self.write_event(TraceEvent::Sync { descriptor, error });
+    }
+
+    pub fn record_sync_data(&mut self, descriptor: Option<u32>, result: &FsResult<()>) {
+        let Some((_, error)) = fs_outcome(result.as_ref()) else {
+            return;
+        };
+        self.write_event(TraceEvent::SyncData { descriptor, error });
+    }
+
+    pub fn record_file_stream_error(
+        &mut self,
+        descriptor: Option<u32>,
+        origin: StreamOrigin,
+        err: &FsError,
+    ) {
+        if let Some(code) = recorded_error_code(err) {
+            self.write_event(TraceEvent::FileStreamError {
+                descriptor,
+                origin,
+                error: code.to_string(),
+            });
+        }
+    }
+
+    pub fn record_file_write(
+        &mut self,
+        descriptor: Option<u32>,
+        offset: u64,
+        bytes: Vec<u8>,
+        result: Result<u64, &FsError>,
+    ) {
+        let Some((written, error)) = fs_outcome(result) else {
+            return;
+        };
+        self.write_event(TraceEvent::FileWrite {
+            descriptor,
+            offset,
+            bytes,
+            written,
+            error,
+        });
+    }
+
+    pub fn record_set_size(&mut self, descriptor: Option<u32>, size: u64, result: &FsResult<()>) {
+        let Some((_, error)) = fs_outcome(result.as_ref()) else {
+            return;
+        };
+        self.write_event(TraceEvent::SetSize {
+            descriptor,
+            size,
+            error,
+        });
+    }
+
+    pub fn record_set_times(
+        &mut self,
+        descriptor: Option<u32>,
+        access: filesystem::types::NewTimestamp,
+        modification: filesystem::types::NewTimestamp,
+        result: Result<Option<FileTimestamp>, &FsError>,
+    ) {
+        let Some((now, error)) = fs_outcome(result) else {
+            return;
+        };
+        self.write_event(TraceEvent::SetTimes {
+            descriptor,
+            access: recorded_timestamp_update(access),
+            modification: recorded_timestamp_update(modification),
+            now: now.flatten(),
+            error,
+        });
+    }
+
+    pub fn record_set_times_at(
+        &mut self,
+        dir: Option<u32>,
+        path: String,
+        path_flags: filesystem::types::PathFlags,
+        access: filesystem::types::NewTimestamp,
+        modification: filesystem::types::NewTimestamp,
+        result: Result<Option<FileTimestamp>, &FsError>,
+    ) {
+        let Some((now, error)) = fs_outcome(result) else {
+            return;
+        };
+        self.write_event(TraceEvent::SetTimesAt {
+            dir,
+            path,
+            path_flags: path_flag_names(path_flags),
+            access: recorded_timestamp_update(access),
+            modification: recorded_timestamp_update(modification),
+            now: now.flatten(),
+            error,
+        });
+    }
+
+    pub fn record_create_directory_at(
+        &mut self,
+        dir: Option<u32>,
+        path: String,
+        result: &FsResult<()>,
+    ) {
+        let Some((_, error)) = fs_outcome(result.as_ref()) else {
+            return;
+        };
+        self.write_event(TraceEvent::CreateDirectoryAt { dir, path, error });
+    }
+
+    pub fn record_link_at(
+        &mut self,
+        dir: Option<u32>,
+        path_flags: filesystem::types::PathFlags,
+        old_path: String,
+        new_dir: Option<u32>,
+        new_path: String,
+        result: &FsResult<()>,
+    ) {
+        let Some((_, error)) = fs_outcome(result.as_ref()) else {
+            return;
+        };
+        self.write_event(TraceEvent::LinkAt {
+            dir,
+            path_flags: path_flag_names(path_flags),
+            old_path,
+            new_dir,
+            new_path,
+            error,
+        });
+    }
+
+    pub fn record_rename_at(
+        &mut self,
+        dir: Option<u32>,
+        old_path: String,
+        new_dir: Option<u32>,
+        new_path: String,
+        result: &FsResult<()>,
+    ) {
+        let Some((_, error)) = fs_outcome(result.as_ref()) else {
+            return;
+        };
+        self.write_event(TraceEvent::RenameAt {
+            dir,
+            old_path,
+            new_dir,
+            new_path,
+            error,
+        });
+    }
+
+    pub fn record_symlink_at(
+        &mut self,
+        dir: Option<u32>,
+        old_path: String,
+        new_path: String,
+        result: &FsResult<()>,
+    ) {
+        let Some((_, error)) = fs_outcome(result.as_ref()) else {
+            return;
+        };
+        self.write_event(TraceEvent::SymlinkAt {
+            dir,
+            old_path,
+            new_path,
+            error,
+        });
+    }
+
+    pub fn record_unlink_file_at(&mut self, dir: Option<u32>, path: String, result: &FsResult<()>) {
+        let Some((_, error)) = fs_outcome(result.as_ref()) else {
+            return;
+        };
+        self.write_event(TraceEvent::UnlinkFileAt { dir, path, error });
+    }
+
+    pub fn record_remove_directory_at(
+        &mut self,
+        dir: Option<u32>,
+        path: String,
+        result: &FsResult<()>,
+    ) {
+        let Some((_, error)) = fs_outcome(result.as_ref()) else {
+            return;
+        };
+        self.write_event(TraceEvent::RemoveDirectoryAt { dir, path, error });
+    }
+
+    pub fn record_stream_create(&mut self, stream: u32, origin: StreamOrigin) {
+        self.write_event(TraceEvent::StreamCreate { stream, origin });
+    }
+
+    pub fn record_stream_drop(&mut self, stream: u32) {
+        self.write_event(TraceEvent::StreamDrop { stream });
+    }
+
+    pub fn record_stream_read(&mut self, stream: u32, result: &StreamResult<Vec<u8>>) {
+        let event = match result {
+            Ok(bytes) => TraceEvent::StreamRead {
+                stream,
+                bytes: bytes.clone(),
+                eof: false,
+                error: None,
+            },
+            Err(StreamError::Closed) => TraceEvent::StreamRead {
+                stream,
+                bytes: Vec::new(),
+                eof: true,
+                error: None,
+            },
+            Err(StreamError::LastOperationFailed(err)) => TraceEvent::StreamRead {
+                stream,
+                bytes: Vec::new(),
+                eof: false,
+                error: Some(format!("{err:#}")),
+            },
+            // A trap aborts the guest, so there is nothing to replay
+            Err(StreamError::Trap(_)) => return,
+        };
+        self.write_event(event);
+    }
+
+    pub fn record_stream_skip(&mut self, stream: u32, len: u64, result: &StreamResult<u64>) {
+        let (skipped, eof, error) = match result {
+            Ok(skipped) => (*skipped, false, None),
+            Err(StreamError::Closed) => (0, true, None),
+            Err(StreamError::LastOperationFailed(err)) => (0, false, Some(format!("{err:#}"))),
+            Err(StreamError::Trap(_)) => return,
+        };
+        self.write_event(TraceEvent::StreamSkip {
+            stream,
+            len,
+            skipped,
+            eof,
+            error,
+        });
+    }
+
+    pub fn record_stream_write(&mut self, stream: u32, bytes: Vec<u8>, result: &StreamResult<()>) {
+        let (closed, error) = match result {
+            Ok(()) => (false, None),
+            Err(StreamError::Closed) => (true, None),
+            Err(StreamError::LastOperationFailed(err)) => (false, Some(format!("{err:#}"))),
+            Err(StreamError::Trap(_)) => return,
+        };
+        self.write_event(TraceEvent::StreamWrite {
+            stream,
+            bytes,
+            closed,
+            error,
+        });
+    }
+
+    pub fn record_poll(&mut self, pollables: u32, ready: Vec<u32>) {
+        self.write_event(TraceEvent::Poll { pollables, ready });
+    }
+
+    pub fn record_pollable_ready(&mut self, ready: bool) {
+        self.write_event(TraceEvent::PollableReady { ready });
+    }
+
+    /// Record an HTTP request as it is sent, returning its id and where its
+    /// task reports what came of it
+    pub fn record_http_request(
+        &mut self,
+        method: String,
+        url: String,
+        headers: Vec<(String, String)>,
+        config: HttpRequestConfig,
+    ) -> (u32, mpsc::Sender<HttpOutcome>) {
+        let request = self.http_requests;
+        self.http_requests += 1;
+        let mut redacted_headers: Vec<String> = headers
+            .iter()
+            .filter(|(name, _)| self.redactor.redacts_header(name))
+            .map(|(name, _)| name.clone())
+            .collect();
+        redacted_headers.sort();
+        redacted_headers.dedup();
+        let headers = self.redactor.redact_headers(headers);
+        self.write_event(TraceEvent::HttpRequest {
+            request,
+            method,
+            url,
             headers,
-            body,
+            redacted_headers,
+            config: Some(config),
         });
+        (request, self.http_outcomes.clone())
+    }
+
+    /// Write the outcomes of HTTP requests that finished since last time
+    ///
+    /// Requests whose task was dropped before it finished, along with the
+    /// guest's handle, never report back and stay without an outcome.
+    fn record_http_outcomes(&mut self) {
+        while let Ok(outcome) = self.finished_http.try_recv() {
+            let event = match outcome {
+                HttpOutcome::Response {
+                    request,
+                    status,
+                    headers,
+                    between_bytes_timeout_nanoseconds,
+                    frames,
+                    body_error,
+                } => TraceEvent::HttpResponse {
+                    request,
+                    status,
+                    headers: self.redactor.redact_headers(headers),
+                    between_bytes_timeout_nanoseconds: Some(between_bytes_timeout_nanoseconds),
+                    frames: frames
+                        .into_iter()
+                        .map(|frame| match frame {
+                            HttpBodyFrame::Trailers {
+                                elapsed_nanoseconds,
+                                trailers,
+                            } => HttpBodyFrame::Trailers {
+                                elapsed_nanoseconds,
+                                trailers: self.redactor.redact_headers(trailers),
+                            },
+                            data => data,
+                        })
+                        .collect(),
+                    body: None,
+                    body_error,
+                },
+                HttpOutcome::Error { request, error } => TraceEvent::HttpError { request, error },
+                HttpOutcome::RequestBody { request, body } => {
+                    TraceEvent::HttpRequestBody { request, body }
+                }
+            };
+            self.emit(event);
+        }
+    }
+
+    pub fn record_exit(&mut self, code: i32) {
+        self.write_event(TraceEvent::Exit { code });
+    }
+
+    pub fn record_trap(&mut self, message: String, backtrace: Option<String>) {
+        self.write_event(TraceEvent::Trap { message, backtrace });
     }
 
+    /// Finish the trace, which `exit` or `trap` has already ended
+    ///
+    /// HTTP outcomes that came in before that event were written ahead of it,
+    /// and any arriving later are left out so that it stays last.
     pub fn save(mut self) -> Result<()> {
         if let Some(error) = self.error.take() {
             return Err(error);
@@ -209,11 +824,24 @@ impl Recorder {
     }
 }
 
+/// Split a filesystem result into the value and error code stored in traces,
+/// or `None` for traps, which abort the guest and leave nothing to replay
+fn fs_outcome<T>(result: Result<T, &FsError>) -> Option<(Option<T>, Option<String>)> {
+    match result {
+        Ok(value) => Some((Some(value), None)),
+        Err(err) => recorded_error_code(err).map(|code| (None, Some(code.to_string()))),
+    }
+}
+
 pub struct CtxRecorder {
     table: ResourceTable,
     wasi: WasiCtx,
     http: WasiHttpCtx,
     recorder: Recorder,
+    streams: HandleRegistry,
+    descriptors: HandleRegistry,
+    directory_streams: HandleRegistry,
+    snapshot_streams: HashSet<u32>,
 }
 
 impl CtxRecorder {
@@ -223,12 +851,46 @@ impl CtxRecorder {
             wasi,
             http,
             recorder,
+            streams: HandleRegistry::default(),
+            descriptors: HandleRegistry::default(),
+            directory_streams: HandleRegistry::default(),
+            snapshot_streams: HashSet::new(),
         }
     }
 
     pub fn into_recorder(self) -> Recorder {
         self.recorder
     }
+
+    /// Trace id of a stream, registering it on first sight, or `None` for
+    /// streams on snapshotted files, which replay serves itself
+    fn stream_id(&mut self, rep: u32, origin: StreamOrigin) -> Option<u32> {
+        if self.snapshot_streams.contains(&rep) {
+            return None;
+        }
+        if let Some(id) = self.streams.get(rep) {
+            return Some(id);
+        }
+        let id = self.streams.register(rep);
+        self.recorder.record_stream_create(id, origin);
+        Some(id)
+    }
+
+    /// Keep a stream on a file out of the trace when the preopens were snapshotted
+    fn file_stream(&mut self, rep: u32, origin: StreamOrigin) {
+        if self.recorder.has_snapshot() {
+            self.snapshot_streams.insert(rep);
+        } else {
+            self.stream_id(rep, origin);
+        }
+    }
+
+    fn drop_stream(&mut self, rep: u32) {
+        self.snapshot_streams.remove(&rep);
+        if let Some(id) = self.streams.remove(rep) {
+            self.recorder.record_stream_drop(id);
+        }
+    }
 }
 
 impl WasiView for CtxRecorder {
@@ -258,69 +920,225 @@ impl WasiHttpView for CtxRecorder {
         let url = request.uri().to_string();
         let request_headers = sorted_headers(request.headers())?;
 
-        let future = default_send_request(request, config);
+        // The guest may still be writing the body, which only finishes once
+        // it waits on the response, so the exchange runs in its own task
+        let (id, outcome) = self.recorder.record_http_request(
+            method,
+            url,
+            request_headers,
+            http::recorded_request_config(&config),
+        );
+        let handle = runtime::spawn(exchange(request, config, id, outcome));
+        Ok(HostFutureIncomingResponse::pending(handle))
+    }
+}
 
-        let result = match future {
-            HostFutureIncomingResponse::Pending(handle) => runtime::in_tokio(handle),
-            HostFutureIncomingResponse::Ready(res) => res,
-            HostFutureIncomingResponse::Consumed => {
-                return Err(HttpError::trap(anyhow!(
-                    "unexpected consumed HTTP response handle"
-                )))
-            }
-        };
+/// Send request `request` and hand back the response, whose body reports what
+/// to record once it ends; failures are reported right away
+///
+/// The request body streams through as the guest writes it and is reported
+/// whole once the guest finishes it.
+async fn exchange(
+    hyper_request: hyper::Request<wasmtime_wasi_http::body::HyperOutgoingBody>,
+    config: OutgoingRequestConfig,
+    request: u32,
+    outcome: mpsc::Sender<HttpOutcome>,
+) -> Result<Result<IncomingResponse, ErrorCode>> {
+    // The recorder is gone if the run ended without waiting for us, so sends
+    // may fail
+    let failed = |code: ErrorCode| {
+        let _ = outcome.send(HttpOutcome::Error {
+            request,
+            error: http::recorded_error_code(&code),
+        });
+        Ok(Err(code))
+    };
+
+    let hyper_request = hyper_request.map(|body| {
+        RequestBodyTap {
+            inner: body,
+            sent: Vec::new(),
+            report: Some((outcome.clone(), request)),
+        }
+        .boxed_unsync()
+    });
+    let result = match default_send_request(hyper_request, config) {
+        HostFutureIncomingResponse::Pending(handle) => handle.await,
+        HostFutureIncomingResponse::Ready(res) => res,
+        HostFutureIncomingResponse::Consumed => {
+            return Err(anyhow!("unexpected consumed HTTP response handle"))
+        }
+    }?;
+
+    let incoming = match result {
+        Ok(resp) => resp,
+        Err(code) => return failed(code),
+    };
+
+    let (parts, body) = incoming.resp.into_parts();
+    let headers = sorted_headers(&parts.headers).map_err(anyhow::Error::from)?;
+    let body = RecordingBody {
+        inner: body,
+        started: Instant::now(),
+        frames: Vec::new(),
+        report: Some(ResponseReport {
+            outcome,
+            request,
+            status: parts.status.as_u16(),
+            headers,
+            between_bytes_timeout_nanoseconds: http::timeout_nanoseconds(
+                incoming.between_bytes_timeout,
+            ),
+        }),
+    };
+
+    Ok(Ok(IncomingResponse {
+        resp: hyper::Response::from_parts(parts, body.boxed_unsync()),
+        worker: incoming.worker,
+        between_bytes_timeout: incoming.between_bytes_timeout,
+    }))
+}
 
-        let result = result.map_err(HttpError::trap)?;
+/// A request body that passes the guest's frames on to the server and reports
+/// the bytes they carried once the guest finishes it
+///
+/// A body the guest abandons never reaches the server in full, so it is not
+/// reported.
+struct RequestBodyTap {
+    inner: UnsyncBoxBody<Bytes, ErrorCode>,
+    sent: Vec<u8>,
+    /// Taken once the body has been reported
+    report: Option<(mpsc::Sender<HttpOutcome>, u32)>,
+}
 
-        let incoming = match result {
-            Ok(resp) => resp,
-            Err(code) => {
-                return Ok(HostFutureIncomingResponse::ready(Ok(Err(code))));
+impl Body for RequestBodyTap {
+    type Data = Bytes;
+    type Error = ErrorCode;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Frame<Bytes>, ErrorCode>>> {
+        let this = self.get_mut();
+        let polled = Pin::new(&mut this.inner).poll_frame(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    this.sent.extend_from_slice(data);
+                }
             }
-        };
+            Poll::Ready(None) => {
+                if let Some((outcome, request)) = this.report.take() {
+                    let _ = outcome.send(HttpOutcome::RequestBody {
+                        request,
+                        body: std::mem::take(&mut this.sent),
+                    });
+                }
+            }
+            Poll::Ready(Some(Err(_))) | Poll::Pending => {}
+        }
+        polled
+    }
 
-        let between_bytes_timeout = incoming.between_bytes_timeout;
-        let (parts, body) = incoming.resp.into_parts();
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
 
-        let recorded_headers = sorted_headers(&parts.headers)?;
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
 
-        let bytes = runtime::in_tokio(async move { body.collect().await })
-            .map_err(HttpError::trap)?
-            .to_bytes();
+/// What a [`RecordingBody`] reports along with its frames once it is done
+struct ResponseReport {
+    outcome: mpsc::Sender<HttpOutcome>,
+    request: u32,
+    status: u16,
+    headers: Vec<(String, String)>,
+    between_bytes_timeout_nanoseconds: u64,
+}
 
-        let body_vec = bytes.to_vec();
+/// A response body that passes frames on to the guest as they arrive, timing
+/// each one, and reports them once it ends, fails or is dropped
+struct RecordingBody {
+    inner: UnsyncBoxBody<Bytes, ErrorCode>,
+    /// When the response head arrived
+    started: Instant,
+    frames: Vec<HttpBodyFrame>,
+    /// Taken once the outcome has been sent
+    report: Option<ResponseReport>,
+}
 
-        self.recorder.record_http_response(
-            method,
-            url,
-            request_headers,
-            parts.status.as_u16(),
-            recorded_headers,
-            body_vec.clone(),
-        );
+impl RecordingBody {
+    fn finish(&mut self, body_error: Option<HttpErrorCode>) {
+        if let Some(report) = self.report.take() {
+            let _ = report.outcome.send(HttpOutcome::Response {
+                request: report.request,
+                status: report.status,
+                headers: report.headers,
+                between_bytes_timeout_nanoseconds: report.between_bytes_timeout_nanoseconds,
+                frames: std::mem::take(&mut self.frames),
+                body_error,
+            });
+        }
+    }
+}
 
-        // Full<Bytes> is infallible, but we need to convert the error type to match the expected signature.
-        // We use an explicit match on Infallible rather than Into::into because ErrorCode doesn't
-        // implement From<Infallible>.
-        let boxed_body = Full::new(Bytes::from(body_vec))
-            .map_err(|e: std::convert::Infallible| match e {})
-            .boxed_unsync();
-
-        let mut builder = hyper::Response::builder().status(parts.status);
-        *builder
-            .headers_mut()
-            .ok_or_else(|| HttpError::trap(anyhow!("failed to access response headers")))? =
-            parts.headers.clone();
-
-        let resp = builder.body(boxed_body).map_err(HttpError::trap)?;
-
-        let incoming_response = IncomingResponse {
-            resp,
-            worker: None,
-            between_bytes_timeout,
-        };
+impl Body for RecordingBody {
+    type Data = Bytes;
+    type Error = ErrorCode;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Frame<Bytes>, ErrorCode>>> {
+        let this = self.get_mut();
+        let polled = Pin::new(&mut this.inner).poll_frame(cx);
+        let elapsed_nanoseconds =
+            u64::try_from(this.started.elapsed().as_nanos()).unwrap_or(u64::MAX);
+        match &polled {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    this.frames.push(HttpBodyFrame::Data {
+                        elapsed_nanoseconds,
+                        size: data.len() as u64,
+                    });
+                } else if let Some(trailers) = frame.trailers_ref() {
+                    match sorted_headers(trailers) {
+                        Ok(trailers) => this.frames.push(HttpBodyFrame::Trailers {
+                            elapsed_nanoseconds,
+                            trailers,
+                        }),
+                        Err(_) => {
+                            let code = ErrorCode::InternalError(Some(
+                                "response trailers are not valid UTF-8".to_string(),
+                            ));
+                            this.finish(Some(http::recorded_error_code(&code)));
+                            return Poll::Ready(Some(Err(code)));
+                        }
+                    }
+                }
+            }
+            Poll::Ready(Some(Err(code))) => this.finish(Some(http::recorded_error_code(code))),
+            Poll::Ready(None) => this.finish(None),
+            Poll::Pending => {}
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
 
-        Ok(HostFutureIncomingResponse::ready(Ok(Ok(incoming_response))))
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for RecordingBody {
+    /// A guest that stops reading early leaves a trace of what it read
+    fn drop(&mut self) {
+        self.finish(None);
     }
 }
 
@@ -396,6 +1214,30 @@ impl cli::environment::Host for CtxRecorder {
     }
 }
 
+impl cli::stdin::Host for CtxRecorder {
+    fn get_stdin(&mut self) -> anyhow::Result<Resource<streams::InputStream>> {
+        let stream = self.cli().get_stdin()?;
+        self.stream_id(stream.rep(), StreamOrigin::Stdin);
+        Ok(stream)
+    }
+}
+
+impl cli::stdout::Host for CtxRecorder {
+    fn get_stdout(&mut self) -> anyhow::Result<Resource<streams::OutputStream>> {
+        let stream = self.cli().get_stdout()?;
+        self.stream_id(stream.rep(), StreamOrigin::Stdout);
+        Ok(stream)
+    }
+}
+
+impl cli::stderr::Host for CtxRecorder {
+    fn get_stderr(&mut self) -> anyhow::Result<Resource<streams::OutputStream>> {
+        let stream = self.cli().get_stderr()?;
+        self.stream_id(stream.rep(), StreamOrigin::Stderr);
+        Ok(stream)
+    }
+}
+
 impl random::random::Host for CtxRecorder {
     fn get_random_bytes(&mut self, len: u64) -> anyhow::Result<Vec<u8>> {
         let bytes = self.random().get_random_bytes(len)?;
@@ -432,6 +1274,35 @@ impl random::insecure_seed::Host for CtxRecorder {
     }
 }
 
+impl poll::Host for CtxRecorder {
+    fn poll(&mut self, pollables: Vec<Resource<poll::Pollable>>) -> anyhow::Result<Vec<u32>> {
+        let count = u32::try_from(pollables.len())?;
+        let view = WasiView::ctx(self);
+        let ready = <ResourceTable as poll::Host>::poll(view.table, pollables)?;
+        self.recorder.record_poll(count, ready.clone());
+        Ok(ready)
+    }
+}
+
+impl poll::HostPollable for CtxRecorder {
+    fn ready(&mut self, pollable: Resource<poll::Pollable>) -> anyhow::Result<bool> {
+        let view = WasiView::ctx(self);
+        let ready = <ResourceTable as poll::HostPollable>::ready(view.table, pollable)?;
+        self.recorder.record_pollable_ready(ready);
+        Ok(ready)
+    }
+
+    fn block(&mut self, pollable: Resource<poll::Pollable>) -> anyhow::Result<()> {
+        let view = WasiView::ctx(self);
+        <ResourceTable as poll::HostPollable>::block(view.table, pollable)
+    }
+
+    fn drop(&mut self, pollable: Resource<poll::Pollable>) -> anyhow::Result<()> {
+        let view = WasiView::ctx(self);
+        <ResourceTable as poll::HostPollable>::drop(view.table, pollable)
+    }
+}
+
 impl streams::Host for CtxRecorder {
     fn convert_stream_error(&mut self, err: StreamError) -> anyhow::Result<streams::StreamError> {
         let view = WasiView::ctx(self);
@@ -441,14 +1312,19 @@ impl streams::Host for CtxRecorder {
 
 impl streams::HostInputStream for CtxRecorder {
     fn drop(&mut self, stream: Resource<streams::InputStream>) -> anyhow::Result<()> {
+        self.drop_stream(stream.rep());
         let view = WasiView::ctx(self);
         <ResourceTable as streams::HostInputStream>::drop(view.table, stream)
     }
 
     fn read(&mut self, stream: Resource<streams::InputStream>, len: u64) -> StreamResult<Vec<u8>> {
-        self.recorder.record_filesystem_read();
+        let id = self.stream_id(stream.rep(), StreamOrigin::Input);
         let view = WasiView::ctx(self);
-        <ResourceTable as streams::HostInputStream>::read(view.table, stream, len)
+        let result = <ResourceTable as streams::HostInputStream>::read(view.table, stream, len);
+        if let Some(id) = id {
+            self.recorder.record_stream_read(id, &result);
+        }
+        result
     }
 
     fn blocking_read(
@@ -456,14 +1332,24 @@ impl streams::HostInputStream for CtxRecorder {
         stream: Resource<streams::InputStream>,
         len: u64,
     ) -> StreamResult<Vec<u8>> {
-        self.recorder.record_filesystem_read();
+        let id = self.stream_id(stream.rep(), StreamOrigin::Input);
         let view = WasiView::ctx(self);
-        <ResourceTable as streams::HostInputStream>::blocking_read(view.table, stream, len)
+        let result =
+            <ResourceTable as streams::HostInputStream>::blocking_read(view.table, stream, len);
+        if let Some(id) = id {
+            self.recorder.record_stream_read(id, &result);
+        }
+        result
     }
 
     fn skip(&mut self, stream: Resource<streams::InputStream>, len: u64) -> StreamResult<u64> {
+        let id = self.stream_id(stream.rep(), StreamOrigin::Input);
         let view = WasiView::ctx(self);
-        <ResourceTable as streams::HostInputStream>::skip(view.table, stream, len)
+        let result = <ResourceTable as streams::HostInputStream>::skip(view.table, stream, len);
+        if let Some(id) = id {
+            self.recorder.record_stream_skip(id, len, &result);
+        }
+        result
     }
 
     fn blocking_skip(
@@ -471,8 +1357,14 @@ impl streams::HostInputStream for CtxRecorder {
         stream: Resource<streams::InputStream>,
         len: u64,
     ) -> StreamResult<u64> {
+        let id = self.stream_id(stream.rep(), StreamOrigin::Input);
         let view = WasiView::ctx(self);
-        <ResourceTable as streams::HostInputStream>::blocking_skip(view.table, stream, len)
+        let result =
+            <ResourceTable as streams::HostInputStream>::blocking_skip(view.table, stream, len);
+        if let Some(id) = id {
+            self.recorder.record_stream_skip(id, len, &result);
+        }
+        result
     }
 
     fn subscribe(
@@ -486,6 +1378,7 @@ impl streams::HostInputStream for CtxRecorder {
 
 impl streams::HostOutputStream for CtxRecorder {
     fn drop(&mut self, stream: Resource<streams::OutputStream>) -> anyhow::Result<()> {
+        self.drop_stream(stream.rep());
         let view = WasiView::ctx(self);
         <ResourceTable as streams::HostOutputStream>::drop(view.table, stream)
     }
@@ -500,8 +1393,14 @@ impl streams::HostOutputStream for CtxRecorder {
         stream: Resource<streams::OutputStream>,
         bytes: Vec<u8>,
     ) -> StreamResult<()> {
+        let id = self.stream_id(stream.rep(), StreamOrigin::Output);
         let view = WasiView::ctx(self);
-        <ResourceTable as streams::HostOutputStream>::write(view.table, stream, bytes)
+        let result =
+            <ResourceTable as streams::HostOutputStream>::write(view.table, stream, bytes.clone());
+        if let Some(id) = id {
+            self.recorder.record_stream_write(id, bytes, &result);
+        }
+        result
     }
 
     fn blocking_write_and_flush(
@@ -509,10 +1408,17 @@ impl streams::HostOutputStream for CtxRecorder {
         stream: Resource<streams::OutputStream>,
         bytes: Vec<u8>,
     ) -> StreamResult<()> {
+        let id = self.stream_id(stream.rep(), StreamOrigin::Output);
         let view = WasiView::ctx(self);
-        <ResourceTable as streams::HostOutputStream>::blocking_write_and_flush(
-            view.table, stream, bytes,
-        )
+        let result = <ResourceTable as streams::HostOutputStream>::blocking_write_and_flush(
+            view.table,
+            stream,
+            bytes.clone(),
+        );
+        if let Some(id) = id {
+            self.recorder.record_stream_write(id, bytes, &result);
+        }
+        result
     }
 
     fn blocking_write_zeroes_and_flush(
@@ -520,10 +1426,16 @@ impl streams::HostOutputStream for CtxRecorder {
         stream: Resource<streams::OutputStream>,
         len: u64,
     ) -> StreamResult<()> {
-        let view = WasiView::ctx(self);
-        <ResourceTable as streams::HostOutputStream>::blocking_write_zeroes_and_flush(
-            view.table, stream, len,
-        )
+        // Zeroes are written as plain bytes so the trace carries them like any
+        // other write
+        if len > MAX_BLOCKING_WRITE {
+            return Err(StreamError::trap(&format!(
+                "Buffer too large for blocking-write-zeroes-and-flush (expected at most {MAX_BLOCKING_WRITE})"
+            )));
+        }
+        let zeroes =
+            vec![0; usize::try_from(len).map_err(|err| StreamError::trap(&err.to_string()))?];
+        self.blocking_write_and_flush(stream, zeroes)
     }
 
     fn subscribe(
@@ -539,8 +1451,9 @@ impl streams::HostOutputStream for CtxRecorder {
         stream: Resource<streams::OutputStream>,
         len: u64,
     ) -> StreamResult<()> {
-        let view = WasiView::ctx(self);
-        <ResourceTable as streams::HostOutputStream>::write_zeroes(view.table, stream, len)
+        let zeroes =
+            vec![0; usize::try_from(len).map_err(|err| StreamError::trap(&err.to_string()))?];
+        <Self as streams::HostOutputStream>::write(self, stream, zeroes)
     }
 
     fn flush(&mut self, stream: Resource<streams::OutputStream>) -> StreamResult<()> {
@@ -553,14 +1466,22 @@ impl streams::HostOutputStream for CtxRecorder {
         <ResourceTable as streams::HostOutputStream>::blocking_flush(view.table, stream)
     }
 
+    // Splices go through `read` and `write` so the bytes they move show up in
+    // the trace as a read on `src` and a write on `dst`
     fn splice(
         &mut self,
         dst: Resource<streams::OutputStream>,
         src: Resource<streams::InputStream>,
         len: u64,
     ) -> StreamResult<u64> {
-        let view = WasiView::ctx(self);
-        <ResourceTable as streams::HostOutputStream>::splice(view.table, dst, src, len)
+        let permit = self.check_write(Resource::new_borrow(dst.rep()))?;
+        let bytes = <Self as streams::HostInputStream>::read(self, src, len.min(permit))?;
+        if bytes.is_empty() {
+            return Ok(0);
+        }
+        let spliced = bytes.len() as u64;
+        <Self as streams::HostOutputStream>::write(self, dst, bytes)?;
+        Ok(spliced)
     }
 
     fn blocking_splice(
@@ -569,8 +1490,31 @@ impl streams::HostOutputStream for CtxRecorder {
         src: Resource<streams::InputStream>,
         len: u64,
     ) -> StreamResult<u64> {
-        let view = WasiView::ctx(self);
-        <ResourceTable as streams::HostOutputStream>::blocking_splice(view.table, dst, src, len)
+        let bytes = <Self as streams::HostInputStream>::blocking_read(
+            self,
+            src,
+            len.min(MAX_BLOCKING_WRITE),
+        )?;
+        if bytes.is_empty() {
+            return Ok(0);
+        }
+        let spliced = bytes.len() as u64;
+        self.blocking_write_and_flush(dst, bytes)?;
+        Ok(spliced)
+    }
+}
+
+impl filesystem::preopens::Host for CtxRecorder {
+    fn get_directories(
+        &mut self,
+    ) -> anyhow::Result<Vec<(Resource<filesystem::types::Descriptor>, String)>> {
+        let dirs = self.filesystem().get_directories()?;
+        let recorded = dirs
+            .iter()
+            .map(|(fd, path)| (self.descriptors.register(fd.rep()), path.clone()))
+            .collect();
+        self.recorder.record_get_directories(recorded);
+        Ok(dirs)
     }
 }
 
@@ -598,25 +1542,43 @@ impl filesystem::types::HostDescriptor for CtxRecorder {
         len: filesystem::types::Filesize,
         advice: filesystem::types::Advice,
     ) -> FsResult<()> {
-        self.filesystem().advise(fd, offset, len, advice)
+        let descriptor = self.descriptors.get(fd.rep());
+        let result = self.filesystem().advise(fd, offset, len, advice);
+        self.recorder.record_advise(descriptor, &result);
+        result
     }
 
     fn sync_data(&mut self, fd: Resource<filesystem::types::Descriptor>) -> FsResult<()> {
-        self.filesystem().sync_data(fd)
+        let descriptor = self.descriptors.get(fd.rep());
+        let result = self.filesystem().sync_data(fd);
+        self.recorder.record_sync_data(descriptor, &result);
+        result
     }
 
     fn get_flags(
         &mut self,
         fd: Resource<filesystem::types::Descriptor>,
     ) -> FsResult<filesystem::types::DescriptorFlags> {
-        self.filesystem().get_flags(fd)
+        let descriptor = self.descriptors.get(fd.rep());
+        let result = self.filesystem().get_flags(fd);
+        self.recorder.record_get_flags(
+            descriptor,
+            result.as_ref().map(|flags| descriptor_flag_names(*flags)),
+        );
+        result
     }
 
     fn get_type(
         &mut self,
         fd: Resource<filesystem::types::Descriptor>,
     ) -> FsResult<filesystem::types::DescriptorType> {
-        self.filesystem().get_type(fd)
+        let descriptor = self.descriptors.get(fd.rep());
+        let result = self.filesystem().get_type(fd);
+        self.recorder.record_get_type(
+            descriptor,
+            result.as_ref().map(|kind| descriptor_type_name(*kind)),
+        );
+        result
     }
 
     fn set_size(
@@ -624,7 +1586,10 @@ impl filesystem::types::HostDescriptor for CtxRecorder {
         fd: Resource<filesystem::types::Descriptor>,
         size: filesystem::types::Filesize,
     ) -> FsResult<()> {
-        self.filesystem().set_size(fd, size)
+        let descriptor = self.descriptors.get(fd.rep());
+        let result = self.filesystem().set_size(fd, size);
+        self.recorder.record_set_size(descriptor, size, &result);
+        result
     }
 
     fn set_times(
@@ -633,7 +1598,18 @@ impl filesystem::types::HostDescriptor for CtxRecorder {
         atim: filesystem::types::NewTimestamp,
         mtim: filesystem::types::NewTimestamp,
     ) -> FsResult<()> {
-        self.filesystem().set_times(fd, atim, mtim)
+        let rep = fd.rep();
+        let descriptor = self.descriptors.get(rep);
+        let result = self.filesystem().set_times(fd, atim, mtim);
+        // Read back the time any `now` update took, for replay to apply
+        let now = result.as_ref().map(|()| {
+            self.filesystem()
+                .stat(Resource::new_borrow(rep))
+                .ok()
+                .and_then(|stat| applied_now(atim, mtim, &stat))
+        });
+        self.recorder.record_set_times(descriptor, atim, mtim, now);
+        result
     }
 
     fn read(
@@ -642,8 +1618,10 @@ impl filesystem::types::HostDescriptor for CtxRecorder {
         len: filesystem::types::Filesize,
         offset: filesystem::types::Filesize,
     ) -> FsResult<(Vec<u8>, bool)> {
-        self.recorder.record_filesystem_read();
-        self.filesystem().read(fd, len, offset)
+        let descriptor = self.descriptors.get(fd.rep());
+        let result = self.filesystem().read(fd, len, offset);
+        self.recorder.record_file_read(descriptor, offset, &result);
+        result
     }
 
     fn write(
@@ -652,18 +1630,31 @@ impl filesystem::types::HostDescriptor for CtxRecorder {
         buf: Vec<u8>,
         offset: filesystem::types::Filesize,
     ) -> FsResult<filesystem::types::Filesize> {
-        self.filesystem().write(fd, buf, offset)
+        let descriptor = self.descriptors.get(fd.rep());
+        let result = self.filesystem().write(fd, buf.clone(), offset);
+        self.recorder
+            .record_file_write(descriptor, offset, buf, result.as_ref().copied());
+        result
     }
 
     fn read_directory(
         &mut self,
         fd: Resource<filesystem::types::Descriptor>,
     ) -> FsResult<Resource<filesystem::types::DirectoryEntryStream>> {
-        self.filesystem().read_directory(fd)
+        let dir = self.descriptors.get(fd.rep());
+        let result = self.filesystem().read_directory(fd);
+        let id = result
+            .as_ref()
+            .map(|stream| self.directory_streams.register(stream.rep()));
+        self.recorder.record_read_directory(dir, id);
+        result
     }
 
     fn sync(&mut self, fd: Resource<filesystem::types::Descriptor>) -> FsResult<()> {
-        self.filesystem().sync(fd)
+        let descriptor = self.descriptors.get(fd.rep());
+        let result = self.filesystem().sync(fd);
+        self.recorder.record_sync(descriptor, &result);
+        result
     }
 
     fn create_directory_at(
@@ -671,14 +1662,21 @@ impl filesystem::types::HostDescriptor for CtxRecorder {
         fd: Resource<filesystem::types::Descriptor>,
         path: String,
     ) -> FsResult<()> {
-        self.filesystem().create_directory_at(fd, path)
+        let dir = self.descriptors.get(fd.rep());
+        let result = self.filesystem().create_directory_at(fd, path.clone());
+        self.recorder.record_create_directory_at(dir, path, &result);
+        result
     }
 
     fn stat(
         &mut self,
         fd: Resource<filesystem::types::Descriptor>,
     ) -> FsResult<filesystem::types::DescriptorStat> {
-        self.filesystem().stat(fd)
+        let descriptor = self.descriptors.get(fd.rep());
+        let result = self.filesystem().stat(fd);
+        self.recorder
+            .record_stat(descriptor, result.as_ref().map(recorded_stat));
+        result
     }
 
     fn stat_at(
@@ -687,7 +1685,11 @@ impl filesystem::types::HostDescriptor for CtxRecorder {
         path_flags: filesystem::types::PathFlags,
         path: String,
     ) -> FsResult<filesystem::types::DescriptorStat> {
-        self.filesystem().stat_at(fd, path_flags, path)
+        let dir = self.descriptors.get(fd.rep());
+        let result = self.filesystem().stat_at(fd, path_flags, path.clone());
+        self.recorder
+            .record_stat_at(dir, path, path_flags, result.as_ref().map(recorded_stat));
+        result
     }
 
     fn set_times_at(
@@ -698,8 +1700,21 @@ impl filesystem::types::HostDescriptor for CtxRecorder {
         atim: filesystem::types::NewTimestamp,
         mtim: filesystem::types::NewTimestamp,
     ) -> FsResult<()> {
-        self.filesystem()
-            .set_times_at(fd, path_flags, path, atim, mtim)
+        let rep = fd.rep();
+        let dir = self.descriptors.get(rep);
+        let result = self
+            .filesystem()
+            .set_times_at(fd, path_flags, path.clone(), atim, mtim);
+        // Read back the time any `now` update took, for replay to apply
+        let now = result.as_ref().map(|()| {
+            self.filesystem()
+                .stat_at(Resource::new_borrow(rep), path_flags, path.clone())
+                .ok()
+                .and_then(|stat| applied_now(atim, mtim, &stat))
+        });
+        self.recorder
+            .record_set_times_at(dir, path, path_flags, atim, mtim, now);
+        result
     }
 
     fn link_at(
@@ -710,8 +1725,14 @@ impl filesystem::types::HostDescriptor for CtxRecorder {
         new_fd: Resource<filesystem::types::Descriptor>,
         new_path: String,
     ) -> FsResult<()> {
-        self.filesystem()
-            .link_at(fd, path_flags, old_path, new_fd, new_path)
+        let dir = self.descriptors.get(fd.rep());
+        let new_dir = self.descriptors.get(new_fd.rep());
+        let result =
+            self.filesystem()
+                .link_at(fd, path_flags, old_path.clone(), new_fd, new_path.clone());
+        self.recorder
+            .record_link_at(dir, path_flags, old_path, new_dir, new_path, &result);
+        result
     }
 
     fn open_at(
@@ -722,11 +1743,22 @@ impl filesystem::types::HostDescriptor for CtxRecorder {
         open_flags: filesystem::types::OpenFlags,
         descriptor_flags: filesystem::types::DescriptorFlags,
     ) -> FsResult<Resource<filesystem::types::Descriptor>> {
-        self.filesystem()
-            .open_at(fd, path_flags, path, open_flags, descriptor_flags)
+        let dir = self.descriptors.get(fd.rep());
+        let result =
+            self.filesystem()
+                .open_at(fd, path_flags, path.clone(), open_flags, descriptor_flags);
+        let id = result
+            .as_ref()
+            .map(|descriptor| self.descriptors.register(descriptor.rep()));
+        self.recorder
+            .record_open_at(dir, path, path_flags, open_flags, descriptor_flags, id);
+        result
     }
 
     fn drop(&mut self, fd: Resource<filesystem::types::Descriptor>) -> anyhow::Result<()> {
+        if let Some(id) = self.descriptors.remove(fd.rep()) {
+            self.recorder.record_descriptor_drop(id);
+        }
         let mut fs = self.filesystem();
         filesystem::types::HostDescriptor::drop(&mut fs, fd)
     }
@@ -736,7 +1768,11 @@ impl filesystem::types::HostDescriptor for CtxRecorder {
         fd: Resource<filesystem::types::Descriptor>,
         path: String,
     ) -> FsResult<String> {
-        self.filesystem().readlink_at(fd, path)
+        let dir = self.descriptors.get(fd.rep());
+        let result = self.filesystem().readlink_at(fd, path.clone());
+        self.recorder
+            .record_readlink_at(dir, path, result.as_ref().cloned());
+        result
     }
 
     fn remove_directory_at(
@@ -744,7 +1780,10 @@ impl filesystem::types::HostDescriptor for CtxRecorder {
         fd: Resource<filesystem::types::Descriptor>,
         path: String,
     ) -> FsResult<()> {
-        self.filesystem().remove_directory_at(fd, path)
+        let dir = self.descriptors.get(fd.rep());
+        let result = self.filesystem().remove_directory_at(fd, path.clone());
+        self.recorder.record_remove_directory_at(dir, path, &result);
+        result
     }
 
     fn rename_at(
@@ -754,7 +1793,14 @@ impl filesystem::types::HostDescriptor for CtxRecorder {
         new_fd: Resource<filesystem::types::Descriptor>,
         new_path: String,
     ) -> FsResult<()> {
-        self.filesystem().rename_at(fd, old_path, new_fd, new_path)
+        let dir = self.descriptors.get(fd.rep());
+        let new_dir = self.descriptors.get(new_fd.rep());
+        let result = self
+            .filesystem()
+            .rename_at(fd, old_path.clone(), new_fd, new_path.clone());
+        self.recorder
+            .record_rename_at(dir, old_path, new_dir, new_path, &result);
+        result
     }
 
     fn symlink_at(
@@ -763,7 +1809,13 @@ impl filesystem::types::HostDescriptor for CtxRecorder {
         old_path: String,
         new_path: String,
     ) -> FsResult<()> {
-        self.filesystem().symlink_at(fd, old_path, new_path)
+        let dir = self.descriptors.get(fd.rep());
+        let result = self
+            .filesystem()
+            .symlink_at(fd, old_path.clone(), new_path.clone());
+        self.recorder
+            .record_symlink_at(dir, old_path, new_path, &result);
+        result
     }
 
     fn unlink_file_at(
@@ -771,7 +1823,10 @@ impl filesystem::types::HostDescriptor for CtxRecorder {
         fd: Resource<filesystem::types::Descriptor>,
         path: String,
     ) -> FsResult<()> {
-        self.filesystem().unlink_file_at(fd, path)
+        let dir = self.descriptors.get(fd.rep());
+        let result = self.filesystem().unlink_file_at(fd, path.clone());
+        self.recorder.record_unlink_file_at(dir, path, &result);
+        result
     }
 
     fn read_via_stream(
@@ -779,7 +1834,16 @@ impl filesystem::types::HostDescriptor for CtxRecorder {
         fd: Resource<filesystem::types::Descriptor>,
         offset: filesystem::types::Filesize,
     ) -> FsResult<Resource<streams::InputStream>> {
-        self.filesystem().read_via_stream(fd, offset)
+        let descriptor = self.descriptors.get(fd.rep());
+        let stream = self
+            .filesystem()
+            .read_via_stream(fd, offset)
+            .inspect_err(|err| {
+                self.recorder
+                    .record_file_stream_error(descriptor, StreamOrigin::ReadViaStream, err)
+            })?;
+        self.file_stream(stream.rep(), StreamOrigin::ReadViaStream);
+        Ok(stream)
     }
 
     fn write_via_stream(
@@ -787,14 +1851,32 @@ impl filesystem::types::HostDescriptor for CtxRecorder {
         fd: Resource<filesystem::types::Descriptor>,
         offset: filesystem::types::Filesize,
     ) -> FsResult<Resource<streams::OutputStream>> {
-        self.filesystem().write_via_stream(fd, offset)
+        let descriptor = self.descriptors.get(fd.rep());
+        let stream = self
+            .filesystem()
+            .write_via_stream(fd, offset)
+            .inspect_err(|err| {
+                self.recorder.record_file_stream_error(
+                    descriptor,
+                    StreamOrigin::WriteViaStream,
+                    err,
+                )
+            })?;
+        self.file_stream(stream.rep(), StreamOrigin::WriteViaStream);
+        Ok(stream)
     }
 
     fn append_via_stream(
         &mut self,
         fd: Resource<filesystem::types::Descriptor>,
     ) -> FsResult<Resource<streams::OutputStream>> {
-        self.filesystem().append_via_stream(fd)
+        let descriptor = self.descriptors.get(fd.rep());
+        let stream = self.filesystem().append_via_stream(fd).inspect_err(|err| {
+            self.recorder
+                .record_file_stream_error(descriptor, StreamOrigin::AppendViaStream, err)
+        })?;
+        self.file_stream(stream.rep(), StreamOrigin::AppendViaStream);
+        Ok(stream)
     }
 
     fn is_same_object(
@@ -802,14 +1884,21 @@ impl filesystem::types::HostDescriptor for CtxRecorder {
         a: Resource<filesystem::types::Descriptor>,
         b: Resource<filesystem::types::Descriptor>,
     ) -> anyhow::Result<bool> {
-        self.filesystem().is_same_object(a, b)
+        let ids = (self.descriptors.get(a.rep()), self.descriptors.get(b.rep()));
+        let same = self.filesystem().is_same_object(a, b)?;
+        self.recorder.record_is_same_object(ids.0, ids.1, same);
+        Ok(same)
     }
 
     fn metadata_hash(
         &mut self,
         fd: Resource<filesystem::types::Descriptor>,
     ) -> FsResult<filesystem::types::MetadataHashValue> {
-        self.filesystem().metadata_hash(fd)
+        let descriptor = self.descriptors.get(fd.rep());
+        let result = self.filesystem().metadata_hash(fd);
+        self.recorder
+            .record_metadata_hash(descriptor, result.as_ref().map(recorded_hash));
+        result
     }
 
     fn metadata_hash_at(
@@ -818,7 +1907,17 @@ impl filesystem::types::HostDescriptor for CtxRecorder {
         path_flags: filesystem::types::PathFlags,
         path: String,
     ) -> FsResult<filesystem::types::MetadataHashValue> {
-        self.filesystem().metadata_hash_at(fd, path_flags, path)
+        let dir = self.descriptors.get(fd.rep());
+        let result = self
+            .filesystem()
+            .metadata_hash_at(fd, path_flags, path.clone());
+        self.recorder.record_metadata_hash_at(
+            dir,
+            path,
+            path_flags,
+            result.as_ref().map(recorded_hash),
+        );
+        result
     }
 }
 
@@ -827,13 +1926,24 @@ impl filesystem::types::HostDirectoryEntryStream for CtxRecorder {
         &mut self,
         stream: Resource<filesystem::types::DirectoryEntryStream>,
     ) -> FsResult<Option<filesystem::types::DirectoryEntry>> {
-        self.filesystem().read_directory_entry(stream)
+        let id = self.directory_streams.get(stream.rep());
+        let result = self.filesystem().read_directory_entry(stream);
+        self.recorder.record_read_directory_entry(
+            id,
+            result
+                .as_ref()
+                .map(|entry| entry.as_ref().map(recorded_entry)),
+        );
+        result
     }
 
     fn drop(
         &mut self,
         stream: Resource<filesystem::types::DirectoryEntryStream>,
     ) -> anyhow::Result<()> {
+        if let Some(id) = self.directory_streams.remove(stream.rep()) {
+            self.recorder.record_directory_stream_drop(id);
+        }
         let mut fs = self.filesystem();
         filesystem::types::HostDirectoryEntryStream::drop(&mut fs, stream)
     }
@@ -0,0 +1,174 @@
+mod store;
+mod tree;
+
+use std::fs::{FileType, Metadata};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Context, Result};
+use wasmtime_wasi::p2::bindings::sync::filesystem::types::DescriptorType;
+
+use crate::engine::DirMapping;
+use crate::trace::{FileHash, FileStat, FileTimestamp, SnapshotDir, SnapshotEntry};
+use crate::wasi::filesystem::descriptor_type_name;
+
+pub use store::BlobStore;
+pub use tree::{Root, Tree};
+
+/// Where the blob store for a trace lives by default: next to it, named after it
+pub fn store_path(trace: &Path) -> PathBuf {
+    let mut name = trace.file_name().unwrap_or_default().to_os_string();
+    name.push(".blobs");
+    trace.with_file_name(name)
+}
+
+/// Copy every file below the preopened directories into `store` and describe
+/// the directory trees so replay can rebuild them
+pub fn capture(dirs: &[DirMapping], store: &BlobStore) -> Result<Vec<SnapshotDir>> {
+    dirs.iter()
+        .map(|dir| {
+            let metadata = std::fs::metadata(&dir.host)
+                .with_context(|| format!("failed to snapshot {}", dir.host.display()))?;
+            let mut entries = Vec::new();
+            capture_entry(&dir.host, String::new(), &metadata, store, &mut entries)?;
+            Ok(SnapshotDir {
+                path: dir.guest.clone(),
+                writable: dir.writable,
+                entries,
+            })
+        })
+        .collect()
+}
+
+fn capture_entry(
+    host: &Path,
+    path: String,
+    metadata: &Metadata,
+    store: &BlobStore,
+    entries: &mut Vec<SnapshotEntry>,
+) -> Result<()> {
+    let file_type = metadata.file_type();
+    let blob = if file_type.is_file() {
+        Some(store.put_file(host)?)
+    } else {
+        None
+    };
+    let target = if file_type.is_symlink() {
+        let target = std::fs::read_link(host)
+            .with_context(|| format!("failed to read link {}", host.display()))?;
+        Some(utf8(target.into_os_string(), host)?)
+    } else {
+        None
+    };
+    entries.push(SnapshotEntry {
+        path: path.clone(),
+        stat: snapshot_stat(metadata),
+        blob,
+        target,
+        hash: metadata_hash(metadata),
+    });
+
+    if file_type.is_dir() {
+        let children = std::fs::read_dir(host)
+            .and_then(|dir| dir.collect::<std::io::Result<Vec<_>>>())
+            .with_context(|| format!("failed to list {}", host.display()))?;
+        for child in children {
+            let child_host = child.path();
+            let name = utf8(child.file_name(), &child_host)?;
+            let child_path = if path.is_empty() {
+                name
+            } else {
+                format!("{path}/{name}")
+            };
+            let metadata = std::fs::symlink_metadata(&child_host)
+                .with_context(|| format!("failed to snapshot {}", child_host.display()))?;
+            capture_entry(&child_host, child_path, &metadata, store, entries)?;
+        }
+    }
+    Ok(())
+}
+
+fn utf8(name: std::ffi::OsString, host: &Path) -> Result<String> {
+    name.into_string()
+        .map_err(|_| anyhow!("cannot snapshot {}: path is not UTF-8", host.display()))
+}
+
+fn snapshot_stat(metadata: &Metadata) -> FileStat {
+    FileStat {
+        kind: descriptor_type_name(descriptor_type(&metadata.file_type())),
+        link_count: link_count(metadata),
+        size: metadata.len(),
+        data_access_timestamp: metadata.accessed().ok().and_then(timestamp),
+        data_modification_timestamp: metadata.modified().ok().and_then(timestamp),
+        // wasmtime-wasi reports the creation time as the status change time
+        status_change_timestamp: metadata.created().ok().and_then(timestamp),
+    }
+}
+
+fn timestamp(time: SystemTime) -> Option<FileTimestamp> {
+    let since_epoch = time.duration_since(UNIX_EPOCH).ok()?;
+    Some(FileTimestamp {
+        seconds: since_epoch.as_secs(),
+        nanoseconds: since_epoch.subsec_nanos(),
+    })
+}
+
+/// The hash wasmtime-wasi gives a file for `metadata_hash`, which it derives
+/// from the device and inode numbers
+#[cfg(unix)]
+fn metadata_hash(metadata: &Metadata) -> Option<FileHash> {
+    use std::hash::Hasher;
+    use std::os::unix::fs::MetadataExt;
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    hasher.write_u64(metadata.dev());
+    hasher.write_u64(metadata.ino());
+    let lower = hasher.finish();
+    Some(FileHash {
+        lower,
+        upper: lower ^ 4614256656552045848,
+    })
+}
+
+#[cfg(not(unix))]
+fn metadata_hash(_metadata: &Metadata) -> Option<FileHash> {
+    None
+}
+
+#[cfg(unix)]
+fn link_count(metadata: &Metadata) -> u64 {
+    std::os::unix::fs::MetadataExt::nlink(metadata)
+}
+
+#[cfg(not(unix))]
+fn link_count(_metadata: &Metadata) -> u64 {
+    1
+}
+
+fn descriptor_type(file_type: &FileType) -> DescriptorType {
+    if file_type.is_dir() {
+        return DescriptorType::Directory;
+    }
+    if file_type.is_file() {
+        return DescriptorType::RegularFile;
+    }
+    if file_type.is_symlink() {
+        return DescriptorType::SymbolicLink;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_fifo() {
+            return DescriptorType::Fifo;
+        }
+        if file_type.is_socket() {
+            return DescriptorType::Socket;
+        }
+        if file_type.is_block_device() {
+            return DescriptorType::BlockDevice;
+        }
+        if file_type.is_char_device() {
+            return DescriptorType::CharacterDevice;
+        }
+    }
+    DescriptorType::Unknown
+}
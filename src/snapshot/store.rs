@@ -0,0 +1,86 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+
+/// A directory of file contents, each stored under the SHA-256 of its bytes
+///
+/// Identical files are only stored once, no matter how often they appear in a
+/// snapshot or how many recordings share the store.
+#[derive(Debug, Clone)]
+pub struct BlobStore {
+    root: PathBuf,
+}
+
+impl BlobStore {
+    /// Open the store at `root`, creating the directory if it is missing
+    pub fn create(root: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&root)
+            .with_context(|| format!("failed to create blob store at {}", root.display()))?;
+        Ok(Self { root })
+    }
+
+    /// Open an existing store for reading
+    pub fn open(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// Copy a file into the store, returning its digest
+    pub fn put_file(&self, path: &Path) -> Result<String> {
+        let mut source =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let staging = self.root.join(format!(".incoming-{}", std::process::id()));
+        let mut writer = HashingWriter {
+            inner: File::create(&staging)
+                .with_context(|| format!("failed to create {}", staging.display()))?,
+            hasher: Sha256::new(),
+        };
+        io::copy(&mut source, &mut writer)
+            .with_context(|| format!("failed to copy {} into the blob store", path.display()))?;
+        writer.inner.sync_all()?;
+
+        let digest = hex::encode(writer.hasher.finalize());
+        let blob = self.root.join(&digest);
+        if blob.exists() {
+            std::fs::remove_file(&staging)?;
+        } else {
+            std::fs::rename(&staging, &blob)
+                .with_context(|| format!("failed to store blob {}", blob.display()))?;
+        }
+        Ok(digest)
+    }
+
+    /// Read the blob with the given digest, checking that it is intact
+    pub fn get(&self, digest: &str) -> Result<Vec<u8>> {
+        let blob = self.root.join(digest);
+        let bytes = std::fs::read(&blob)
+            .with_context(|| format!("failed to read blob {}", blob.display()))?;
+        if hex::encode(Sha256::digest(&bytes)) != digest {
+            return Err(anyhow!(
+                "blob {} does not match its digest; the store is corrupt",
+                blob.display()
+            ));
+        }
+        Ok(bytes)
+    }
+}
+
+/// Passes bytes through to a file while hashing them
+struct HashingWriter {
+    inner: File,
+    hasher: Sha256,
+}
+
+impl Write for HashingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(buf.get(..written).unwrap_or_default());
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,772 @@
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::{anyhow, Result};
+use wasmtime_wasi::p2::bindings::clocks::wall_clock::Datetime;
+use wasmtime_wasi::p2::bindings::filesystem::types::ErrorCode;
+use wasmtime_wasi::p2::bindings::sync::filesystem::types::{
+    DescriptorStat, DescriptorType, DirectoryEntry, MetadataHashValue,
+};
+use wasmtime_wasi::p2::{FsError, FsResult};
+
+use super::BlobStore;
+use crate::trace::{SnapshotDir, SnapshotEntry};
+use crate::wasi::filesystem::{replayed_hash, replayed_stat};
+
+/// Longest chain of symbolic links followed while resolving one path
+const MAX_SYMLINKS: usize = 40;
+
+/// The entries of a directory, kept in the order the host listed them
+#[derive(Default)]
+struct Directory {
+    names: Vec<String>,
+    nodes: HashMap<String, usize>,
+}
+
+impl Directory {
+    fn get(&self, name: &str) -> Option<usize> {
+        self.nodes.get(name).copied()
+    }
+
+    /// Add an entry, or point an existing one at a new node in place
+    fn insert(&mut self, name: String, node: usize) {
+        if self.nodes.insert(name.clone(), node).is_none() {
+            self.names.push(name);
+        }
+    }
+
+    fn remove(&mut self, name: &str) {
+        if self.nodes.remove(name).is_some() {
+            self.names.retain(|known| known != name);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (&String, usize)> {
+        self.names
+            .iter()
+            .filter_map(|name| self.get(name).map(|node| (name, node)))
+    }
+}
+
+enum Contents {
+    Directory(Directory),
+    /// A regular file whose bytes are still in the blob store
+    Blob(String),
+    File(Vec<u8>),
+    Symlink(String),
+    /// A fifo, socket or device, which snapshots carry no data for
+    Other,
+}
+
+struct Node {
+    stat: DescriptorStat,
+    contents: Contents,
+    /// The host's `metadata_hash`, for entries that were snapshotted with one
+    hash: Option<MetadataHashValue>,
+}
+
+/// A preopened directory mounted in a [`Tree`]
+pub struct Root {
+    pub path: String,
+    pub node: usize,
+    pub writable: bool,
+}
+
+/// An in-memory filesystem rebuilt from a snapshot
+///
+/// Nodes are addressed by index and never freed, so descriptors on unlinked
+/// files keep working the way they do on a real filesystem. File contents are
+/// loaded from the blob store the first time they are needed.
+pub struct Tree {
+    store: BlobStore,
+    nodes: Vec<Node>,
+}
+
+impl Tree {
+    /// Build the tree for the snapshotted directories, returning their roots
+    pub fn mount(store: BlobStore, dirs: Vec<SnapshotDir>) -> Result<(Self, Vec<Root>)> {
+        let mut tree = Self {
+            store,
+            nodes: Vec::new(),
+        };
+        let roots = dirs
+            .into_iter()
+            .map(|dir| {
+                let node = tree.mount_dir(&dir.path, dir.entries)?;
+                Ok(Root {
+                    path: dir.path,
+                    node,
+                    writable: dir.writable,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok((tree, roots))
+    }
+
+    fn mount_dir(&mut self, guest: &str, entries: Vec<SnapshotEntry>) -> Result<usize> {
+        let mut paths: HashMap<String, usize> = HashMap::new();
+        for entry in entries {
+            let stat = replayed_stat(entry.stat)?;
+            let contents = match stat.type_ {
+                DescriptorType::Directory => Contents::Directory(Directory::default()),
+                DescriptorType::RegularFile => Contents::Blob(entry.blob.ok_or_else(|| {
+                    anyhow!("snapshot of {guest:?} has no blob for {:?}", entry.path)
+                })?),
+                DescriptorType::SymbolicLink => {
+                    Contents::Symlink(entry.target.ok_or_else(|| {
+                        anyhow!("snapshot of {guest:?} has no target for {:?}", entry.path)
+                    })?)
+                }
+                _ => Contents::Other,
+            };
+            let node = self.push(stat, contents, entry.hash.map(replayed_hash));
+            if !entry.path.is_empty() {
+                let (parent, name) = entry.path.rsplit_once('/').unwrap_or(("", &entry.path));
+                let parent = paths.get(parent).copied().ok_or_else(|| {
+                    anyhow!(
+                        "snapshot of {guest:?} lists {:?} before its directory",
+                        entry.path
+                    )
+                })?;
+                self.children_mut(parent)?.insert(name.to_string(), node);
+            }
+            paths.insert(entry.path, node);
+        }
+        paths
+            .get("")
+            .copied()
+            .ok_or_else(|| anyhow!("snapshot of {guest:?} does not contain the directory itself"))
+    }
+
+    fn push(
+        &mut self,
+        stat: DescriptorStat,
+        contents: Contents,
+        hash: Option<MetadataHashValue>,
+    ) -> usize {
+        self.nodes.push(Node {
+            stat,
+            contents,
+            hash,
+        });
+        self.nodes.len() - 1
+    }
+
+    fn node(&self, node: usize) -> FsResult<&Node> {
+        self.nodes
+            .get(node)
+            .ok_or_else(|| ErrorCode::BadDescriptor.into())
+    }
+
+    fn node_mut(&mut self, node: usize) -> FsResult<&mut Node> {
+        self.nodes
+            .get_mut(node)
+            .ok_or_else(|| ErrorCode::BadDescriptor.into())
+    }
+
+    pub fn stat(&self, node: usize) -> FsResult<DescriptorStat> {
+        Ok(self.node(node)?.stat)
+    }
+
+    pub fn kind(&self, node: usize) -> FsResult<DescriptorType> {
+        Ok(self.node(node)?.stat.type_)
+    }
+
+    /// The host's hash of a snapshotted entry; entries made during the run
+    /// have none
+    pub fn hash(&self, node: usize) -> FsResult<Option<MetadataHashValue>> {
+        Ok(self.node(node)?.hash)
+    }
+
+    fn children(&self, dir: usize) -> FsResult<&Directory> {
+        match &self.node(dir)?.contents {
+            Contents::Directory(children) => Ok(children),
+            _ => Err(ErrorCode::NotDirectory.into()),
+        }
+    }
+
+    fn children_mut(&mut self, dir: usize) -> FsResult<&mut Directory> {
+        match &mut self.node_mut(dir)?.contents {
+            Contents::Directory(children) => Ok(children),
+            _ => Err(ErrorCode::NotDirectory.into()),
+        }
+    }
+
+    /// The entries of a directory, in listing order
+    pub fn entries(&self, dir: usize) -> FsResult<Vec<DirectoryEntry>> {
+        self.children(dir)?
+            .iter()
+            .map(|(name, node)| {
+                Ok(DirectoryEntry {
+                    type_: self.kind(node)?,
+                    name: name.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// The node named `name` in directory `dir`, if there is one
+    pub fn child(&self, dir: usize, name: &str) -> FsResult<Option<usize>> {
+        Ok(self.children(dir)?.get(name))
+    }
+
+    /// Resolve `path` relative to directory `start`, following symbolic links
+    /// in every component but the last unless `follow` is set or the path
+    /// ends in a slash
+    ///
+    /// Like the host implementation, paths may not be absolute or climb out
+    /// of `start`, even through a symbolic link.
+    pub fn lookup(&self, start: usize, path: &str, follow: bool) -> FsResult<usize> {
+        // A trailing slash or `.` names what a final link points at
+        let follow = follow || path.ends_with('/') || path.ends_with("/.");
+        let mut ancestors = vec![start];
+        let mut pending = components(path)?;
+        let mut links = 0;
+        while let Some(name) = pending.pop_front() {
+            let current = ancestors.last().copied().unwrap_or(start);
+            if name == ".." {
+                if ancestors.len() == 1 {
+                    return Err(ErrorCode::NotPermitted.into());
+                }
+                ancestors.pop();
+                continue;
+            }
+            let node = self.child(current, &name)?.ok_or(ErrorCode::NoEntry)?;
+            if let Contents::Symlink(target) = &self.node(node)?.contents {
+                if follow || !pending.is_empty() {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        return Err(ErrorCode::Loop.into());
+                    }
+                    let mut target = components(target)?;
+                    target.extend(pending);
+                    pending = target;
+                    continue;
+                }
+            }
+            ancestors.push(node);
+        }
+        Ok(ancestors.last().copied().unwrap_or(start))
+    }
+
+    /// Split `path` into the directory it names an entry of and the entry's name
+    pub fn parent(&self, start: usize, path: &str) -> FsResult<(usize, String)> {
+        let path = path.trim_end_matches('/');
+        let (dir, name) = path.rsplit_once('/').unwrap_or((".", path));
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(ErrorCode::Invalid.into());
+        }
+        let dir = self.lookup(start, dir, true)?;
+        self.children(dir)?;
+        Ok((dir, name.to_string()))
+    }
+
+    pub fn readlink(&self, node: usize) -> FsResult<String> {
+        match &self.node(node)?.contents {
+            Contents::Symlink(target) => Ok(target.clone()),
+            _ => Err(ErrorCode::Invalid.into()),
+        }
+    }
+
+    /// The bytes of a regular file, fetched from the blob store if needed
+    fn data(&mut self, node: usize) -> FsResult<&mut Vec<u8>> {
+        let store = self.store.clone();
+        let node = self.node_mut(node)?;
+        if let Contents::Blob(digest) = &node.contents {
+            node.contents = Contents::File(store.get(digest).map_err(FsError::trap)?);
+        }
+        match &mut node.contents {
+            Contents::File(bytes) => Ok(bytes),
+            Contents::Directory(_) => Err(ErrorCode::IsDirectory.into()),
+            _ => Err(ErrorCode::BadDescriptor.into()),
+        }
+    }
+
+    pub fn read(&mut self, node: usize, len: u64, offset: u64) -> FsResult<(Vec<u8>, bool)> {
+        let data = self.data(node)?;
+        let start = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(data.len());
+        let end = start
+            .saturating_add(usize::try_from(len).unwrap_or(usize::MAX))
+            .min(data.len());
+        let bytes = data.get(start..end).unwrap_or_default().to_vec();
+        Ok((bytes, end == data.len()))
+    }
+
+    /// Write `bytes` at `offset`, or at the end of the file when there is no
+    /// offset, returning the position just past them
+    pub fn write(&mut self, node: usize, offset: Option<u64>, bytes: &[u8]) -> FsResult<u64> {
+        let data = self.data(node)?;
+        let start = match offset {
+            Some(offset) => usize::try_from(offset).map_err(|_| ErrorCode::FileTooLarge)?,
+            None => data.len(),
+        };
+        let end = start
+            .checked_add(bytes.len())
+            .ok_or(ErrorCode::FileTooLarge)?;
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        if let Some(target) = data.get_mut(start..end) {
+            target.copy_from_slice(bytes);
+        }
+        self.update_size(node)?;
+        Ok(end as u64)
+    }
+
+    pub fn set_size(&mut self, node: usize, size: u64) -> FsResult<()> {
+        let size = usize::try_from(size).map_err(|_| ErrorCode::FileTooLarge)?;
+        self.data(node)?.resize(size, 0);
+        self.update_size(node)
+    }
+
+    fn update_size(&mut self, node: usize) -> FsResult<()> {
+        let size = self.data(node)?.len() as u64;
+        self.node_mut(node)?.stat.size = size;
+        Ok(())
+    }
+
+    /// Replace the timestamps that are given, keeping the others
+    pub fn set_times(
+        &mut self,
+        node: usize,
+        access: Option<Datetime>,
+        modification: Option<Datetime>,
+    ) -> FsResult<()> {
+        let stat = &mut self.node_mut(node)?.stat;
+        if access.is_some() {
+            stat.data_access_timestamp = access;
+        }
+        if modification.is_some() {
+            stat.data_modification_timestamp = modification;
+        }
+        Ok(())
+    }
+
+    fn insert(
+        &mut self,
+        dir: usize,
+        name: String,
+        kind: DescriptorType,
+        contents: Contents,
+    ) -> FsResult<usize> {
+        if self.child(dir, &name)?.is_some() {
+            return Err(ErrorCode::Exist.into());
+        }
+        let node = self.push(
+            DescriptorStat {
+                type_: kind,
+                link_count: 1,
+                size: 0,
+                data_access_timestamp: None,
+                data_modification_timestamp: None,
+                status_change_timestamp: None,
+            },
+            contents,
+            None,
+        );
+        self.children_mut(dir)?.insert(name, node);
+        Ok(node)
+    }
+
+    pub fn create_file(&mut self, dir: usize, name: String) -> FsResult<usize> {
+        self.insert(
+            dir,
+            name,
+            DescriptorType::RegularFile,
+            Contents::File(Vec::new()),
+        )
+    }
+
+    pub fn create_directory(&mut self, dir: usize, name: String) -> FsResult<()> {
+        let contents = Contents::Directory(Directory::default());
+        self.insert(dir, name, DescriptorType::Directory, contents)?;
+        Ok(())
+    }
+
+    pub fn symlink(&mut self, dir: usize, name: String, target: String) -> FsResult<()> {
+        let size = target.len() as u64;
+        let node = self.insert(
+            dir,
+            name,
+            DescriptorType::SymbolicLink,
+            Contents::Symlink(target),
+        )?;
+        self.node_mut(node)?.stat.size = size;
+        Ok(())
+    }
+
+    /// Give `node` another name; directories cannot be hard linked
+    pub fn link(&mut self, node: usize, dir: usize, name: String) -> FsResult<()> {
+        if self.kind(node)? == DescriptorType::Directory {
+            return Err(ErrorCode::NotPermitted.into());
+        }
+        if self.child(dir, &name)?.is_some() {
+            return Err(ErrorCode::Exist.into());
+        }
+        self.children_mut(dir)?.insert(name, node);
+        self.node_mut(node)?.stat.link_count += 1;
+        Ok(())
+    }
+
+    /// Remove an entry, which must be an empty directory when `directory` is
+    /// set and anything else otherwise
+    pub fn remove(&mut self, dir: usize, name: &str, directory: bool) -> FsResult<()> {
+        let node = self.child(dir, name)?.ok_or(ErrorCode::NoEntry)?;
+        match (&self.node(node)?.contents, directory) {
+            (Contents::Directory(children), true) if !children.is_empty() => {
+                return Err(ErrorCode::NotEmpty.into())
+            }
+            (Contents::Directory(_), false) => return Err(ErrorCode::IsDirectory.into()),
+            (Contents::Directory(_), true) => {}
+            (_, true) => return Err(ErrorCode::NotDirectory.into()),
+            (_, false) => {}
+        }
+        self.children_mut(dir)?.remove(name);
+        let stat = &mut self.node_mut(node)?.stat;
+        stat.link_count = stat.link_count.saturating_sub(1);
+        Ok(())
+    }
+
+    /// Move an entry, replacing whatever the new name pointed at the way
+    /// `rename(2)` does
+    pub fn rename(
+        &mut self,
+        dir: usize,
+        name: &str,
+        new_dir: usize,
+        new_name: String,
+    ) -> FsResult<()> {
+        let node = self.child(dir, name)?.ok_or(ErrorCode::NoEntry)?;
+        let is_dir = self.kind(node)? == DescriptorType::Directory;
+        if is_dir && self.contains(node, new_dir)? {
+            return Err(ErrorCode::Invalid.into());
+        }
+        let replaced = self.child(new_dir, &new_name)?;
+        if let Some(existing) = replaced {
+            if existing == node {
+                return Ok(());
+            }
+            match (&self.node(existing)?.contents, is_dir) {
+                (Contents::Directory(children), true) if !children.is_empty() => {
+                    return Err(ErrorCode::NotEmpty.into())
+                }
+                (Contents::Directory(_), false) => return Err(ErrorCode::IsDirectory.into()),
+                (Contents::Directory(_), true) => {}
+                (_, true) => return Err(ErrorCode::NotDirectory.into()),
+                (_, false) => {}
+            }
+        }
+        self.children_mut(dir)?.remove(name);
+        self.children_mut(new_dir)?.insert(new_name, node);
+        if let Some(replaced) = replaced {
+            let stat = &mut self.node_mut(replaced)?.stat;
+            stat.link_count = stat.link_count.saturating_sub(1);
+        }
+        Ok(())
+    }
+
+    /// Whether `node` is `ancestor` or somewhere below it
+    fn contains(&self, ancestor: usize, node: usize) -> FsResult<bool> {
+        if ancestor == node {
+            return Ok(true);
+        }
+        match &self.node(ancestor)?.contents {
+            Contents::Directory(children) => {
+                for (_, child) in children.iter() {
+                    if self.contains(child, node)? {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+/// The components of a relative path, without empty and `.` components
+fn components(path: &str) -> FsResult<VecDeque<String>> {
+    if path.starts_with('/') {
+        return Err(ErrorCode::NotPermitted.into());
+    }
+    Ok(path
+        .split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+        .map(str::to_string)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::FileStat;
+
+    fn entry(path: &str, kind: &str, size: u64) -> SnapshotEntry {
+        SnapshotEntry {
+            path: path.to_string(),
+            stat: FileStat {
+                kind: kind.to_string(),
+                link_count: 1,
+                size,
+                data_access_timestamp: None,
+                data_modification_timestamp: None,
+                status_change_timestamp: None,
+            },
+            blob: None,
+            target: None,
+            hash: None,
+        }
+    }
+
+    /// A tree holding `hello.txt`, whose contents are in a blob store, and an
+    /// empty directory `sub`, mounted at the returned root
+    fn tree(store: &tempfile::TempDir) -> Result<(Tree, usize)> {
+        let host = store.path().join("hello.txt");
+        std::fs::write(&host, b"hello")?;
+        let store = BlobStore::create(store.path().join("blobs"))?;
+        let hello = SnapshotEntry {
+            blob: Some(store.put_file(&host)?),
+            ..entry("hello.txt", "regular-file", 5)
+        };
+        let dir = SnapshotDir {
+            path: "/data".to_string(),
+            writable: true,
+            entries: vec![
+                entry("", "directory", 0),
+                hello,
+                entry("sub", "directory", 0),
+            ],
+        };
+        let (tree, roots) = Tree::mount(store, vec![dir])?;
+        let root = roots.first().map(|root| root.node);
+        Ok((tree, root.ok_or_else(|| anyhow!("no root was mounted"))?))
+    }
+
+    fn error<T>(result: FsResult<T>) -> Option<ErrorCode> {
+        result.err().and_then(|err| err.downcast_ref().copied())
+    }
+
+    #[test]
+    fn lookup_follows_symlinks() -> Result<()> {
+        let store = tempfile::tempdir()?;
+        let (mut tree, root) = tree(&store)?;
+        let hello = tree.lookup(root, "hello.txt", false)?;
+        tree.symlink(root, "dir".to_string(), "sub".to_string())?;
+        tree.symlink(root, "link".to_string(), "hello.txt".to_string())?;
+        let sub = tree.lookup(root, "sub", false)?;
+        tree.symlink(sub, "up".to_string(), "../hello.txt".to_string())?;
+
+        assert_eq!(tree.lookup(root, "link", true)?, hello);
+        let link = tree.lookup(root, "link", false)?;
+        assert_eq!(tree.kind(link)?, DescriptorType::SymbolicLink);
+        // Links in the middle of a path are followed either way
+        assert_eq!(tree.lookup(root, "dir/up", true)?, hello);
+        assert_eq!(tree.lookup(root, "dir/", false)?, sub);
+        assert_eq!(tree.lookup(root, "dir/.", false)?, sub);
+        assert_eq!(
+            error(tree.lookup(root, "dir/missing", true)),
+            Some(ErrorCode::NoEntry)
+        );
+        assert_eq!(
+            error(tree.lookup(root, "hello.txt/x", true)),
+            Some(ErrorCode::NotDirectory)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn lookup_stays_inside_the_start_directory() -> Result<()> {
+        let store = tempfile::tempdir()?;
+        let (mut tree, root) = tree(&store)?;
+        let hello = tree.lookup(root, "hello.txt", false)?;
+        let sub = tree.lookup(root, "sub", false)?;
+        tree.symlink(sub, "escape".to_string(), "../../x".to_string())?;
+        tree.symlink(sub, "absolute".to_string(), "/etc".to_string())?;
+
+        assert_eq!(tree.lookup(root, "sub/../hello.txt", false)?, hello);
+        assert_eq!(tree.lookup(root, "sub/..", false)?, root);
+        assert_eq!(
+            error(tree.lookup(sub, "..", false)),
+            Some(ErrorCode::NotPermitted)
+        );
+        for path in ["..", "sub/../..", "/hello.txt"] {
+            assert_eq!(
+                error(tree.lookup(root, path, false)),
+                Some(ErrorCode::NotPermitted)
+            );
+        }
+        for link in ["sub/escape", "sub/absolute"] {
+            assert_eq!(
+                error(tree.lookup(root, link, true)),
+                Some(ErrorCode::NotPermitted)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn lookup_gives_up_on_symlink_loops() -> Result<()> {
+        let store = tempfile::tempdir()?;
+        let (mut tree, root) = tree(&store)?;
+        let hello = tree.lookup(root, "hello.txt", false)?;
+        tree.symlink(root, "self".to_string(), "self".to_string())?;
+        assert_eq!(
+            error(tree.lookup(root, "self", true)),
+            Some(ErrorCode::Loop)
+        );
+        assert_eq!(
+            error(tree.lookup(root, "self/x", false)),
+            Some(ErrorCode::Loop)
+        );
+
+        // A chain exactly MAX_SYMLINKS long still resolves
+        tree.symlink(root, "chain0".to_string(), "hello.txt".to_string())?;
+        for link in 1..=MAX_SYMLINKS {
+            tree.symlink(root, format!("chain{link}"), format!("chain{}", link - 1))?;
+        }
+        let longest = format!("chain{}", MAX_SYMLINKS - 1);
+        assert_eq!(tree.lookup(root, &longest, true)?, hello);
+        let too_long = format!("chain{MAX_SYMLINKS}");
+        assert_eq!(
+            error(tree.lookup(root, &too_long, true)),
+            Some(ErrorCode::Loop)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rename_replaces_files() -> Result<()> {
+        let store = tempfile::tempdir()?;
+        let (mut tree, root) = tree(&store)?;
+        let hello = tree.lookup(root, "hello.txt", false)?;
+        let other = tree.create_file(root, "other.txt".to_string())?;
+
+        tree.rename(root, "hello.txt", root, "other.txt".to_string())?;
+        assert_eq!(tree.child(root, "hello.txt")?, None);
+        assert_eq!(tree.child(root, "other.txt")?, Some(hello));
+        assert_eq!(tree.stat(hello)?.link_count, 1);
+        assert_eq!(tree.stat(other)?.link_count, 0);
+
+        // Renaming onto another name of the same file changes nothing
+        tree.link(hello, root, "alias".to_string())?;
+        tree.rename(root, "alias", root, "other.txt".to_string())?;
+        assert_eq!(tree.child(root, "alias")?, Some(hello));
+        assert_eq!(tree.stat(hello)?.link_count, 2);
+
+        let sub = tree.lookup(root, "sub", false)?;
+        tree.rename(root, "other.txt", sub, "moved.txt".to_string())?;
+        assert_eq!(tree.lookup(root, "sub/moved.txt", false)?, hello);
+        assert_eq!(
+            error(tree.rename(root, "other.txt", sub, "x".to_string())),
+            Some(ErrorCode::NoEntry)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rename_replaces_directories() -> Result<()> {
+        let store = tempfile::tempdir()?;
+        let (mut tree, root) = tree(&store)?;
+        let sub = tree.lookup(root, "sub", false)?;
+        tree.create_directory(root, "empty".to_string())?;
+        tree.create_directory(root, "full".to_string())?;
+        let full = tree.lookup(root, "full", false)?;
+        tree.create_file(full, "f".to_string())?;
+
+        assert_eq!(
+            error(tree.rename(root, "sub", root, "hello.txt".to_string())),
+            Some(ErrorCode::NotDirectory)
+        );
+        assert_eq!(
+            error(tree.rename(root, "hello.txt", root, "sub".to_string())),
+            Some(ErrorCode::IsDirectory)
+        );
+        assert_eq!(
+            error(tree.rename(root, "sub", root, "full".to_string())),
+            Some(ErrorCode::NotEmpty)
+        );
+        // A directory cannot move below itself
+        assert_eq!(
+            error(tree.rename(root, "sub", sub, "inner".to_string())),
+            Some(ErrorCode::Invalid)
+        );
+
+        tree.rename(root, "sub", root, "empty".to_string())?;
+        assert_eq!(tree.child(root, "sub")?, None);
+        assert_eq!(tree.child(root, "empty")?, Some(sub));
+        tree.rename(root, "empty", full, "nested".to_string())?;
+        assert_eq!(tree.lookup(root, "full/nested", false)?, sub);
+        Ok(())
+    }
+
+    #[test]
+    fn link_and_remove_count_names() -> Result<()> {
+        let store = tempfile::tempdir()?;
+        let (mut tree, root) = tree(&store)?;
+        let hello = tree.lookup(root, "hello.txt", false)?;
+        let sub = tree.lookup(root, "sub", false)?;
+
+        tree.link(hello, sub, "again.txt".to_string())?;
+        assert_eq!(tree.stat(hello)?.link_count, 2);
+        assert_eq!(tree.lookup(root, "sub/again.txt", false)?, hello);
+        assert_eq!(
+            error(tree.link(hello, root, "sub".to_string())),
+            Some(ErrorCode::Exist)
+        );
+        assert_eq!(
+            error(tree.link(sub, root, "dir".to_string())),
+            Some(ErrorCode::NotPermitted)
+        );
+
+        assert_eq!(
+            error(tree.remove(root, "hello.txt", true)),
+            Some(ErrorCode::NotDirectory)
+        );
+        assert_eq!(
+            error(tree.remove(root, "sub", false)),
+            Some(ErrorCode::IsDirectory)
+        );
+        assert_eq!(
+            error(tree.remove(root, "sub", true)),
+            Some(ErrorCode::NotEmpty)
+        );
+
+        tree.remove(root, "hello.txt", false)?;
+        assert_eq!(tree.stat(hello)?.link_count, 1);
+        assert_eq!(tree.read(hello, 5, 0)?, (b"hello".to_vec(), true));
+        tree.remove(sub, "again.txt", false)?;
+        assert_eq!(tree.stat(hello)?.link_count, 0);
+        // The node outlives its names, like an open file on the host
+        assert_eq!(tree.read(hello, 5, 0)?, (b"hello".to_vec(), true));
+        tree.remove(root, "sub", true)?;
+        assert_eq!(
+            error(tree.remove(root, "sub", true)),
+            Some(ErrorCode::NoEntry)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn set_size_truncates_and_extends() -> Result<()> {
+        let store = tempfile::tempdir()?;
+        let (mut tree, root) = tree(&store)?;
+        let hello = tree.lookup(root, "hello.txt", false)?;
+
+        tree.set_size(hello, 2)?;
+        assert_eq!(tree.stat(hello)?.size, 2);
+        assert_eq!(tree.read(hello, 10, 0)?, (b"he".to_vec(), true));
+        tree.set_size(hello, 4)?;
+        assert_eq!(tree.stat(hello)?.size, 4);
+        assert_eq!(tree.read(hello, 3, 1)?, (b"e\0\0".to_vec(), true));
+        assert_eq!(tree.read(hello, 1, 0)?, (b"h".to_vec(), false));
+
+        let sub = tree.lookup(root, "sub", false)?;
+        assert_eq!(error(tree.set_size(sub, 0)), Some(ErrorCode::IsDirectory));
+        Ok(())
+    }
+}
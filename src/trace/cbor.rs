@@ -0,0 +1,119 @@
+use anyhow::{anyhow, Result};
+use ciborium::Value;
+use std::collections::VecDeque;
+use std::io::BufRead;
+
+use super::event::TraceEvent;
+use super::header::TraceHeader;
+use super::migrate::Upgrader;
+use crate::util::cbor::is_cbor_eof;
+
+/// What reading the next event of a CBOR trace produced
+pub enum CborItem {
+    Event(Box<TraceEvent>),
+    /// The trace ended cleanly between events
+    End,
+    /// The trace ends partway through an event, so the recording was cut off
+    Truncated,
+}
+
+/// Reads the events of a CBOR trace, which are concatenated after an
+/// optional header
+pub struct CborEvents<R> {
+    reader: R,
+    upgrader: Upgrader,
+    /// The first item, read while looking for a header, when it was not one
+    first: Option<Option<Option<Value>>>,
+    /// Decoded events not handed out yet, as an older event may upgrade into
+    /// several
+    upgraded: VecDeque<TraceEvent>,
+}
+
+impl<R: BufRead> CborEvents<R> {
+    /// Start reading a trace, taking the header off the front if it has one;
+    /// traces from before headers existed start straight with events
+    ///
+    /// Older traces are upgraded to the current schema as they are read.
+    pub fn new(mut reader: R) -> Result<(TraceHeader, Self)> {
+        let (header, first) = match read_value(&mut reader)? {
+            Some(Some(value)) if is_header(&value) => (Some(to_json(value)?), None),
+            first => (None, Some(first)),
+        };
+        let (header, upgrader) = Upgrader::new(header)?;
+        Ok((
+            header,
+            Self {
+                reader,
+                upgrader,
+                first,
+                upgraded: VecDeque::new(),
+            },
+        ))
+    }
+
+    /// Schema the trace was written under, before upgrading
+    pub fn schema(&self) -> u32 {
+        self.upgrader.schema()
+    }
+
+    pub fn next_item(&mut self) -> Result<CborItem> {
+        loop {
+            if let Some(event) = self.upgraded.pop_front() {
+                return Ok(CborItem::Event(Box::new(event)));
+            }
+            let value = match self.first.take() {
+                Some(value) => value,
+                None => read_value(&mut self.reader)?,
+            };
+            match value {
+                Some(Some(value)) => self.decode_event(value)?,
+                Some(None) => return Ok(CborItem::Truncated),
+                None => return Ok(CborItem::End),
+            }
+        }
+    }
+
+    /// Queue up the events `value` decodes to
+    fn decode_event(&mut self, value: Value) -> Result<()> {
+        if self.upgrader.is_current() {
+            let event = value
+                .deserialized()
+                .map_err(|e| anyhow!("invalid trace event: {e}"))?;
+            self.upgraded.push_back(event);
+            return Ok(());
+        }
+        let events = self.upgrader.event(to_json(value)?)?;
+        self.upgraded.extend(events);
+        Ok(())
+    }
+}
+
+/// Read one CBOR item: `None` at a clean end, `Some(None)` if the file
+/// stops partway through it
+fn read_value(reader: &mut impl BufRead) -> Result<Option<Option<Value>>> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    match ciborium::from_reader::<Value, _>(reader) {
+        Ok(value) => Ok(Some(Some(value))),
+        Err(e) if is_cbor_eof(&e) => Ok(Some(None)),
+        Err(e) => Err(anyhow::Error::msg(format!("{}", e))),
+    }
+}
+
+/// Events are tagged with `call`, while the header is the only item with a
+/// `schema` field
+fn is_header(value: &Value) -> bool {
+    value.as_map().is_some_and(|entries| {
+        entries
+            .iter()
+            .any(|(key, _)| key.as_text() == Some("schema"))
+    })
+}
+
+/// Migrations work on JSON values, which every trace item maps onto
+fn to_json(value: Value) -> Result<serde_json::Value> {
+    value
+        .deserialized()
+        .map_err(|e| anyhow!("invalid trace item: {e}"))
+}
@@ -18,6 +18,247 @@ mod hex_serde {
         let s = String::deserialize(deserializer)?;
         hex::decode(&s).map_err(serde::de::Error::custom)
     }
+
+    /// For bytes only older traces recorded
+    pub mod option {
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            match bytes {
+                Some(bytes) => super::serialize(bytes, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            Option::<String>::deserialize(deserializer)?
+                .map(|s| hex::decode(&s).map_err(serde::de::Error::custom))
+                .transpose()
+        }
+    }
+}
+
+/// Where a stream resource handed to the guest came from
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamOrigin {
+    Stdin,
+    Stdout,
+    Stderr,
+    ReadViaStream,
+    WriteViaStream,
+    AppendViaStream,
+    /// An input stream produced by an interface we do not intercept, such as an HTTP body
+    Input,
+    /// An output stream produced by an interface we do not intercept
+    Output,
+}
+
+/// A filesystem timestamp
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileTimestamp {
+    pub seconds: u64,
+    pub nanoseconds: u32,
+}
+
+/// How `set_times` was asked to change one timestamp
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampUpdate {
+    NoChange,
+    Now,
+    Set(FileTimestamp),
+}
+
+/// Metadata of a file or directory as returned by `stat`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileStat {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub link_count: u64,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_access_timestamp: Option<FileTimestamp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_modification_timestamp: Option<FileTimestamp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_change_timestamp: Option<FileTimestamp>,
+}
+
+/// The 128-bit value returned by `metadata_hash`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileHash {
+    pub lower: u64,
+    pub upper: u64,
+}
+
+/// An entry returned while listing a directory
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub name: String,
+}
+
+/// A file, directory or symbolic link captured in a filesystem snapshot
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotEntry {
+    /// Path relative to the preopened directory, empty for the directory itself
+    pub path: String,
+    pub stat: FileStat,
+    /// SHA-256 of a regular file's contents, naming its blob in the store
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// What `metadata_hash` gave the entry on the host; missing from older
+    /// snapshots and on hosts without inode numbers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<FileHash>,
+}
+
+/// The contents of one preopened directory at the start of recording
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotDir {
+    pub path: String,
+    pub writable: bool,
+    /// Every entry below the directory, parents before their children
+    pub entries: Vec<SnapshotEntry>,
+}
+
+/// A `wasi:http` error code, tagged with its WIT name
+///
+/// Mirrors the WIT `error-code` variant, whose bindings cannot be serialized.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "code", rename_all = "kebab-case")]
+pub enum HttpErrorCode {
+    DnsTimeout,
+    DnsError {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        rcode: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        info_code: Option<u16>,
+    },
+    DestinationNotFound,
+    DestinationUnavailable,
+    DestinationIpProhibited,
+    DestinationIpUnroutable,
+    ConnectionRefused,
+    ConnectionTerminated,
+    ConnectionTimeout,
+    ConnectionReadTimeout,
+    ConnectionWriteTimeout,
+    ConnectionLimitReached,
+    TlsProtocolError,
+    TlsCertificateError,
+    TlsAlertReceived {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        alert_id: Option<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        alert_message: Option<String>,
+    },
+    HttpRequestDenied,
+    HttpRequestLengthRequired,
+    HttpRequestBodySize {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        size: Option<u64>,
+    },
+    HttpRequestMethodInvalid,
+    HttpRequestUriInvalid,
+    HttpRequestUriTooLong,
+    HttpRequestHeaderSectionSize {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        size: Option<u32>,
+    },
+    HttpRequestHeaderSize {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        field: Option<HttpFieldSize>,
+    },
+    HttpRequestTrailerSectionSize {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        size: Option<u32>,
+    },
+    HttpRequestTrailerSize {
+        field: HttpFieldSize,
+    },
+    HttpResponseIncomplete,
+    HttpResponseHeaderSectionSize {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        size: Option<u32>,
+    },
+    HttpResponseHeaderSize {
+        field: HttpFieldSize,
+    },
+    HttpResponseBodySize {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        size: Option<u64>,
+    },
+    HttpResponseTrailerSectionSize {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        size: Option<u32>,
+    },
+    HttpResponseTrailerSize {
+        field: HttpFieldSize,
+    },
+    HttpResponseTransferCoding {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        coding: Option<String>,
+    },
+    HttpResponseContentCoding {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        coding: Option<String>,
+    },
+    HttpResponseTimeout,
+    HttpUpgradeFailed,
+    HttpProtocolError,
+    LoopDetected,
+    ConfigurationError,
+    InternalError {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+}
+
+/// The field named by an HTTP size error
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HttpFieldSize {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub field_size: Option<u32>,
+}
+
+/// The transport settings the guest gave an outgoing HTTP request
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequestConfig {
+    pub use_tls: bool,
+    pub connect_timeout_nanoseconds: u64,
+    pub first_byte_timeout_nanoseconds: u64,
+    pub between_bytes_timeout_nanoseconds: u64,
+}
+
+/// One frame of an HTTP response body
+///
+/// `elapsed_nanoseconds` counts from when the response head arrived, and
+/// replay holds each frame back until then. Data frames only keep their size:
+/// the bytes are in the `stream_read` events of the guest's body stream.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "frame", rename_all = "snake_case")]
+pub enum HttpBodyFrame {
+    Data {
+        elapsed_nanoseconds: u64,
+        size: u64,
+    },
+    Trailers {
+        elapsed_nanoseconds: u64,
+        trailers: Vec<(String, String)>,
+    },
 }
 
 /// A single trace event recorded during execution
@@ -64,14 +305,359 @@ pub enum TraceEvent {
     InsecureSeed {
         seed: (u64, u64),
     },
+    /// Preopened directories were snapshotted into the blob store `store`,
+    /// named relative to the trace
+    Snapshot {
+        store: String,
+        dirs: Vec<SnapshotDir>,
+        /// Whether filesystem calls are still traced after the snapshot, for
+        /// replay to check it against; older traces leave them out
+        #[serde(default)]
+        calls_traced: bool,
+    },
+    GetDirectories {
+        dirs: Vec<(u32, String)>,
+    },
     Read,
-    HttpResponse {
-        request_method: String,
-        request_url: String,
-        request_headers: Vec<(String, String)>,
-        status: u16,
+    FileRead {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        descriptor: Option<u32>,
+        offset: u64,
+        #[serde(with = "hex_serde")]
+        bytes: Vec<u8>,
+        eof: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    OpenAt {
+        /// Trace id of the directory the path is resolved against, when it was
+        /// itself opened by the guest
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dir: Option<u32>,
+        path: String,
+        path_flags: Vec<String>,
+        open_flags: Vec<String>,
+        descriptor_flags: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        descriptor: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    DescriptorDrop {
+        descriptor: u32,
+    },
+    ReadDirectory {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dir: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stream: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// One `read_directory_entry` call; neither `entry` nor `error` marks the end
+    ReadDirectoryEntry {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stream: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        entry: Option<DirEntry>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    DirectoryStreamDrop {
+        stream: u32,
+    },
+    Stat {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        descriptor: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stat: Option<FileStat>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    StatAt {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dir: Option<u32>,
+        path: String,
+        path_flags: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stat: Option<FileStat>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    MetadataHash {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        descriptor: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hash: Option<FileHash>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    MetadataHashAt {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dir: Option<u32>,
+        path: String,
+        path_flags: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hash: Option<FileHash>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    GetType {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        descriptor: Option<u32>,
+        #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+        kind: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    GetFlags {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        descriptor: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        flags: Option<Vec<String>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    ReadlinkAt {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dir: Option<u32>,
+        path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        target: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    IsSameObject {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        a: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        b: Option<u32>,
+        same: bool,
+    },
+    Advise {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        descriptor: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Sync {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        descriptor: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    SyncData {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        descriptor: Option<u32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// A `read_via_stream`, `write_via_stream` or `append_via_stream` call that
+    /// failed; successful ones show up as `stream_create`
+    FileStreamError {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        descriptor: Option<u32>,
+        origin: StreamOrigin,
+        error: String,
+    },
+    FileWrite {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        descriptor: Option<u32>,
+        offset: u64,
+        #[serde(with = "hex_serde")]
+        bytes: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        written: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    SetSize {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        descriptor: Option<u32>,
+        size: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    SetTimes {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        descriptor: Option<u32>,
+        access: TimestampUpdate,
+        modification: TimestampUpdate,
+        /// The time a `now` update set, read back from the file, which replay
+        /// applies in its place
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        now: Option<FileTimestamp>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    SetTimesAt {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dir: Option<u32>,
+        path: String,
+        path_flags: Vec<String>,
+        access: TimestampUpdate,
+        modification: TimestampUpdate,
+        /// The time a `now` update set, read back from the file, which replay
+        /// applies in its place
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        now: Option<FileTimestamp>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    CreateDirectoryAt {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dir: Option<u32>,
+        path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    LinkAt {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dir: Option<u32>,
+        path_flags: Vec<String>,
+        old_path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        new_dir: Option<u32>,
+        new_path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    RenameAt {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dir: Option<u32>,
+        old_path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        new_dir: Option<u32>,
+        new_path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// Creates `new_path` as a symbolic link pointing at `old_path`
+    SymlinkAt {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dir: Option<u32>,
+        old_path: String,
+        new_path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    UnlinkFileAt {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dir: Option<u32>,
+        path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    RemoveDirectoryAt {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dir: Option<u32>,
+        path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    StreamCreate {
+        stream: u32,
+        origin: StreamOrigin,
+    },
+    StreamDrop {
+        stream: u32,
+    },
+    StreamRead {
+        stream: u32,
+        #[serde(with = "hex_serde")]
+        bytes: Vec<u8>,
+        eof: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    StreamSkip {
+        stream: u32,
+        len: u64,
+        skipped: u64,
+        eof: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    StreamWrite {
+        stream: u32,
+        #[serde(with = "hex_serde")]
+        bytes: Vec<u8>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        closed: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Poll {
+        pollables: u32,
+        ready: Vec<u32>,
+    },
+    PollableReady {
+        ready: bool,
+    },
+    /// An outgoing HTTP request as the guest sent it; what came of it is
+    /// recorded later, once it is known, as an `http_response` or
+    /// `http_error` with the same `request` id
+    ///
+    /// The body follows as an `http_request_body` once the guest finishes it.
+    HttpRequest {
+        request: u32,
+        method: String,
+        url: String,
         headers: Vec<(String, String)>,
+        /// Headers whose values were redacted, which replay accepts any value
+        /// for
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        redacted_headers: Vec<String>,
+        /// Missing from traces recorded before request settings were captured
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        config: Option<HttpRequestConfig>,
+    },
+    /// Everything the guest sent as the body of request `request`, recorded
+    /// once it finished the body so replay can check it request by request
+    HttpRequestBody {
+        request: u32,
         #[serde(with = "hex_serde")]
         body: Vec<u8>,
     },
+    HttpResponse {
+        request: u32,
+        status: u16,
+        headers: Vec<(String, String)>,
+        /// How long the guest's reads may wait between body frames before
+        /// they fail
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        between_bytes_timeout_nanoseconds: Option<u64>,
+        /// The body as it arrived, one entry per frame
+        frames: Vec<HttpBodyFrame>,
+        /// The whole body, kept only by traces from before reads of the
+        /// guest's body stream were recorded
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none",
+            with = "hex_serde::option"
+        )]
+        body: Option<Vec<u8>>,
+        /// Why the body stopped partway, if reading it failed
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        body_error: Option<HttpErrorCode>,
+    },
+    /// A request that failed before any response arrived, such as on a DNS
+    /// failure or a refused connection
+    HttpError {
+        request: u32,
+        error: HttpErrorCode,
+    },
+    /// How the guest finished; always the last event of a complete trace
+    Exit {
+        code: i32,
+    },
+    /// The trap that aborted the guest, ending the trace instead of `exit`
+    Trap {
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        backtrace: Option<String>,
+    },
+    /// Added by `repair` where a recording that was cut off ends; replay
+    /// cannot continue past it
+    Incomplete,
 }
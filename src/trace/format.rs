@@ -1,8 +1,9 @@
 use anyhow::{bail, Result};
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use std::path::Path;
 
 use super::event::TraceEvent;
+use super::header::TraceHeader;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TraceFormat {
@@ -31,7 +32,8 @@ impl TraceFormat {
 }
 
 /// A trace file containing multiple events
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct TraceFile {
+    pub header: TraceHeader,
     pub events: Vec<TraceEvent>,
 }
@@ -0,0 +1,68 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version of the trace schema this build writes
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Describes the recording a trace came from
+///
+/// JSON traces carry it as a `header` field next to `events`; CBOR traces
+/// start with it before the first event. Only `schema` is always present:
+/// traces migrated from before headers existed cannot know the rest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TraceHeader {
+    pub schema: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wasm_rr_version: Option<String>,
+    /// SHA-256 of the component's bytes, hex encoded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub component_sha256: Option<String>,
+    /// Arguments the component was started with, including its own name
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub argv: Vec<String>,
+    /// Host wall-clock time when recording started
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_seconds: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_nanoseconds: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<HostInfo>,
+}
+
+/// The machine a trace was recorded on
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HostInfo {
+    pub os: String,
+    pub arch: String,
+}
+
+impl TraceHeader {
+    /// Describe a recording of `component` starting now
+    pub fn new(component: &Path, argv: Vec<String>) -> Result<Self> {
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok(Self {
+            schema: SCHEMA_VERSION,
+            wasm_rr_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            component_sha256: Some(component_digest(component)?),
+            argv,
+            start_seconds: Some(start.as_secs()),
+            start_nanoseconds: Some(start.subsec_nanos()),
+            host: Some(HostInfo {
+                os: std::env::consts::OS.to_string(),
+                arch: std::env::consts::ARCH.to_string(),
+            }),
+        })
+    }
+}
+
+/// SHA-256 of a component file, hex encoded
+pub fn component_digest(component: &Path) -> Result<String> {
+    let bytes = std::fs::read(component)
+        .with_context(|| format!("failed to read component {}", component.display()))?;
+    Ok(hex::encode(Sha256::digest(&bytes)))
+}
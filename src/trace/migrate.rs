@@ -0,0 +1,276 @@
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+use std::path::Path;
+
+use super::event::TraceEvent;
+use super::format::{TraceFile, TraceFormat};
+use super::header::{TraceHeader, SCHEMA_VERSION};
+use super::repair::{recover, Recovered};
+
+/// How the trace schema changed from one version to the next
+///
+/// `MIGRATIONS[n]` upgrades a trace written under schema `n` to schema
+/// `n + 1`, so the list grows by one entry every time `SCHEMA_VERSION` is
+/// bumped. Steps work on raw JSON values because the old shapes no longer
+/// have types to deserialize into.
+struct Migration {
+    header: fn(&mut Value) -> Result<()>,
+    /// Rewrite one event, which may become several
+    event: fn(Value, &mut NewIds) -> Result<Vec<Value>>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    // 0 → 1: traces gained a header; events are unchanged
+    Migration {
+        header: unchanged,
+        event: unchanged_event,
+    },
+    // 1 → 2: HTTP requests got their own event, and response bodies became a
+    // list of frame sizes
+    Migration {
+        header: unchanged,
+        event: split_http_exchange,
+    },
+];
+
+/// Ids handed out to events that gained one while being upgraded
+#[derive(Default)]
+struct NewIds {
+    http_requests: u32,
+}
+
+fn unchanged(_: &mut Value) -> Result<()> {
+    Ok(())
+}
+
+fn unchanged_event(event: Value, _: &mut NewIds) -> Result<Vec<Value>> {
+    Ok(vec![event])
+}
+
+/// Split an `http_response` or `http_error` that carried its request into an
+/// `http_request` followed by the outcome, and describe a response's `body`
+/// as one data frame that arrived with the response head
+///
+/// The body itself stays, as traces this old may not have recorded the
+/// guest's reads of it.
+fn split_http_exchange(mut event: Value, ids: &mut NewIds) -> Result<Vec<Value>> {
+    let call = event.get("call").and_then(Value::as_str);
+    if call != Some("http_response") && call != Some("http_error") {
+        return Ok(vec![event]);
+    }
+    let Some(fields) = event.as_object_mut() else {
+        return Ok(vec![event]);
+    };
+    let request_id = ids.http_requests;
+    ids.http_requests += 1;
+
+    let mut request = serde_json::Map::new();
+    request.insert("call".to_string(), "http_request".into());
+    request.insert("request".to_string(), request_id.into());
+    for (old, new) in [
+        ("request_method", "method"),
+        ("request_url", "url"),
+        ("request_headers", "headers"),
+        ("request_config", "config"),
+    ] {
+        if let Some(value) = fields.remove(old) {
+            request.insert(new.to_string(), value);
+        }
+    }
+    // Request bodies are in the output stream's `stream_write` events
+    fields.remove("request_body");
+    fields.insert("request".to_string(), request_id.into());
+
+    if let Some(body) = fields.get("body") {
+        let frames = match body.as_str() {
+            Some("") => Vec::new(),
+            Some(hex) => vec![serde_json::json!({
+                "frame": "data",
+                "elapsed_nanoseconds": 0,
+                "size": hex.len() / 2,
+            })],
+            None => bail!("http_response body is not a hex string"),
+        };
+        fields.insert("frames".to_string(), frames.into());
+    }
+    Ok(vec![request.into(), event])
+}
+
+/// Brings the header and events of a trace up to the current schema as they
+/// are read
+pub struct Upgrader {
+    /// Schema the trace was written under
+    schema: u32,
+    ids: NewIds,
+}
+
+impl Upgrader {
+    /// Upgrade a raw header, which traces from before headers existed lack
+    pub fn new(header: Option<Value>) -> Result<(TraceHeader, Self)> {
+        let mut header = header.unwrap_or_else(|| serde_json::json!({ "schema": 0 }));
+        let schema = header
+            .get("schema")
+            .and_then(Value::as_u64)
+            .and_then(|schema| u32::try_from(schema).ok())
+            .ok_or_else(|| anyhow!("trace header has no valid schema version"))?;
+        if schema > SCHEMA_VERSION {
+            bail!(
+                "trace uses schema version {schema}, but this wasm-rr only understands up to \
+                 {SCHEMA_VERSION}; upgrade wasm-rr to read it"
+            );
+        }
+        let upgrader = Self {
+            schema,
+            ids: NewIds::default(),
+        };
+        for migration in upgrader.pending() {
+            (migration.header)(&mut header)?;
+        }
+        if let Some(fields) = header.as_object_mut() {
+            fields.insert("schema".to_string(), SCHEMA_VERSION.into());
+        }
+        let header = serde_json::from_value(header).context("invalid trace header")?;
+        Ok((header, upgrader))
+    }
+
+    /// Schema the trace was written under, before upgrading
+    pub fn schema(&self) -> u32 {
+        self.schema
+    }
+
+    /// Whether events can be read as they are, without going through
+    /// [`Upgrader::event`]
+    pub fn is_current(&self) -> bool {
+        self.schema == SCHEMA_VERSION
+    }
+
+    /// Upgrade one event, which comes out as one or more current ones
+    pub fn event(&mut self, event: Value) -> Result<Vec<TraceEvent>> {
+        let mut events = vec![event];
+        for migration in self.pending() {
+            let mut upgraded = Vec::new();
+            for event in events {
+                upgraded.extend((migration.event)(event, &mut self.ids)?);
+            }
+            events = upgraded;
+        }
+        events
+            .into_iter()
+            .map(|event| serde_json::from_value(event).context("invalid trace event"))
+            .collect()
+    }
+
+    fn pending(&self) -> &'static [Migration] {
+        usize::try_from(self.schema)
+            .ok()
+            .and_then(|schema| MIGRATIONS.get(schema..))
+            .unwrap_or_default()
+    }
+}
+
+/// Rewrite a trace in place under the current schema
+///
+/// Returns the schema it was written under before, or `None` if it was
+/// already current and has been left alone.
+pub fn migrate(path: &Path, format: TraceFormat) -> Result<Option<u32>> {
+    let Recovered {
+        header,
+        events,
+        truncated,
+        schema,
+    } = recover(path, format)?;
+    if truncated {
+        bail!(
+            "{} is truncated; run `wasm-rr repair` on it first",
+            path.display()
+        );
+    }
+    if schema == SCHEMA_VERSION {
+        return Ok(None);
+    }
+
+    // Write next to the original and swap it in, so a failure never leaves
+    // a half-written trace behind
+    let mut staging = path.as_os_str().to_owned();
+    staging.push(".migrating");
+    let staging = Path::new(&staging);
+    super::convert::write_trace(staging, format, &TraceFile { header, events })?;
+    std::fs::rename(staging, path)
+        .with_context(|| format!("failed to replace trace file at {}", path.display()))?;
+    Ok(Some(schema))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::HttpBodyFrame;
+
+    /// The `fetch_quote` golden trace predates headers, so it is schema 0
+    const FETCH_QUOTE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/golden/fetch_quote/trace.json");
+
+    #[test]
+    fn schema_0_trace_upgrades_to_the_current_schema() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("trace.json");
+        std::fs::copy(FETCH_QUOTE, &path)?;
+
+        assert_eq!(migrate(&path, TraceFormat::Json)?, Some(0));
+        let Recovered {
+            header,
+            events,
+            truncated,
+            schema,
+        } = recover(&path, TraceFormat::Json)?;
+        assert!(!truncated);
+        assert_eq!(schema, SCHEMA_VERSION);
+        assert_eq!(header.schema, SCHEMA_VERSION);
+        assert_eq!(header.component_sha256, None);
+
+        let calls: Vec<_> = events
+            .iter()
+            .map(|event| match event {
+                TraceEvent::HttpRequest { request, url, .. } => {
+                    format!("http_request {request} {url}")
+                }
+                TraceEvent::HttpResponse {
+                    request,
+                    status,
+                    frames,
+                    body,
+                    ..
+                } => {
+                    let sizes: Vec<_> = frames
+                        .iter()
+                        .map(|frame| match frame {
+                            HttpBodyFrame::Data { size, .. } => *size,
+                            HttpBodyFrame::Trailers { .. } => 0,
+                        })
+                        .collect();
+                    let kept = body.as_ref().map(Vec::len);
+                    format!("http_response {request} {status} {sizes:?} {kept:?}")
+                }
+                TraceEvent::RandomBytes { .. } => "random_bytes".to_string(),
+                TraceEvent::Read => "read".to_string(),
+                other => format!("{other:?}"),
+            })
+            .collect();
+        assert_eq!(calls.len(), 7);
+        assert_eq!(
+            calls.get(..4).unwrap_or_default(),
+            [
+                "http_request 0 https://bash-org-archive.com/?random1",
+                "http_response 0 302 [47] Some(47)",
+                "random_bytes",
+                "http_request 1 https://bash-org-archive.com/?664348",
+            ]
+        );
+        assert!(calls
+            .get(4)
+            .is_some_and(|call| call.starts_with("http_response 1 200 [")));
+        assert_eq!(calls.get(5..).unwrap_or_default(), ["read", "read"]);
+
+        // Once upgraded, the trace is left alone
+        assert_eq!(migrate(&path, TraceFormat::Json)?, None);
+        Ok(())
+    }
+}
@@ -1,7 +1,20 @@
+mod cbor;
 mod convert;
 mod event;
 mod format;
+mod header;
+mod migrate;
+mod redact;
+mod repair;
 
+pub use cbor::{CborEvents, CborItem};
 pub use convert::convert;
-pub use event::TraceEvent;
-pub use format::{TraceFile, TraceFormat};
+pub use event::{
+    DirEntry, FileHash, FileStat, FileTimestamp, HttpBodyFrame, HttpErrorCode, HttpFieldSize,
+    HttpRequestConfig, SnapshotDir, SnapshotEntry, StreamOrigin, TimestampUpdate, TraceEvent,
+};
+pub use format::TraceFormat;
+pub use header::{component_digest, TraceHeader, SCHEMA_VERSION};
+pub use migrate::migrate;
+pub use redact::{RedactionPolicy, Redactor};
+pub use repair::{is_finished, recover, repair, Recovered};
@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::util::glob::glob_match;
+
+const PLACEHOLDER_PREFIX: &str = "<redacted:";
+
+/// Which environment variables and HTTP headers have their values kept out
+/// of traces
+///
+/// Patterns may use `*` to match any run of characters. Header patterns are
+/// case-insensitive, like header names themselves.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedactionPolicy {
+    #[serde(default)]
+    pub env: Vec<String>,
+    #[serde(default)]
+    pub headers: Vec<String>,
+}
+
+impl RedactionPolicy {
+    /// Load a policy from a JSON file such as
+    /// `{"env": ["*_TOKEN"], "headers": ["authorization", "cookie"]}`
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read redaction policy {}", path.display()))?;
+        serde_json::from_str(&contents)
+            .with_context(|| format!("failed to parse redaction policy {}", path.display()))
+    }
+
+    /// Add the patterns of another policy to this one
+    pub fn extend(&mut self, other: RedactionPolicy) {
+        self.env.extend(other.env);
+        self.headers.extend(other.headers);
+    }
+}
+
+/// Applies a [`RedactionPolicy`] while recording, replacing each distinct
+/// secret with the same placeholder every time it appears in a trace
+#[derive(Debug, Default)]
+pub struct Redactor {
+    policy: RedactionPolicy,
+    placeholders: HashMap<String, String>,
+}
+
+impl Redactor {
+    pub fn new(policy: RedactionPolicy) -> Self {
+        Self {
+            policy,
+            placeholders: HashMap::new(),
+        }
+    }
+
+    pub fn redact_env(&mut self, entries: Vec<(String, String)>) -> Vec<(String, String)> {
+        entries
+            .into_iter()
+            .map(|(key, value)| {
+                if self
+                    .policy
+                    .env
+                    .iter()
+                    .any(|pattern| glob_match(pattern, &key))
+                {
+                    let placeholder = self.placeholder(value);
+                    (key, placeholder)
+                } else {
+                    (key, value)
+                }
+            })
+            .collect()
+    }
+
+    /// Redact header values, keeping the headers sorted as traces store them
+    pub fn redact_headers(&mut self, headers: Vec<(String, String)>) -> Vec<(String, String)> {
+        let mut headers: Vec<_> = headers
+            .into_iter()
+            .map(|(name, value)| {
+                if self.redacts_header(&name) {
+                    let placeholder = self.placeholder(value);
+                    (name, placeholder)
+                } else {
+                    (name, value)
+                }
+            })
+            .collect();
+        headers.sort();
+        headers
+    }
+
+    /// Whether the policy keeps the values of header `name` out of traces
+    pub fn redacts_header(&self, name: &str) -> bool {
+        let name = name.to_ascii_lowercase();
+        self.policy
+            .headers
+            .iter()
+            .any(|pattern| glob_match(&pattern.to_ascii_lowercase(), &name))
+    }
+
+    fn placeholder(&mut self, value: String) -> String {
+        let next = self.placeholders.len() + 1;
+        self.placeholders
+            .entry(value)
+            .or_insert_with(|| format!("{PLACEHOLDER_PREFIX}{next}>"))
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(entries: &[(&str, &str)]) -> Vec<(String, String)> {
+        entries
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn redactor(env: &[&str], headers: &[&str]) -> Redactor {
+        Redactor::new(RedactionPolicy {
+            env: env.iter().map(|pattern| pattern.to_string()).collect(),
+            headers: headers.iter().map(|pattern| pattern.to_string()).collect(),
+        })
+    }
+
+    #[test]
+    fn redacts_env_values_by_name() {
+        let mut redactor = redactor(&["*_TOKEN", "AWS_SECRET_*"], &[]);
+        let entries = redactor.redact_env(pairs(&[
+            ("GH_TOKEN", "ghp_1"),
+            ("AWS_SECRET_ACCESS_KEY", "wJal"),
+            ("HOME", "/root"),
+            ("gh_token", "lower"),
+        ]));
+        assert_eq!(
+            entries,
+            pairs(&[
+                ("GH_TOKEN", "<redacted:1>"),
+                ("AWS_SECRET_ACCESS_KEY", "<redacted:2>"),
+                ("HOME", "/root"),
+                // Environment variable names are case-sensitive
+                ("gh_token", "lower"),
+            ])
+        );
+    }
+
+    #[test]
+    fn header_names_are_case_insensitive() {
+        let mut redactor = redactor(&[], &["Authorization", "x-api-*"]);
+        assert!(redactor.redacts_header("authorization"));
+        assert!(redactor.redacts_header("AUTHORIZATION"));
+        assert!(redactor.redacts_header("X-Api-Key"));
+        assert!(!redactor.redacts_header("x-request-id"));
+        let headers = redactor.redact_headers(pairs(&[
+            ("x-request-id", "7"),
+            ("AUTHORIZATION", "Bearer a"),
+        ]));
+        assert_eq!(
+            headers,
+            pairs(&[("AUTHORIZATION", "<redacted:1>"), ("x-request-id", "7")])
+        );
+    }
+
+    #[test]
+    fn placeholders_are_stable_across_env_and_headers() {
+        let mut redactor = redactor(&["TOKEN", "OTHER"], &["authorization", "x-token"]);
+        let env = redactor.redact_env(pairs(&[("TOKEN", "abc"), ("OTHER", "xyz")]));
+        let headers = redactor.redact_headers(pairs(&[("authorization", "abc")]));
+        let again = redactor.redact_headers(pairs(&[("x-token", "xyz"), ("authorization", "new")]));
+        assert_eq!(
+            env,
+            pairs(&[("TOKEN", "<redacted:1>"), ("OTHER", "<redacted:2>")])
+        );
+        assert_eq!(headers, pairs(&[("authorization", "<redacted:1>")]));
+        assert_eq!(
+            again,
+            pairs(&[
+                ("authorization", "<redacted:3>"),
+                ("x-token", "<redacted:2>")
+            ])
+        );
+    }
+
+    #[test]
+    fn empty_policy_redacts_nothing() {
+        let mut redactor = Redactor::default();
+        let entries = pairs(&[("GH_TOKEN", "ghp_1")]);
+        assert_eq!(redactor.redact_env(entries.clone()), entries);
+        assert!(!redactor.redacts_header("authorization"));
+    }
+}
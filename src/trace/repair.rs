@@ -0,0 +1,380 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use super::cbor::{CborEvents, CborItem};
+use super::event::TraceEvent;
+use super::format::{TraceFile, TraceFormat};
+use super::header::TraceHeader;
+use super::migrate::Upgrader;
+
+/// The events recovered from a trace file, upgraded to the current schema
+pub struct Recovered {
+    pub header: TraceHeader,
+    pub events: Vec<TraceEvent>,
+    /// Whether the file stopped partway, e.g. because `wasm-rr` was killed
+    /// while recording
+    pub truncated: bool,
+    /// Schema the file was written under
+    pub schema: u32,
+}
+
+/// A JSON trace before its header and events are upgraded
+#[derive(Deserialize)]
+struct RawTraceFile {
+    #[serde(default)]
+    header: Option<Value>,
+    events: Vec<Value>,
+}
+
+/// Read every complete event from a trace, tolerating a file that was cut
+/// off at any point; anything else that does not parse is still an error
+pub fn recover(path: &Path, format: TraceFormat) -> Result<Recovered> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open trace file at {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    match format {
+        TraceFormat::Json => {
+            let mut contents = String::new();
+            reader
+                .read_to_string(&mut contents)
+                .with_context(|| format!("failed to read trace file at {}", path.display()))?;
+            recover_json(&contents)
+                .with_context(|| format!("failed to parse JSON trace file at {}", path.display()))
+        }
+        TraceFormat::Cbor => recover_cbor(reader)
+            .with_context(|| format!("failed to parse CBOR trace file at {}", path.display())),
+    }
+}
+
+fn recover_json(contents: &str) -> Result<Recovered> {
+    if let Ok(RawTraceFile { header, events }) = serde_json::from_str(contents) {
+        let (header, mut upgrader) = Upgrader::new(header)?;
+        return Ok(Recovered {
+            header,
+            events: upgrade_events(&mut upgrader, events)?,
+            truncated: false,
+            schema: upgrader.schema(),
+        });
+    }
+
+    // Walk the `{"header":{...},"events":[ ... ]}` wrapper by hand so a
+    // missing tail only loses the event it cut through
+    let mut header = None;
+    let mut events = Vec::new();
+    let truncated = walk_json(contents, &mut header, &mut events)?;
+
+    let (header, mut upgrader) = Upgrader::new(header)?;
+    Ok(Recovered {
+        header,
+        events: upgrade_events(&mut upgrader, events)?,
+        truncated,
+        schema: upgrader.schema(),
+    })
+}
+
+/// Collect the raw header and events of a JSON trace, returning whether it
+/// stops before the end
+fn walk_json(contents: &str, header: &mut Option<Value>, events: &mut Vec<Value>) -> Result<bool> {
+    let mut json = JsonWalker { rest: contents };
+    if !json.expect("{")? {
+        return Ok(true);
+    }
+    if json.rest.trim_start().starts_with("\"header\"") {
+        if !json.expect("\"header\"")? || !json.expect(":")? {
+            return Ok(true);
+        }
+        match json.value::<Value>().context("invalid trace header")? {
+            Some(value) => *header = Some(value),
+            None => return Ok(true),
+        }
+        if !json.expect(",")? {
+            return Ok(true);
+        }
+    }
+    for token in ["\"events\"", ":", "["] {
+        if !json.expect(token)? {
+            return Ok(true);
+        }
+    }
+    loop {
+        json.rest = json.rest.trim_start();
+        if json.rest.starts_with(']') {
+            // Everything after the last event arrived, so nothing was lost
+            return Ok(false);
+        }
+        match json
+            .value::<Value>()
+            .with_context(|| format!("invalid event #{}", events.len()))?
+        {
+            Some(event) => events.push(event),
+            None => return Ok(true),
+        }
+        json.rest = json.rest.trim_start();
+        json.rest = json.rest.strip_prefix(',').unwrap_or(json.rest);
+    }
+}
+
+fn upgrade_events(upgrader: &mut Upgrader, events: Vec<Value>) -> Result<Vec<TraceEvent>> {
+    let mut upgraded = Vec::with_capacity(events.len());
+    for (index, event) in events.into_iter().enumerate() {
+        upgraded.extend(
+            upgrader
+                .event(event)
+                .with_context(|| format!("invalid event #{index}"))?,
+        );
+    }
+    Ok(upgraded)
+}
+
+/// Steps through a JSON trace that may stop at any byte
+struct JsonWalker<'a> {
+    rest: &'a str,
+}
+
+impl JsonWalker<'_> {
+    /// Consume `token`, returning `false` if the input ends before it does
+    fn expect(&mut self, token: &str) -> Result<bool> {
+        let rest = self.rest.trim_start();
+        if token.starts_with(rest) && rest.len() < token.len() {
+            return Ok(false);
+        }
+        self.rest = rest
+            .strip_prefix(token)
+            .ok_or_else(|| anyhow!("expected {token} in the trace"))?;
+        Ok(true)
+    }
+
+    /// Parse one value, or `None` if the input ends partway through it
+    fn value<T: serde::de::DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        let mut stream = serde_json::Deserializer::from_str(self.rest).into_iter::<T>();
+        match stream.next() {
+            Some(Ok(value)) => {
+                self.rest = self.rest.get(stream.byte_offset()..).unwrap_or_default();
+                Ok(Some(value))
+            }
+            Some(Err(e)) if e.is_eof() => Ok(None),
+            Some(Err(e)) => Err(e.into()),
+            None => Ok(None),
+        }
+    }
+}
+
+fn recover_cbor(reader: impl BufRead) -> Result<Recovered> {
+    let (header, mut reader) = CborEvents::new(reader)?;
+    let mut events = Vec::new();
+    let truncated = loop {
+        match reader
+            .next_item()
+            .with_context(|| format!("invalid event #{}", events.len()))?
+        {
+            CborItem::Event(event) => events.push(*event),
+            CborItem::End => break false,
+            CborItem::Truncated => break true,
+        }
+    };
+    Ok(Recovered {
+        header,
+        events,
+        truncated,
+        schema: reader.schema(),
+    })
+}
+
+/// Whether a trace ends the way every finished recording does
+pub fn is_finished(events: &[TraceEvent]) -> bool {
+    matches!(
+        events.last(),
+        Some(TraceEvent::Exit { .. } | TraceEvent::Trap { .. } | TraceEvent::Incomplete)
+    )
+}
+
+/// Write a valid trace holding everything recoverable from `input`, ending in
+/// an `incomplete` marker if the recording did not finish
+///
+/// Returns the number of events recovered and whether the marker was added.
+pub fn repair(
+    input: &Path,
+    output: &Path,
+    input_format: TraceFormat,
+    output_format: TraceFormat,
+) -> Result<(usize, bool)> {
+    let Recovered {
+        header, mut events, ..
+    } = recover(input, input_format)?;
+    let recovered = events.len();
+    let unfinished = !is_finished(&events);
+    if unfinished {
+        events.push(TraceEvent::Incomplete);
+    }
+    super::convert::write_trace(output, output_format, &TraceFile { header, events })?;
+    Ok((recovered, unfinished))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::SCHEMA_VERSION;
+
+    fn header() -> TraceHeader {
+        TraceHeader {
+            schema: SCHEMA_VERSION,
+            wasm_rr_version: None,
+            component_sha256: None,
+            argv: Vec::new(),
+            start_seconds: None,
+            start_nanoseconds: None,
+            host: None,
+        }
+    }
+
+    fn events() -> Vec<TraceEvent> {
+        vec![
+            TraceEvent::Arguments {
+                args: vec!["app.wasm".to_string()],
+            },
+            TraceEvent::RandomU64 { value: 4 },
+            TraceEvent::Exit { code: 0 },
+        ]
+    }
+
+    /// A JSON trace laid out the way the recorder writes it
+    fn json_trace() -> Result<String> {
+        let events = events()
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<serde_json::Result<Vec<_>>>()?;
+        Ok(format!(
+            "{{\"header\":{},\"events\":[\n{}\n]}}",
+            serde_json::to_string(&header())?,
+            events.join(",\n")
+        ))
+    }
+
+    /// Walk `contents` cut off just before the first occurrence of `cut`
+    fn walk_until(contents: &str, cut: &str) -> Result<(bool, Option<Value>, Vec<Value>)> {
+        let end = contents
+            .find(cut)
+            .ok_or_else(|| anyhow!("{cut:?} is not in the trace"))?;
+        let mut header = None;
+        let mut events = Vec::new();
+        let truncated = walk_json(
+            contents.get(..end).unwrap_or_default(),
+            &mut header,
+            &mut events,
+        )?;
+        Ok((truncated, header, events))
+    }
+
+    #[test]
+    fn walk_json_reads_a_whole_trace() -> Result<()> {
+        let contents = json_trace()?;
+        let mut header = None;
+        let mut events = Vec::new();
+        assert!(!walk_json(&contents, &mut header, &mut events)?);
+        assert!(header.is_some());
+        assert_eq!(events.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn walk_json_stops_partway_through_the_header() -> Result<()> {
+        let contents = json_trace()?;
+        for cut in ["\"header\"", "\"schema\"", "}"] {
+            let (truncated, header, events) = walk_until(&contents, cut)?;
+            assert!(truncated, "cut before {cut:?}");
+            assert_eq!(header, None);
+            assert!(events.is_empty());
+        }
+        let (truncated, header, events) = walk_until(&contents, "[")?;
+        assert!(truncated);
+        assert!(header.is_some());
+        assert!(events.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn walk_json_keeps_the_events_before_the_cut() -> Result<()> {
+        let contents = json_trace()?;
+        // Partway through the second event
+        let (truncated, _, events) = walk_until(&contents, "4}")?;
+        assert!(truncated);
+        assert_eq!(events.len(), 1);
+        // Right after the comma that follows it
+        let (truncated, _, events) = walk_until(&contents, "\n{\"call\":\"exit\"")?;
+        assert!(truncated);
+        assert_eq!(events.len(), 2);
+        // Only the closing brackets are missing
+        let (truncated, _, events) = walk_until(&contents, "\n]}")?;
+        assert!(truncated);
+        assert_eq!(events.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn walk_json_rejects_invalid_events() -> Result<()> {
+        let contents = json_trace()?.replace("\"value\":4", "\"value\":x");
+        let mut header = None;
+        let mut events = Vec::new();
+        assert!(walk_json(&contents, &mut header, &mut events).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn recover_json_upgrades_what_it_finds() -> Result<()> {
+        let contents = json_trace()?;
+        let whole = recover_json(&contents)?;
+        assert!(!whole.truncated);
+        assert_eq!(whole.events, events());
+        assert!(is_finished(&whole.events));
+
+        let end = contents.find("\n{\"call\":\"exit\"").unwrap_or_default();
+        let cut = recover_json(contents.get(..end).unwrap_or_default())?;
+        assert!(cut.truncated);
+        assert_eq!(cut.header, header());
+        assert_eq!(cut.events, events().get(..2).unwrap_or_default());
+        assert!(!is_finished(&cut.events));
+        Ok(())
+    }
+
+    /// A CBOR trace, with the offsets at which each of its events starts
+    fn cbor_trace() -> Result<(Vec<u8>, Vec<usize>)> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&header(), &mut bytes)?;
+        let mut starts = Vec::new();
+        for event in events() {
+            starts.push(bytes.len());
+            ciborium::into_writer(&event, &mut bytes)?;
+        }
+        Ok((bytes, starts))
+    }
+
+    #[test]
+    fn recover_cbor_stops_partway_through_an_item() -> Result<()> {
+        let (bytes, starts) = cbor_trace()?;
+        let whole = recover_cbor(bytes.as_slice())?;
+        assert!(!whole.truncated);
+        assert_eq!(whole.events, events());
+
+        for (kept, start) in starts.iter().enumerate() {
+            // Cut between events, which looks like a clean end
+            let cut = recover_cbor(bytes.get(..*start).unwrap_or_default())?;
+            assert!(!cut.truncated);
+            assert_eq!(cut.events.len(), kept);
+            // Cut partway through the event
+            let cut = recover_cbor(bytes.get(..start + 2).unwrap_or_default())?;
+            assert!(cut.truncated, "cut inside event {kept}");
+            assert_eq!(cut.events.len(), kept);
+            assert_eq!(cut.header, header());
+        }
+
+        // Partway through the header, which leaves nothing to go on
+        let cut = recover_cbor(bytes.get(..3).unwrap_or_default())?;
+        assert!(cut.truncated);
+        assert!(cut.events.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+/// Match `name` against a pattern where `*` stands for any run of characters
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // No `*` at all, so the name must match exactly
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = rest.get(index + part.len()..).unwrap_or_default(),
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    #[test]
+    fn without_wildcards_matches_exactly() {
+        assert!(glob_match("PATH", "PATH"));
+        assert!(!glob_match("PATH", "PATHS"));
+        assert!(!glob_match("PATH", "PAT"));
+        assert!(!glob_match("PATH", "path"));
+    }
+
+    #[test]
+    fn empty_pattern_matches_only_empty_names() {
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "HOME"));
+    }
+
+    #[test]
+    fn star_at_either_end() {
+        assert!(glob_match("*_TOKEN", "GH_TOKEN"));
+        assert!(glob_match("*_TOKEN", "_TOKEN"));
+        assert!(!glob_match("*_TOKEN", "GH_TOKEN_FILE"));
+        assert!(glob_match("AWS_*", "AWS_SECRET_ACCESS_KEY"));
+        assert!(glob_match("AWS_*", "AWS_"));
+        assert!(!glob_match("AWS_*", "MY_AWS_KEY"));
+        assert!(glob_match("*SECRET*", "SECRET"));
+        assert!(glob_match("*SECRET*", "MY_SECRET_KEY"));
+        assert!(!glob_match("*SECRET*", "SECRE"));
+    }
+
+    #[test]
+    fn stars_in_the_middle_match_in_order() {
+        assert!(glob_match("a*b*c", "abc"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("a*b*c", "acb"));
+        // The parts around a star may not overlap
+        assert!(!glob_match("ab*ba", "aba"));
+        assert!(!glob_match("*x*x", "x"));
+    }
+
+    #[test]
+    fn double_star_is_one_star() {
+        for name in ["", "a", "KEY_ID"] {
+            assert!(glob_match("*", name));
+            assert!(glob_match("**", name));
+        }
+        assert!(glob_match("KEY**ID", "KEY_ID"));
+        assert!(!glob_match("KEY**ID", "KEY_IDS"));
+    }
+}
@@ -1 +1,10 @@
 pub mod cbor;
+pub mod glob;
+
+/// Tell the user about something suspicious that does not stop the run
+pub fn warn(message: impl std::fmt::Display) {
+    #[allow(clippy::print_stderr)]
+    {
+        eprintln!("warning: {message}");
+    }
+}
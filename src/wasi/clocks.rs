@@ -1,2 +1,77 @@
-// Placeholder for clock-related types and utilities
-// Current implementations remain in recorder.rs and playback.rs
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use wasmtime::component::{Resource, ResourceTable};
+use wasmtime_wasi_io::poll::{subscribe, DynPollable, Pollable};
+
+/// A clock subscription that resolves as soon as it is polled
+struct VirtualTimer;
+
+#[async_trait::async_trait]
+impl Pollable for VirtualTimer {
+    async fn ready(&mut self) {}
+}
+
+/// Monotonic time as seen by a replayed guest
+///
+/// Clock subscriptions never wait on the host. The trace decides when a timer
+/// fired, and the clock jumps forward to that timer's deadline.
+#[derive(Debug, Default)]
+pub struct VirtualClock {
+    now: u64,
+    /// Deadlines of live timer pollables, keyed by resource handle
+    timers: HashMap<u32, u64>,
+}
+
+impl VirtualClock {
+    /// Advance to a monotonic reading served from the trace
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the reading is earlier than a timer that already fired
+    pub fn observe(&mut self, now: u64) -> Result<()> {
+        if now < self.now {
+            return Err(anyhow!(
+                "recorded monotonic time {} is earlier than a timer that already fired at {}",
+                now,
+                self.now
+            ));
+        }
+        self.now = now;
+        Ok(())
+    }
+
+    /// Create a pollable that fires at `when` on the virtual clock
+    pub fn subscribe_instant(
+        &mut self,
+        table: &mut ResourceTable,
+        when: u64,
+    ) -> Result<Resource<DynPollable>> {
+        let timer = table.push(VirtualTimer)?;
+        let pollable = subscribe(table, timer)?;
+        self.timers.insert(pollable.rep(), when);
+        Ok(pollable)
+    }
+
+    /// Create a pollable that fires `duration` nanoseconds from now
+    pub fn subscribe_duration(
+        &mut self,
+        table: &mut ResourceTable,
+        duration: u64,
+    ) -> Result<Resource<DynPollable>> {
+        let when = self.now.saturating_add(duration);
+        self.subscribe_instant(table, when)
+    }
+
+    /// Move the clock to the deadline of `pollable` if it is a timer that fired
+    pub fn fire(&mut self, pollable: u32) {
+        if let Some(&when) = self.timers.get(&pollable) {
+            self.now = self.now.max(when);
+        }
+    }
+
+    /// Forget a timer when the guest drops its pollable
+    pub fn forget(&mut self, pollable: u32) {
+        self.timers.remove(&pollable);
+    }
+}
@@ -0,0 +1,260 @@
+use anyhow::anyhow;
+use wasmtime_wasi::p2::bindings::clocks::wall_clock::Datetime;
+use wasmtime_wasi::p2::bindings::filesystem::types::ErrorCode;
+use wasmtime_wasi::p2::bindings::sync::filesystem::types::{
+    DescriptorFlags, DescriptorStat, DescriptorType, DirectoryEntry, MetadataHashValue,
+    NewTimestamp, OpenFlags, PathFlags,
+};
+use wasmtime_wasi::p2::{FsError, FsResult};
+
+use crate::trace::{DirEntry, FileHash, FileStat, FileTimestamp, TimestampUpdate};
+
+/// Stand-in for a descriptor during replay, where every call on it is answered
+/// from the trace instead of the host filesystem
+pub struct ReplayDescriptor;
+
+/// Stand-in for a directory listing during replay
+pub struct ReplayDirectoryStream;
+
+/// Every `wasi:filesystem` error code, used to map recorded names back to values
+const ERROR_CODES: [ErrorCode; 37] = [
+    ErrorCode::Access,
+    ErrorCode::WouldBlock,
+    ErrorCode::Already,
+    ErrorCode::BadDescriptor,
+    ErrorCode::Busy,
+    ErrorCode::Deadlock,
+    ErrorCode::Quota,
+    ErrorCode::Exist,
+    ErrorCode::FileTooLarge,
+    ErrorCode::IllegalByteSequence,
+    ErrorCode::InProgress,
+    ErrorCode::Interrupted,
+    ErrorCode::Invalid,
+    ErrorCode::Io,
+    ErrorCode::IsDirectory,
+    ErrorCode::Loop,
+    ErrorCode::TooManyLinks,
+    ErrorCode::MessageSize,
+    ErrorCode::NameTooLong,
+    ErrorCode::NoDevice,
+    ErrorCode::NoEntry,
+    ErrorCode::NoLock,
+    ErrorCode::InsufficientMemory,
+    ErrorCode::InsufficientSpace,
+    ErrorCode::NotDirectory,
+    ErrorCode::NotEmpty,
+    ErrorCode::NotRecoverable,
+    ErrorCode::Unsupported,
+    ErrorCode::NoTty,
+    ErrorCode::NoSuchDevice,
+    ErrorCode::Overflow,
+    ErrorCode::NotPermitted,
+    ErrorCode::Pipe,
+    ErrorCode::ReadOnly,
+    ErrorCode::InvalidSeek,
+    ErrorCode::TextFileBusy,
+    ErrorCode::CrossDevice,
+];
+
+/// Look up an error code by its WIT name, as stored in traces
+pub fn error_code_from_name(name: &str) -> Option<ErrorCode> {
+    ERROR_CODES.into_iter().find(|code| code.name() == name)
+}
+
+/// The error code carried by a filesystem error, or `None` for traps
+pub fn recorded_error_code(err: &FsError) -> Option<&'static str> {
+    err.downcast_ref().map(|code: &ErrorCode| code.name())
+}
+
+/// Rebuild the filesystem error for a recorded error code name
+pub fn replay_error_code(name: &str) -> FsError {
+    match error_code_from_name(name) {
+        Some(code) => code.into(),
+        None => FsError::trap(anyhow!("unknown filesystem error code in trace: {name}")),
+    }
+}
+
+const PATH_FLAGS: [(PathFlags, &str); 1] = [(PathFlags::SYMLINK_FOLLOW, "symlink-follow")];
+
+const OPEN_FLAGS: [(OpenFlags, &str); 4] = [
+    (OpenFlags::CREATE, "create"),
+    (OpenFlags::DIRECTORY, "directory"),
+    (OpenFlags::EXCLUSIVE, "exclusive"),
+    (OpenFlags::TRUNCATE, "truncate"),
+];
+
+const DESCRIPTOR_FLAGS: [(DescriptorFlags, &str); 6] = [
+    (DescriptorFlags::READ, "read"),
+    (DescriptorFlags::WRITE, "write"),
+    (DescriptorFlags::FILE_INTEGRITY_SYNC, "file-integrity-sync"),
+    (DescriptorFlags::DATA_INTEGRITY_SYNC, "data-integrity-sync"),
+    (
+        DescriptorFlags::REQUESTED_WRITE_SYNC,
+        "requested-write-sync",
+    ),
+    (DescriptorFlags::MUTATE_DIRECTORY, "mutate-directory"),
+];
+
+/// WIT names of the flags in a set for which `contains` holds
+fn flag_names<F: Copy>(table: &[(F, &str)], contains: impl Fn(F) -> bool) -> Vec<String> {
+    table
+        .iter()
+        .filter(|(flag, _)| contains(*flag))
+        .map(|(_, name)| name.to_string())
+        .collect()
+}
+
+pub fn path_flag_names(flags: PathFlags) -> Vec<String> {
+    flag_names(&PATH_FLAGS, |flag| flags.contains(flag))
+}
+
+pub fn open_flag_names(flags: OpenFlags) -> Vec<String> {
+    flag_names(&OPEN_FLAGS, |flag| flags.contains(flag))
+}
+
+pub fn descriptor_flag_names(flags: DescriptorFlags) -> Vec<String> {
+    flag_names(&DESCRIPTOR_FLAGS, |flag| flags.contains(flag))
+}
+
+/// Rebuild a descriptor flag set from the names stored in traces
+pub fn descriptor_flags_from_names(names: &[String]) -> FsResult<DescriptorFlags> {
+    names
+        .iter()
+        .try_fold(DescriptorFlags::empty(), |flags, name| {
+            DESCRIPTOR_FLAGS
+                .iter()
+                .find(|(_, known)| known == name)
+                .map(|(flag, _)| flags | *flag)
+                .ok_or_else(|| FsError::trap(anyhow!("unknown descriptor flag in trace: {name}")))
+        })
+}
+
+const DESCRIPTOR_TYPES: [(DescriptorType, &str); 8] = [
+    (DescriptorType::Unknown, "unknown"),
+    (DescriptorType::BlockDevice, "block-device"),
+    (DescriptorType::CharacterDevice, "character-device"),
+    (DescriptorType::Directory, "directory"),
+    (DescriptorType::Fifo, "fifo"),
+    (DescriptorType::SymbolicLink, "symbolic-link"),
+    (DescriptorType::RegularFile, "regular-file"),
+    (DescriptorType::Socket, "socket"),
+];
+
+/// WIT name of a descriptor type, as stored in traces
+pub fn descriptor_type_name(kind: DescriptorType) -> String {
+    DESCRIPTOR_TYPES
+        .iter()
+        .find(|(known, _)| *known == kind)
+        .map_or("unknown", |(_, name)| name)
+        .to_string()
+}
+
+/// Look up a descriptor type by its WIT name
+pub fn descriptor_type_from_name(name: &str) -> FsResult<DescriptorType> {
+    DESCRIPTOR_TYPES
+        .iter()
+        .find(|(_, known)| *known == name)
+        .map(|(kind, _)| *kind)
+        .ok_or_else(|| FsError::trap(anyhow!("unknown descriptor type in trace: {name}")))
+}
+
+fn recorded_timestamp(datetime: Option<Datetime>) -> Option<FileTimestamp> {
+    datetime.map(|datetime| FileTimestamp {
+        seconds: datetime.seconds,
+        nanoseconds: datetime.nanoseconds,
+    })
+}
+
+fn replayed_timestamp(timestamp: Option<FileTimestamp>) -> Option<Datetime> {
+    timestamp.map(|timestamp| Datetime {
+        seconds: timestamp.seconds,
+        nanoseconds: timestamp.nanoseconds,
+    })
+}
+
+pub fn recorded_timestamp_update(timestamp: NewTimestamp) -> TimestampUpdate {
+    match timestamp {
+        NewTimestamp::NoChange => TimestampUpdate::NoChange,
+        NewTimestamp::Now => TimestampUpdate::Now,
+        NewTimestamp::Timestamp(datetime) => TimestampUpdate::Set(FileTimestamp {
+            seconds: datetime.seconds,
+            nanoseconds: datetime.nanoseconds,
+        }),
+    }
+}
+
+/// The time a `set-times` call took for its `now` updates, read back from a
+/// stat of the file right after it, or `None` if it had none
+pub fn applied_now(
+    access: NewTimestamp,
+    modification: NewTimestamp,
+    stat: &DescriptorStat,
+) -> Option<FileTimestamp> {
+    match (access, modification) {
+        (_, NewTimestamp::Now) => recorded_timestamp(stat.data_modification_timestamp),
+        (NewTimestamp::Now, _) => recorded_timestamp(stat.data_access_timestamp),
+        _ => None,
+    }
+}
+
+/// Replace a `now` update with the time it took when recorded
+pub fn replayed_timestamp_update(
+    timestamp: NewTimestamp,
+    now: Option<FileTimestamp>,
+) -> NewTimestamp {
+    match (timestamp, replayed_timestamp(now)) {
+        (NewTimestamp::Now, Some(datetime)) => NewTimestamp::Timestamp(datetime),
+        (timestamp, _) => timestamp,
+    }
+}
+
+pub fn recorded_stat(stat: &DescriptorStat) -> FileStat {
+    FileStat {
+        kind: descriptor_type_name(stat.type_),
+        link_count: stat.link_count,
+        size: stat.size,
+        data_access_timestamp: recorded_timestamp(stat.data_access_timestamp),
+        data_modification_timestamp: recorded_timestamp(stat.data_modification_timestamp),
+        status_change_timestamp: recorded_timestamp(stat.status_change_timestamp),
+    }
+}
+
+pub fn replayed_stat(stat: FileStat) -> FsResult<DescriptorStat> {
+    Ok(DescriptorStat {
+        type_: descriptor_type_from_name(&stat.kind)?,
+        link_count: stat.link_count,
+        size: stat.size,
+        data_access_timestamp: replayed_timestamp(stat.data_access_timestamp),
+        data_modification_timestamp: replayed_timestamp(stat.data_modification_timestamp),
+        status_change_timestamp: replayed_timestamp(stat.status_change_timestamp),
+    })
+}
+
+pub fn recorded_hash(hash: &MetadataHashValue) -> FileHash {
+    FileHash {
+        lower: hash.lower,
+        upper: hash.upper,
+    }
+}
+
+pub fn replayed_hash(hash: FileHash) -> MetadataHashValue {
+    MetadataHashValue {
+        lower: hash.lower,
+        upper: hash.upper,
+    }
+}
+
+pub fn recorded_entry(entry: &DirectoryEntry) -> DirEntry {
+    DirEntry {
+        kind: descriptor_type_name(entry.type_),
+        name: entry.name.clone(),
+    }
+}
+
+pub fn replayed_entry(entry: DirEntry) -> FsResult<DirectoryEntry> {
+    Ok(DirectoryEntry {
+        type_: descriptor_type_from_name(&entry.kind)?,
+        name: entry.name,
+    })
+}
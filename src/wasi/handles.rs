@@ -0,0 +1,33 @@
+use std::collections::HashMap;
+
+/// Assigns stable trace ids to resources as the guest obtains them
+///
+/// Resource handles are table indices that get reused after a drop, so the
+/// trace refers to streams, descriptors and directory streams by an id that is
+/// never handed out twice. Each kind of resource gets its own registry so ids
+/// count up from zero per kind.
+#[derive(Debug, Default)]
+pub struct HandleRegistry {
+    next_id: u32,
+    ids: HashMap<u32, u32>,
+}
+
+impl HandleRegistry {
+    /// Assign the next trace id to the resource behind handle `rep`
+    pub fn register(&mut self, rep: u32) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.ids.insert(rep, id);
+        id
+    }
+
+    /// Look up the trace id of a registered resource
+    pub fn get(&self, rep: u32) -> Option<u32> {
+        self.ids.get(&rep).copied()
+    }
+
+    /// Forget a resource when the guest drops it, returning its trace id
+    pub fn remove(&mut self, rep: u32) -> Option<u32> {
+        self.ids.remove(&rep)
+    }
+}
@@ -1,2 +1,183 @@
-// Placeholder for HTTP-related types and utilities
-// Current implementations remain in recorder.rs and playback.rs
+use std::time::Duration;
+
+use wasmtime_wasi_http::bindings::http::types::{
+    DnsErrorPayload, ErrorCode, FieldSizePayload, TlsAlertReceivedPayload,
+};
+use wasmtime_wasi_http::types::OutgoingRequestConfig;
+
+use crate::trace::{HttpErrorCode, HttpFieldSize, HttpRequestConfig};
+
+/// Turn the transport settings of a request into their trace form
+pub fn recorded_request_config(config: &OutgoingRequestConfig) -> HttpRequestConfig {
+    HttpRequestConfig {
+        use_tls: config.use_tls,
+        connect_timeout_nanoseconds: timeout_nanoseconds(config.connect_timeout),
+        first_byte_timeout_nanoseconds: timeout_nanoseconds(config.first_byte_timeout),
+        between_bytes_timeout_nanoseconds: timeout_nanoseconds(config.between_bytes_timeout),
+    }
+}
+
+/// A timeout in nanoseconds, saturating for ones too long to matter
+pub fn timeout_nanoseconds(timeout: Duration) -> u64 {
+    u64::try_from(timeout.as_nanos()).unwrap_or(u64::MAX)
+}
+
+/// Turn the error code of a failed request into its trace form
+pub fn recorded_error_code(code: &ErrorCode) -> HttpErrorCode {
+    match code.clone() {
+        ErrorCode::DnsTimeout => HttpErrorCode::DnsTimeout,
+        ErrorCode::DnsError(DnsErrorPayload { rcode, info_code }) => {
+            HttpErrorCode::DnsError { rcode, info_code }
+        }
+        ErrorCode::DestinationNotFound => HttpErrorCode::DestinationNotFound,
+        ErrorCode::DestinationUnavailable => HttpErrorCode::DestinationUnavailable,
+        ErrorCode::DestinationIpProhibited => HttpErrorCode::DestinationIpProhibited,
+        ErrorCode::DestinationIpUnroutable => HttpErrorCode::DestinationIpUnroutable,
+        ErrorCode::ConnectionRefused => HttpErrorCode::ConnectionRefused,
+        ErrorCode::ConnectionTerminated => HttpErrorCode::ConnectionTerminated,
+        ErrorCode::ConnectionTimeout => HttpErrorCode::ConnectionTimeout,
+        ErrorCode::ConnectionReadTimeout => HttpErrorCode::ConnectionReadTimeout,
+        ErrorCode::ConnectionWriteTimeout => HttpErrorCode::ConnectionWriteTimeout,
+        ErrorCode::ConnectionLimitReached => HttpErrorCode::ConnectionLimitReached,
+        ErrorCode::TlsProtocolError => HttpErrorCode::TlsProtocolError,
+        ErrorCode::TlsCertificateError => HttpErrorCode::TlsCertificateError,
+        ErrorCode::TlsAlertReceived(TlsAlertReceivedPayload {
+            alert_id,
+            alert_message,
+        }) => HttpErrorCode::TlsAlertReceived {
+            alert_id,
+            alert_message,
+        },
+        ErrorCode::HttpRequestDenied => HttpErrorCode::HttpRequestDenied,
+        ErrorCode::HttpRequestLengthRequired => HttpErrorCode::HttpRequestLengthRequired,
+        ErrorCode::HttpRequestBodySize(size) => HttpErrorCode::HttpRequestBodySize { size },
+        ErrorCode::HttpRequestMethodInvalid => HttpErrorCode::HttpRequestMethodInvalid,
+        ErrorCode::HttpRequestUriInvalid => HttpErrorCode::HttpRequestUriInvalid,
+        ErrorCode::HttpRequestUriTooLong => HttpErrorCode::HttpRequestUriTooLong,
+        ErrorCode::HttpRequestHeaderSectionSize(size) => {
+            HttpErrorCode::HttpRequestHeaderSectionSize { size }
+        }
+        ErrorCode::HttpRequestHeaderSize(field) => HttpErrorCode::HttpRequestHeaderSize {
+            field: field.map(recorded_field_size),
+        },
+        ErrorCode::HttpRequestTrailerSectionSize(size) => {
+            HttpErrorCode::HttpRequestTrailerSectionSize { size }
+        }
+        ErrorCode::HttpRequestTrailerSize(field) => HttpErrorCode::HttpRequestTrailerSize {
+            field: recorded_field_size(field),
+        },
+        ErrorCode::HttpResponseIncomplete => HttpErrorCode::HttpResponseIncomplete,
+        ErrorCode::HttpResponseHeaderSectionSize(size) => {
+            HttpErrorCode::HttpResponseHeaderSectionSize { size }
+        }
+        ErrorCode::HttpResponseHeaderSize(field) => HttpErrorCode::HttpResponseHeaderSize {
+            field: recorded_field_size(field),
+        },
+        ErrorCode::HttpResponseBodySize(size) => HttpErrorCode::HttpResponseBodySize { size },
+        ErrorCode::HttpResponseTrailerSectionSize(size) => {
+            HttpErrorCode::HttpResponseTrailerSectionSize { size }
+        }
+        ErrorCode::HttpResponseTrailerSize(field) => HttpErrorCode::HttpResponseTrailerSize {
+            field: recorded_field_size(field),
+        },
+        ErrorCode::HttpResponseTransferCoding(coding) => {
+            HttpErrorCode::HttpResponseTransferCoding { coding }
+        }
+        ErrorCode::HttpResponseContentCoding(coding) => {
+            HttpErrorCode::HttpResponseContentCoding { coding }
+        }
+        ErrorCode::HttpResponseTimeout => HttpErrorCode::HttpResponseTimeout,
+        ErrorCode::HttpUpgradeFailed => HttpErrorCode::HttpUpgradeFailed,
+        ErrorCode::HttpProtocolError => HttpErrorCode::HttpProtocolError,
+        ErrorCode::LoopDetected => HttpErrorCode::LoopDetected,
+        ErrorCode::ConfigurationError => HttpErrorCode::ConfigurationError,
+        ErrorCode::InternalError(message) => HttpErrorCode::InternalError { message },
+    }
+}
+
+/// Rebuild the error code of a failed request from the trace
+pub fn replay_error_code(code: HttpErrorCode) -> ErrorCode {
+    match code {
+        HttpErrorCode::DnsTimeout => ErrorCode::DnsTimeout,
+        HttpErrorCode::DnsError { rcode, info_code } => {
+            ErrorCode::DnsError(DnsErrorPayload { rcode, info_code })
+        }
+        HttpErrorCode::DestinationNotFound => ErrorCode::DestinationNotFound,
+        HttpErrorCode::DestinationUnavailable => ErrorCode::DestinationUnavailable,
+        HttpErrorCode::DestinationIpProhibited => ErrorCode::DestinationIpProhibited,
+        HttpErrorCode::DestinationIpUnroutable => ErrorCode::DestinationIpUnroutable,
+        HttpErrorCode::ConnectionRefused => ErrorCode::ConnectionRefused,
+        HttpErrorCode::ConnectionTerminated => ErrorCode::ConnectionTerminated,
+        HttpErrorCode::ConnectionTimeout => ErrorCode::ConnectionTimeout,
+        HttpErrorCode::ConnectionReadTimeout => ErrorCode::ConnectionReadTimeout,
+        HttpErrorCode::ConnectionWriteTimeout => ErrorCode::ConnectionWriteTimeout,
+        HttpErrorCode::ConnectionLimitReached => ErrorCode::ConnectionLimitReached,
+        HttpErrorCode::TlsProtocolError => ErrorCode::TlsProtocolError,
+        HttpErrorCode::TlsCertificateError => ErrorCode::TlsCertificateError,
+        HttpErrorCode::TlsAlertReceived {
+            alert_id,
+            alert_message,
+        } => ErrorCode::TlsAlertReceived(TlsAlertReceivedPayload {
+            alert_id,
+            alert_message,
+        }),
+        HttpErrorCode::HttpRequestDenied => ErrorCode::HttpRequestDenied,
+        HttpErrorCode::HttpRequestLengthRequired => ErrorCode::HttpRequestLengthRequired,
+        HttpErrorCode::HttpRequestBodySize { size } => ErrorCode::HttpRequestBodySize(size),
+        HttpErrorCode::HttpRequestMethodInvalid => ErrorCode::HttpRequestMethodInvalid,
+        HttpErrorCode::HttpRequestUriInvalid => ErrorCode::HttpRequestUriInvalid,
+        HttpErrorCode::HttpRequestUriTooLong => ErrorCode::HttpRequestUriTooLong,
+        HttpErrorCode::HttpRequestHeaderSectionSize { size } => {
+            ErrorCode::HttpRequestHeaderSectionSize(size)
+        }
+        HttpErrorCode::HttpRequestHeaderSize { field } => {
+            ErrorCode::HttpRequestHeaderSize(field.map(replay_field_size))
+        }
+        HttpErrorCode::HttpRequestTrailerSectionSize { size } => {
+            ErrorCode::HttpRequestTrailerSectionSize(size)
+        }
+        HttpErrorCode::HttpRequestTrailerSize { field } => {
+            ErrorCode::HttpRequestTrailerSize(replay_field_size(field))
+        }
+        HttpErrorCode::HttpResponseIncomplete => ErrorCode::HttpResponseIncomplete,
+        HttpErrorCode::HttpResponseHeaderSectionSize { size } => {
+            ErrorCode::HttpResponseHeaderSectionSize(size)
+        }
+        HttpErrorCode::HttpResponseHeaderSize { field } => {
+            ErrorCode::HttpResponseHeaderSize(replay_field_size(field))
+        }
+        HttpErrorCode::HttpResponseBodySize { size } => ErrorCode::HttpResponseBodySize(size),
+        HttpErrorCode::HttpResponseTrailerSectionSize { size } => {
+            ErrorCode::HttpResponseTrailerSectionSize(size)
+        }
+        HttpErrorCode::HttpResponseTrailerSize { field } => {
+            ErrorCode::HttpResponseTrailerSize(replay_field_size(field))
+        }
+        HttpErrorCode::HttpResponseTransferCoding { coding } => {
+            ErrorCode::HttpResponseTransferCoding(coding)
+        }
+        HttpErrorCode::HttpResponseContentCoding { coding } => {
+            ErrorCode::HttpResponseContentCoding(coding)
+        }
+        HttpErrorCode::HttpResponseTimeout => ErrorCode::HttpResponseTimeout,
+        HttpErrorCode::HttpUpgradeFailed => ErrorCode::HttpUpgradeFailed,
+        HttpErrorCode::HttpProtocolError => ErrorCode::HttpProtocolError,
+        HttpErrorCode::LoopDetected => ErrorCode::LoopDetected,
+        HttpErrorCode::ConfigurationError => ErrorCode::ConfigurationError,
+        HttpErrorCode::InternalError { message } => ErrorCode::InternalError(message),
+    }
+}
+
+fn recorded_field_size(field: FieldSizePayload) -> HttpFieldSize {
+    HttpFieldSize {
+        field_name: field.field_name,
+        field_size: field.field_size,
+    }
+}
+
+fn replay_field_size(field: HttpFieldSize) -> FieldSizePayload {
+    FieldSizePayload {
+        field_name: field.field_name,
+        field_size: field.field_size,
+    }
+}
@@ -1,5 +1,8 @@
 pub mod clocks;
 pub mod environment;
+pub mod filesystem;
+pub mod handles;
 pub mod http;
 pub mod random;
+pub mod snapshot;
 pub mod util;
@@ -0,0 +1,760 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use wasmtime::component::{Resource, ResourceTable};
+use wasmtime_wasi::p2::bindings::clocks::wall_clock::Datetime;
+use wasmtime_wasi::p2::bindings::filesystem::types::ErrorCode;
+use wasmtime_wasi::p2::bindings::sync::filesystem::types::{
+    self, Advice, Descriptor, DescriptorFlags, DescriptorStat, DescriptorType, DirectoryEntry,
+    DirectoryEntryStream, Filesize, MetadataHashValue, NewTimestamp, OpenFlags, PathFlags,
+};
+use wasmtime_wasi::p2::bindings::sync::io::streams;
+use wasmtime_wasi::p2::pipe::MemoryInputPipe;
+use wasmtime_wasi::p2::{FsError, FsResult, OutputStream, StreamError, StreamResult};
+use wasmtime_wasi_io::poll::Pollable;
+
+use crate::snapshot::{BlobStore, Root, Tree};
+use crate::trace::SnapshotDir;
+
+/// How much a guest may write to a snapshot file stream in one go
+const WRITE_BUDGET: usize = 64 * 1024;
+
+/// The preopened directories of a recording, served from memory during replay
+///
+/// Changes the guest makes stay in memory; the host filesystem is never
+/// touched.
+pub struct Snapshot {
+    tree: Arc<Mutex<Tree>>,
+    roots: Vec<Root>,
+}
+
+impl Snapshot {
+    pub fn mount(store: BlobStore, dirs: Vec<SnapshotDir>) -> Result<Self> {
+        let (tree, roots) = Tree::mount(store, dirs)?;
+        Ok(Self {
+            tree: Arc::new(Mutex::new(tree)),
+            roots,
+        })
+    }
+
+    /// Borrow the snapshot together with the resource table holding its handles
+    pub fn view<'a>(&'a self, table: &'a mut ResourceTable) -> SnapshotView<'a> {
+        SnapshotView {
+            table,
+            snapshot: self,
+        }
+    }
+
+    fn lock(&self) -> FsResult<MutexGuard<'_, Tree>> {
+        lock(&self.tree).map_err(FsError::trap)
+    }
+}
+
+fn lock(tree: &Mutex<Tree>) -> Result<MutexGuard<'_, Tree>> {
+    tree.lock()
+        .map_err(|_| anyhow!("snapshot filesystem was poisoned by an earlier panic"))
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SnapshotDescriptor {
+    node: usize,
+    flags: DescriptorFlags,
+}
+
+impl SnapshotDescriptor {
+    fn require(&self, flag: DescriptorFlags) -> FsResult<()> {
+        if self.flags.contains(flag) {
+            Ok(())
+        } else if flag == DescriptorFlags::MUTATE_DIRECTORY {
+            Err(ErrorCode::NotPermitted.into())
+        } else {
+            Err(ErrorCode::BadDescriptor.into())
+        }
+    }
+}
+
+struct SnapshotDirectoryStream {
+    entries: VecDeque<DirectoryEntry>,
+}
+
+/// Writes to a file in the snapshot, at a moving position or always at the end
+struct SnapshotOutputStream {
+    tree: Arc<Mutex<Tree>>,
+    node: usize,
+    position: Option<u64>,
+}
+
+#[async_trait::async_trait]
+impl OutputStream for SnapshotOutputStream {
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        let mut tree = lock(&self.tree).map_err(StreamError::Trap)?;
+        let end = tree
+            .write(self.node, self.position, &bytes)
+            .map_err(|err| StreamError::LastOperationFailed(anyhow!("{err}")))?;
+        if self.position.is_some() {
+            self.position = Some(end);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StreamResult<()> {
+        Ok(())
+    }
+
+    fn check_write(&mut self) -> StreamResult<usize> {
+        Ok(WRITE_BUDGET)
+    }
+}
+
+#[async_trait::async_trait]
+impl Pollable for SnapshotOutputStream {
+    async fn ready(&mut self) {}
+}
+
+/// Implements `wasi:filesystem` on top of a [`Snapshot`]
+pub struct SnapshotView<'a> {
+    table: &'a mut ResourceTable,
+    snapshot: &'a Snapshot,
+}
+
+impl SnapshotView<'_> {
+    pub fn get_directories(&mut self) -> Result<Vec<(Resource<Descriptor>, String)>> {
+        let snapshot = self.snapshot;
+        snapshot
+            .roots
+            .iter()
+            .map(|root| {
+                let fd = self.push(root.node, directory_flags(root.writable))?;
+                Ok((fd, root.path.clone()))
+            })
+            .collect()
+    }
+
+    fn push(&mut self, node: usize, flags: DescriptorFlags) -> FsResult<Resource<Descriptor>> {
+        let fd = self
+            .table
+            .push(SnapshotDescriptor { node, flags })
+            .map_err(FsError::trap)?;
+        Ok(Resource::new_own(fd.rep()))
+    }
+
+    fn descriptor(&self, fd: &Resource<Descriptor>) -> FsResult<SnapshotDescriptor> {
+        self.table
+            .get(&Resource::<SnapshotDescriptor>::new_borrow(fd.rep()))
+            .copied()
+            .map_err(FsError::trap)
+    }
+
+    /// Resolve a path against a directory descriptor
+    fn lookup(
+        &self,
+        fd: &Resource<Descriptor>,
+        path_flags: PathFlags,
+        path: &str,
+    ) -> FsResult<usize> {
+        let dir = self.descriptor(fd)?;
+        let tree = self.snapshot.lock()?;
+        tree.lookup(
+            dir.node,
+            path,
+            path_flags.contains(PathFlags::SYMLINK_FOLLOW),
+        )
+    }
+
+    /// The hash the host gave a snapshotted entry; entries created during the
+    /// run never had a host inode, so their index, which is just as stable,
+    /// stands in
+    fn hash(&self, node: usize) -> FsResult<MetadataHashValue> {
+        let hash = self.snapshot.lock()?.hash(node)?;
+        Ok(hash.unwrap_or(MetadataHashValue {
+            lower: node as u64,
+            upper: 0,
+        }))
+    }
+
+    /// Find the directory an entry is to be created in or removed from
+    fn parent(&self, fd: &Resource<Descriptor>, path: &str) -> FsResult<(usize, String)> {
+        let dir = self.descriptor(fd)?;
+        dir.require(DescriptorFlags::MUTATE_DIRECTORY)?;
+        self.snapshot.lock()?.parent(dir.node, path)
+    }
+}
+
+/// Flags of a directory descriptor, which can change its entries only on a
+/// writable mount
+fn directory_flags(writable: bool) -> DescriptorFlags {
+    if writable {
+        DescriptorFlags::READ | DescriptorFlags::MUTATE_DIRECTORY
+    } else {
+        DescriptorFlags::READ
+    }
+}
+
+/// Replay hands over `now` updates as the time they took when recorded, so
+/// one left over comes from a trace that never had it
+fn new_timestamp(timestamp: NewTimestamp) -> FsResult<Option<Datetime>> {
+    match timestamp {
+        NewTimestamp::NoChange => Ok(None),
+        NewTimestamp::Now => Err(FsError::trap(anyhow!(
+            "cannot replay a `now` timestamp update: the trace does not record the time it set, \
+             re-record it to replay this run"
+        ))),
+        NewTimestamp::Timestamp(datetime) => Ok(Some(datetime)),
+    }
+}
+
+impl types::HostDescriptor for SnapshotView<'_> {
+    fn advise(
+        &mut self,
+        fd: Resource<Descriptor>,
+        _offset: Filesize,
+        _len: Filesize,
+        _advice: Advice,
+    ) -> FsResult<()> {
+        self.descriptor(&fd)?;
+        Ok(())
+    }
+
+    fn sync_data(&mut self, fd: Resource<Descriptor>) -> FsResult<()> {
+        self.descriptor(&fd)?;
+        Ok(())
+    }
+
+    fn get_flags(&mut self, fd: Resource<Descriptor>) -> FsResult<DescriptorFlags> {
+        Ok(self.descriptor(&fd)?.flags)
+    }
+
+    fn get_type(&mut self, fd: Resource<Descriptor>) -> FsResult<DescriptorType> {
+        let fd = self.descriptor(&fd)?;
+        self.snapshot.lock()?.kind(fd.node)
+    }
+
+    fn set_size(&mut self, fd: Resource<Descriptor>, size: Filesize) -> FsResult<()> {
+        let fd = self.descriptor(&fd)?;
+        fd.require(DescriptorFlags::WRITE)?;
+        self.snapshot.lock()?.set_size(fd.node, size)
+    }
+
+    fn set_times(
+        &mut self,
+        fd: Resource<Descriptor>,
+        atim: NewTimestamp,
+        mtim: NewTimestamp,
+    ) -> FsResult<()> {
+        let fd = self.descriptor(&fd)?;
+        self.snapshot
+            .lock()?
+            .set_times(fd.node, new_timestamp(atim)?, new_timestamp(mtim)?)
+    }
+
+    fn read(
+        &mut self,
+        fd: Resource<Descriptor>,
+        len: Filesize,
+        offset: Filesize,
+    ) -> FsResult<(Vec<u8>, bool)> {
+        let fd = self.descriptor(&fd)?;
+        fd.require(DescriptorFlags::READ)?;
+        self.snapshot.lock()?.read(fd.node, len, offset)
+    }
+
+    fn write(
+        &mut self,
+        fd: Resource<Descriptor>,
+        buf: Vec<u8>,
+        offset: Filesize,
+    ) -> FsResult<Filesize> {
+        let fd = self.descriptor(&fd)?;
+        fd.require(DescriptorFlags::WRITE)?;
+        self.snapshot.lock()?.write(fd.node, Some(offset), &buf)?;
+        Ok(buf.len() as u64)
+    }
+
+    fn read_directory(
+        &mut self,
+        fd: Resource<Descriptor>,
+    ) -> FsResult<Resource<DirectoryEntryStream>> {
+        let fd = self.descriptor(&fd)?;
+        let entries = self.snapshot.lock()?.entries(fd.node)?.into();
+        let stream = self
+            .table
+            .push(SnapshotDirectoryStream { entries })
+            .map_err(FsError::trap)?;
+        Ok(Resource::new_own(stream.rep()))
+    }
+
+    fn sync(&mut self, fd: Resource<Descriptor>) -> FsResult<()> {
+        self.descriptor(&fd)?;
+        Ok(())
+    }
+
+    fn create_directory_at(&mut self, fd: Resource<Descriptor>, path: String) -> FsResult<()> {
+        let (dir, name) = self.parent(&fd, &path)?;
+        self.snapshot.lock()?.create_directory(dir, name)
+    }
+
+    fn stat(&mut self, fd: Resource<Descriptor>) -> FsResult<DescriptorStat> {
+        let fd = self.descriptor(&fd)?;
+        self.snapshot.lock()?.stat(fd.node)
+    }
+
+    fn stat_at(
+        &mut self,
+        fd: Resource<Descriptor>,
+        path_flags: PathFlags,
+        path: String,
+    ) -> FsResult<DescriptorStat> {
+        let node = self.lookup(&fd, path_flags, &path)?;
+        self.snapshot.lock()?.stat(node)
+    }
+
+    fn set_times_at(
+        &mut self,
+        fd: Resource<Descriptor>,
+        path_flags: PathFlags,
+        path: String,
+        atim: NewTimestamp,
+        mtim: NewTimestamp,
+    ) -> FsResult<()> {
+        let node = self.lookup(&fd, path_flags, &path)?;
+        self.snapshot
+            .lock()?
+            .set_times(node, new_timestamp(atim)?, new_timestamp(mtim)?)
+    }
+
+    fn link_at(
+        &mut self,
+        fd: Resource<Descriptor>,
+        path_flags: PathFlags,
+        old_path: String,
+        new_fd: Resource<Descriptor>,
+        new_path: String,
+    ) -> FsResult<()> {
+        let node = self.lookup(&fd, path_flags, &old_path)?;
+        let (dir, name) = self.parent(&new_fd, &new_path)?;
+        self.snapshot.lock()?.link(node, dir, name)
+    }
+
+    fn open_at(
+        &mut self,
+        fd: Resource<Descriptor>,
+        path_flags: PathFlags,
+        path: String,
+        open_flags: OpenFlags,
+        descriptor_flags: DescriptorFlags,
+    ) -> FsResult<Resource<Descriptor>> {
+        let dir = self.descriptor(&fd)?;
+        let follow = path_flags.contains(PathFlags::SYMLINK_FOLLOW);
+        let writes = open_flags.contains(OpenFlags::CREATE)
+            || open_flags.contains(OpenFlags::TRUNCATE)
+            || descriptor_flags.contains(DescriptorFlags::WRITE);
+        if writes {
+            dir.require(DescriptorFlags::MUTATE_DIRECTORY)?;
+        }
+        if open_flags.contains(OpenFlags::DIRECTORY)
+            && (open_flags.contains(OpenFlags::CREATE)
+                || open_flags.contains(OpenFlags::EXCLUSIVE)
+                || open_flags.contains(OpenFlags::TRUNCATE))
+        {
+            return Err(ErrorCode::Invalid.into());
+        }
+
+        let snapshot = self.snapshot;
+        let mut tree = snapshot.lock()?;
+        let node = if open_flags.contains(OpenFlags::CREATE) {
+            let (parent, name) = tree.parent(dir.node, &path)?;
+            match tree.child(parent, &name)? {
+                Some(_) if open_flags.contains(OpenFlags::EXCLUSIVE) => {
+                    return Err(ErrorCode::Exist.into())
+                }
+                Some(_) => tree.lookup(dir.node, &path, follow)?,
+                None => tree.create_file(parent, name)?,
+            }
+        } else {
+            tree.lookup(dir.node, &path, follow)?
+        };
+
+        let flags = match tree.kind(node)? {
+            DescriptorType::Directory => {
+                if writes {
+                    return Err(ErrorCode::IsDirectory.into());
+                }
+                dir.flags & directory_flags(true)
+            }
+            DescriptorType::SymbolicLink => return Err(ErrorCode::Loop.into()),
+            _ if open_flags.contains(OpenFlags::DIRECTORY) => {
+                return Err(ErrorCode::NotDirectory.into())
+            }
+            _ => {
+                let mut flags = descriptor_flags & (DescriptorFlags::READ | DescriptorFlags::WRITE);
+                if !flags.contains(DescriptorFlags::WRITE) {
+                    flags |= DescriptorFlags::READ;
+                }
+                flags
+            }
+        };
+        if open_flags.contains(OpenFlags::TRUNCATE) {
+            tree.set_size(node, 0)?;
+        }
+        drop(tree);
+        self.push(node, flags)
+    }
+
+    fn drop(&mut self, fd: Resource<Descriptor>) -> anyhow::Result<()> {
+        self.table
+            .delete(Resource::<SnapshotDescriptor>::new_own(fd.rep()))?;
+        Ok(())
+    }
+
+    fn readlink_at(&mut self, fd: Resource<Descriptor>, path: String) -> FsResult<String> {
+        let node = self.lookup(&fd, PathFlags::empty(), &path)?;
+        self.snapshot.lock()?.readlink(node)
+    }
+
+    fn remove_directory_at(&mut self, fd: Resource<Descriptor>, path: String) -> FsResult<()> {
+        let (dir, name) = self.parent(&fd, &path)?;
+        self.snapshot.lock()?.remove(dir, &name, true)
+    }
+
+    fn rename_at(
+        &mut self,
+        fd: Resource<Descriptor>,
+        old_path: String,
+        new_fd: Resource<Descriptor>,
+        new_path: String,
+    ) -> FsResult<()> {
+        let (dir, name) = self.parent(&fd, &old_path)?;
+        let (new_dir, new_name) = self.parent(&new_fd, &new_path)?;
+        self.snapshot.lock()?.rename(dir, &name, new_dir, new_name)
+    }
+
+    fn symlink_at(
+        &mut self,
+        fd: Resource<Descriptor>,
+        old_path: String,
+        new_path: String,
+    ) -> FsResult<()> {
+        let (dir, name) = self.parent(&fd, &new_path)?;
+        self.snapshot.lock()?.symlink(dir, name, old_path)
+    }
+
+    fn unlink_file_at(&mut self, fd: Resource<Descriptor>, path: String) -> FsResult<()> {
+        let (dir, name) = self.parent(&fd, &path)?;
+        self.snapshot.lock()?.remove(dir, &name, false)
+    }
+
+    fn read_via_stream(
+        &mut self,
+        fd: Resource<Descriptor>,
+        offset: Filesize,
+    ) -> FsResult<Resource<streams::InputStream>> {
+        let fd = self.descriptor(&fd)?;
+        fd.require(DescriptorFlags::READ)?;
+        let (bytes, _) = self.snapshot.lock()?.read(fd.node, u64::MAX, offset)?;
+        let stream: streams::InputStream = Box::new(MemoryInputPipe::new(bytes));
+        self.table.push(stream).map_err(FsError::trap)
+    }
+
+    fn write_via_stream(
+        &mut self,
+        fd: Resource<Descriptor>,
+        offset: Filesize,
+    ) -> FsResult<Resource<streams::OutputStream>> {
+        let fd = self.descriptor(&fd)?;
+        fd.require(DescriptorFlags::WRITE)?;
+        let stream: streams::OutputStream = Box::new(SnapshotOutputStream {
+            tree: Arc::clone(&self.snapshot.tree),
+            node: fd.node,
+            position: Some(offset),
+        });
+        self.table.push(stream).map_err(FsError::trap)
+    }
+
+    fn append_via_stream(
+        &mut self,
+        fd: Resource<Descriptor>,
+    ) -> FsResult<Resource<streams::OutputStream>> {
+        let fd = self.descriptor(&fd)?;
+        fd.require(DescriptorFlags::WRITE)?;
+        let stream: streams::OutputStream = Box::new(SnapshotOutputStream {
+            tree: Arc::clone(&self.snapshot.tree),
+            node: fd.node,
+            position: None,
+        });
+        self.table.push(stream).map_err(FsError::trap)
+    }
+
+    fn is_same_object(
+        &mut self,
+        a: Resource<Descriptor>,
+        b: Resource<Descriptor>,
+    ) -> anyhow::Result<bool> {
+        Ok(self.descriptor(&a)?.node == self.descriptor(&b)?.node)
+    }
+
+    fn metadata_hash(&mut self, fd: Resource<Descriptor>) -> FsResult<MetadataHashValue> {
+        let node = self.descriptor(&fd)?.node;
+        self.hash(node)
+    }
+
+    fn metadata_hash_at(
+        &mut self,
+        fd: Resource<Descriptor>,
+        path_flags: PathFlags,
+        path: String,
+    ) -> FsResult<MetadataHashValue> {
+        let node = self.lookup(&fd, path_flags, &path)?;
+        self.hash(node)
+    }
+}
+
+impl types::HostDirectoryEntryStream for SnapshotView<'_> {
+    fn read_directory_entry(
+        &mut self,
+        stream: Resource<DirectoryEntryStream>,
+    ) -> FsResult<Option<DirectoryEntry>> {
+        let stream = self
+            .table
+            .get_mut(&Resource::<SnapshotDirectoryStream>::new_borrow(
+                stream.rep(),
+            ))
+            .map_err(FsError::trap)?;
+        Ok(stream.entries.pop_front())
+    }
+
+    fn drop(&mut self, stream: Resource<DirectoryEntryStream>) -> anyhow::Result<()> {
+        self.table
+            .delete(Resource::<SnapshotDirectoryStream>::new_own(stream.rep()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use types::HostDescriptor as _;
+
+    use super::*;
+    use crate::trace::{FileHash, FileStat, SnapshotEntry};
+
+    fn entry(path: &str, kind: &str, size: u64) -> SnapshotEntry {
+        SnapshotEntry {
+            path: path.to_string(),
+            stat: FileStat {
+                kind: kind.to_string(),
+                link_count: 1,
+                size,
+                data_access_timestamp: None,
+                data_modification_timestamp: None,
+                status_change_timestamp: None,
+            },
+            blob: None,
+            target: None,
+            hash: None,
+        }
+    }
+
+    /// A writable `/data` holding `hello.txt` and a link to it, and an empty
+    /// read-only `/ro`
+    fn snapshot(store: &tempfile::TempDir) -> Result<Snapshot> {
+        let host = store.path().join("hello.txt");
+        std::fs::write(&host, b"hello")?;
+        let store = BlobStore::create(store.path().join("blobs"))?;
+        let hello = SnapshotEntry {
+            blob: Some(store.put_file(&host)?),
+            hash: Some(FileHash { lower: 7, upper: 9 }),
+            ..entry("hello.txt", "regular-file", 5)
+        };
+        let link = SnapshotEntry {
+            target: Some("hello.txt".to_string()),
+            ..entry("link", "symbolic-link", 9)
+        };
+        let dirs = vec![
+            SnapshotDir {
+                path: "/data".to_string(),
+                writable: true,
+                entries: vec![entry("", "directory", 0), hello, link],
+            },
+            SnapshotDir {
+                path: "/ro".to_string(),
+                writable: false,
+                entries: vec![entry("", "directory", 0)],
+            },
+        ];
+        Snapshot::mount(store, dirs)
+    }
+
+    fn preopens(
+        view: &mut SnapshotView<'_>,
+    ) -> Result<(Resource<Descriptor>, Resource<Descriptor>)> {
+        let mut dirs = view.get_directories()?.into_iter().map(|(fd, _)| fd);
+        match (dirs.next(), dirs.next()) {
+            (Some(data), Some(ro)) => Ok((data, ro)),
+            _ => Err(anyhow!("expected two preopens")),
+        }
+    }
+
+    fn borrow(fd: &Resource<Descriptor>) -> Resource<Descriptor> {
+        Resource::new_borrow(fd.rep())
+    }
+
+    fn error<T>(result: FsResult<T>) -> Option<ErrorCode> {
+        result.err().and_then(|err| err.downcast_ref().copied())
+    }
+
+    #[test]
+    fn open_at_follows_links_only_when_asked() -> Result<()> {
+        let store = tempfile::tempdir()?;
+        let snapshot = snapshot(&store)?;
+        let mut table = ResourceTable::new();
+        let mut view = snapshot.view(&mut table);
+        let (data, _) = preopens(&mut view)?;
+
+        let no_follow = view.open_at(
+            borrow(&data),
+            PathFlags::empty(),
+            "link".to_string(),
+            OpenFlags::empty(),
+            DescriptorFlags::READ,
+        );
+        assert_eq!(error(no_follow), Some(ErrorCode::Loop));
+        let file = view.open_at(
+            borrow(&data),
+            PathFlags::SYMLINK_FOLLOW,
+            "link".to_string(),
+            OpenFlags::empty(),
+            DescriptorFlags::READ,
+        )?;
+        assert_eq!(view.read(borrow(&file), 10, 0)?, (b"hello".to_vec(), true));
+        assert_eq!(
+            view.readlink_at(borrow(&data), "link".to_string())?,
+            "hello.txt"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn writes_need_write_access() -> Result<()> {
+        let store = tempfile::tempdir()?;
+        let snapshot = snapshot(&store)?;
+        let mut table = ResourceTable::new();
+        let mut view = snapshot.view(&mut table);
+        let (data, ro) = preopens(&mut view)?;
+
+        let create = view.open_at(
+            borrow(&ro),
+            PathFlags::empty(),
+            "new.txt".to_string(),
+            OpenFlags::CREATE,
+            DescriptorFlags::WRITE,
+        );
+        assert_eq!(error(create), Some(ErrorCode::NotPermitted));
+        let mkdir = view.create_directory_at(borrow(&ro), "sub".to_string());
+        assert_eq!(error(mkdir), Some(ErrorCode::NotPermitted));
+
+        let reader = view.open_at(
+            borrow(&data),
+            PathFlags::empty(),
+            "hello.txt".to_string(),
+            OpenFlags::empty(),
+            DescriptorFlags::READ,
+        )?;
+        assert_eq!(
+            error(view.set_size(borrow(&reader), 0)),
+            Some(ErrorCode::BadDescriptor)
+        );
+        assert_eq!(
+            error(view.write(borrow(&reader), b"x".to_vec(), 0)),
+            Some(ErrorCode::BadDescriptor)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn set_size_through_a_descriptor() -> Result<()> {
+        let store = tempfile::tempdir()?;
+        let snapshot = snapshot(&store)?;
+        let mut table = ResourceTable::new();
+        let mut view = snapshot.view(&mut table);
+        let (data, _) = preopens(&mut view)?;
+
+        let file = view.open_at(
+            borrow(&data),
+            PathFlags::empty(),
+            "hello.txt".to_string(),
+            OpenFlags::empty(),
+            DescriptorFlags::READ | DescriptorFlags::WRITE,
+        )?;
+        view.set_size(borrow(&file), 3)?;
+        assert_eq!(view.stat(borrow(&file))?.size, 3);
+        let stat = view.stat_at(borrow(&data), PathFlags::SYMLINK_FOLLOW, "link".to_string())?;
+        assert_eq!(stat.size, 3);
+
+        let truncated = view.open_at(
+            borrow(&data),
+            PathFlags::empty(),
+            "hello.txt".to_string(),
+            OpenFlags::TRUNCATE,
+            DescriptorFlags::WRITE,
+        )?;
+        assert_eq!(view.stat(borrow(&truncated))?.size, 0);
+        assert_eq!(view.read(borrow(&file), 10, 0)?, (Vec::new(), true));
+        Ok(())
+    }
+
+    #[test]
+    fn set_times_applies_given_timestamps() -> Result<()> {
+        let store = tempfile::tempdir()?;
+        let snapshot = snapshot(&store)?;
+        let mut table = ResourceTable::new();
+        let mut view = snapshot.view(&mut table);
+        let (data, _) = preopens(&mut view)?;
+
+        let time = Datetime {
+            seconds: 1_700_000_000,
+            nanoseconds: 5,
+        };
+        view.set_times_at(
+            borrow(&data),
+            PathFlags::empty(),
+            "hello.txt".to_string(),
+            NewTimestamp::NoChange,
+            NewTimestamp::Timestamp(time),
+        )?;
+        let stat = view.stat_at(borrow(&data), PathFlags::empty(), "hello.txt".to_string())?;
+        let parts = |time: Option<Datetime>| time.map(|time| (time.seconds, time.nanoseconds));
+        assert_eq!(
+            parts(stat.data_modification_timestamp),
+            Some((1_700_000_000, 5))
+        );
+        assert_eq!(parts(stat.data_access_timestamp), None);
+
+        // Replay resolves `now` from the trace, so reaching the snapshot with
+        // one is a trap rather than an error the guest sees
+        let now = view.set_times(borrow(&data), NewTimestamp::Now, NewTimestamp::NoChange);
+        assert!(matches!(&now, Err(err) if err.downcast_ref().is_none()));
+        Ok(())
+    }
+
+    #[test]
+    fn metadata_hash_keeps_the_host_hash() -> Result<()> {
+        let store = tempfile::tempdir()?;
+        let snapshot = snapshot(&store)?;
+        let mut table = ResourceTable::new();
+        let mut view = snapshot.view(&mut table);
+        let (data, _) = preopens(&mut view)?;
+
+        let hash =
+            view.metadata_hash_at(borrow(&data), PathFlags::SYMLINK_FOLLOW, "link".to_string())?;
+        assert_eq!((hash.lower, hash.upper), (7, 9));
+
+        view.create_directory_at(borrow(&data), "sub".to_string())?;
+        let sub = view.metadata_hash_at(borrow(&data), PathFlags::empty(), "sub".to_string())?;
+        let again =
+            view.metadata_hash_at(borrow(&data), PathFlags::empty(), "sub/.".to_string())?;
+        assert_eq!((sub.lower, sub.upper), (again.lower, again.upper));
+        assert_ne!((sub.lower, sub.upper), (hash.lower, hash.upper));
+        Ok(())
+    }
+}
@@ -1,6 +1,9 @@
 use anyhow::anyhow;
 use wasmtime_wasi_http::HttpError;
 
+/// Most bytes a single `blocking-write-and-flush` may carry
+pub const MAX_BLOCKING_WRITE: u64 = 4096;
+
 /// Sort HTTP headers for deterministic comparison
 ///
 /// # Errors